@@ -0,0 +1,81 @@
+use crate::prelude::*;
+use gdriver_common::ipc::clients::{ClientId, ClientInfo};
+use gdriver_common::ipc::events::EventSubscription;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Keeps track of the clients that are connected right now
+#[derive(Debug, Default)]
+pub struct ClientRegistry {
+    next_id: AtomicU64,
+    clients: Mutex<BTreeMap<ClientId, Arc<ConnectedClient>>>,
+}
+impl ClientRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Gives a new connection its id, it stays registered until [ClientRegistry::remove]
+    pub fn register(&self, peer: String) -> Arc<ConnectedClient> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let client = Arc::new(ConnectedClient {
+            id,
+            peer,
+            name: Mutex::new(None),
+            subscription: Mutex::new(EventSubscription::default()),
+        });
+        info!("Client {id} connected from {}", client.peer);
+        self.clients.lock().unwrap().insert(id, client.clone());
+        client
+    }
+    pub fn remove(&self, id: ClientId) {
+        if self.clients.lock().unwrap().remove(&id).is_some() {
+            info!("Client {id} disconnected");
+        }
+    }
+    pub fn list(&self) -> Vec<ClientInfo> {
+        self.clients
+            .lock()
+            .unwrap()
+            .values()
+            .map(|client| client.info())
+            .collect()
+    }
+}
+
+/// The state the backend keeps for a single connection
+#[derive(Debug)]
+pub struct ConnectedClient {
+    pub id: ClientId,
+    /// Describes where the client connected from, for logging
+    pub peer: String,
+    name: Mutex<Option<String>>,
+    subscription: Mutex<EventSubscription>,
+}
+impl ConnectedClient {
+    pub fn set_name(&self, name: String) {
+        *self.name.lock().unwrap() = Some(name);
+    }
+    pub fn subscription(&self) -> EventSubscription {
+        self.subscription.lock().unwrap().clone()
+    }
+    pub fn set_subscription(&self, subscription: EventSubscription) {
+        *self.subscription.lock().unwrap() = subscription;
+    }
+    pub fn info(&self) -> ClientInfo {
+        ClientInfo {
+            id: self.id,
+            name: self.name.lock().unwrap().clone(),
+            peer: self.peer.clone(),
+            subscription: self.subscription(),
+        }
+    }
+}
+impl std::fmt::Display for ConnectedClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name.lock().unwrap().as_deref() {
+            Some(name) => write!(f, "{} ({name})", self.id),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
@@ -1,93 +1,346 @@
 use crate::apply_change;
-use crate::drive::google_drive::{FileData, GoogleDrive};
+use crate::drive::google_drive::GoogleDrive;
+use crate::drive::remote::{BatchItemError, MetaUpdate, RemoteChange, RemoteDrive};
+use crate::events::EventLog;
 use crate::path_resolver::PathResolver;
 use chrono::{DateTime, Utc};
-use gdriver_common::drive_structure::meta::{read_metadata_by_id, write_metadata_file, Metadata};
-use google_drive3::api::Change;
-use std::collections::HashMap;
+use gdriver_common::drive_structure::meta::{
+    read_metadata_by_id, write_metadata_file, FileKind, FileState, Metadata,
+};
+use gdriver_common::ipc::events::BackendEvent;
+use gdriver_common::ipc::gdriver_service::SETTINGS;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{RwLock, Semaphore};
 
 use crate::prelude::*;
 mod google_drive;
-pub struct Drive {
-    tracked_files: HashMap<DriveId, DateTime<Utc>>,
-    pub path_resolver: PathResolver,
-    google_drive: GoogleDrive,
-    pub offline_mode: bool,
+pub mod local_drive;
+#[cfg(test)]
+pub mod memory_drive;
+pub mod remote;
+/// The local state of the remote drive, shared by all requests.
+///
+/// Every part is locked on its own, so requests that only read run at the same time. The path
+/// resolver is only locked for writing while changes are applied, never while the remote is
+/// asked for them, and downloads do not lock it at all.
+pub struct Drive<R: RemoteDrive = GoogleDrive> {
+    tracked_files: Mutex<HashMap<DriveId, DateTime<Utc>>>,
+    pub path_resolver: RwLock<PathResolver>,
+    /// Used for every call that does not touch the change token
+    remote: R,
+    /// The remote that keeps the change token. Locked for the whole sync, so only one sync
+    /// runs at a time and the changes are applied in the order they were listed.
+    syncing_remote: tokio::sync::Mutex<R>,
+    /// How many times [Drive::update] started to get the changes
+    started_syncs: AtomicU64,
+    /// The number in [Drive::started_syncs] of the last update that applied all changes
+    completed_sync: AtomicU64,
+    offline_mode: AtomicBool,
+    events: Arc<EventLog>,
+    /// Limits how many files are downloaded at the same time
+    download_permits: Arc<Semaphore>,
+    /// How many permits [Drive::download_permits] has in total
+    download_concurrency: u32,
+    downloads_in_progress: Arc<Mutex<HashSet<DriveId>>>,
+    /// Held for reading by every upload, so [Drive::wait_for_uploads] can wait for all of them
+    uploads: RwLock<()>,
+    /// When [Drive::update] applied the remote changes successfully the last time
+    last_sync: Mutex<Option<DateTime<Utc>>>,
 }
-impl Drive {
-    #[instrument()]
-    pub async fn new() -> Result<Self> {
-        Ok(Self {
-            tracked_files: HashMap::new(),
-            path_resolver: PathResolver::new(),
-            google_drive: GoogleDrive::new().await?,
-            offline_mode: false,
-        })
-    }
-    pub fn set_offline_mode(&mut self, offline_mode: bool) {
-        self.offline_mode = offline_mode;
+impl Drive<GoogleDrive> {
+    #[instrument(skip(events))]
+    pub async fn new(events: Arc<EventLog>) -> Result<Self> {
+        Ok(Self::with_remote(GoogleDrive::new().await?, events))
+    }
+}
+impl<R: RemoteDrive> Drive<R> {
+    pub fn with_remote(remote: R, events: Arc<EventLog>) -> Self {
+        let download_concurrency = CONFIGURATION.current().prefetch_concurrency.max(1);
+        Self {
+            tracked_files: Mutex::new(HashMap::new()),
+            path_resolver: RwLock::new(PathResolver::new()),
+            syncing_remote: tokio::sync::Mutex::new(remote.clone()),
+            remote,
+            started_syncs: AtomicU64::new(0),
+            completed_sync: AtomicU64::new(0),
+            offline_mode: AtomicBool::new(false),
+            events,
+            download_permits: Arc::new(Semaphore::new(download_concurrency)),
+            download_concurrency: download_concurrency as u32,
+            downloads_in_progress: Arc::new(Mutex::new(HashSet::new())),
+            uploads: RwLock::new(()),
+            last_sync: Mutex::new(None),
+        }
+    }
+    pub fn offline_mode(&self) -> bool {
+        self.offline_mode.load(Ordering::Acquire)
+    }
+    pub fn set_offline_mode(&self, offline_mode: bool) {
+        if self.offline_mode.swap(offline_mode, Ordering::AcqRel) != offline_mode {
+            self.events
+                .push(BackendEvent::OfflineModeChanged(offline_mode));
+        }
     }
     #[instrument(skip(self))]
     pub fn get_file_tracking_state(&self, id: &DriveId) -> TrackingState {
-        let file = self.tracked_files.get(id);
+        let file = self.tracked_files.lock().unwrap().get(id).copied();
         match file {
-            Some(date) => TrackingState::Tracked(*date),
+            Some(date) => TrackingState::Tracked(date),
             None => TrackingState::Untracked,
         }
     }
 
     #[instrument(skip(self))]
-    pub async fn get_all_file_metas(&mut self) -> Result<()> {
-        let has_existing_token = self.google_drive.has_local_change_token().await;
+    pub async fn get_all_file_metas(&self) -> Result<()> {
+        let mut remote = self.syncing_remote.lock().await;
+        let has_existing_token = remote.has_local_change_token().await;
         //TODO: show an error when offline and no local data exists
         if !has_existing_token {
             //only get start token & data if this is the first time & we don't have it
-            self.google_drive.get_change_start_token().await?;
-            let files = self.google_drive.get_all_file_metas().await?;
+            self.fetch_all_file_metas(&mut remote).await?;
+        } else {
+            self.path_resolver.write().await.load_from_disk()?;
+        }
 
-            self.path_resolver.reset()?;
-            for file in files {
-                let parents = file.parents.clone();
-                let meta = file.into_meta()?;
-                self.path_resolver
-                    .add_relationships_for_meta(parents, &meta)?;
+        Ok(())
+    }
+    /// Gets a new start token and the metadata of every file, replacing everything that was
+    /// known about the remote
+    async fn fetch_all_file_metas(&self, remote: &mut R) -> Result<()> {
+        remote.get_change_start_token().await?;
+        let mut files = remote.get_all_file_metas().await?;
+        files.retain(|file| !file.trashed.unwrap_or_default());
+        // the oldest of files with the same name keeps the name, the others get a suffix
+        files.sort_by(|a, b| (a.created_time, &a.id).cmp(&(b.created_time, &b.id)));
 
-                write_metadata_file(&meta)?;
-            }
-        } else {
-            self.path_resolver.load_from_disk()?;
+        let mut metas = Vec::with_capacity(files.len());
+        for file in files {
+            let parents = file.parents.clone();
+            let meta = file.into_meta()?;
+            write_metadata_file(&meta)?;
+            metas.push((parents, meta));
         }
 
+        // only locked once everything is on disk, listing waits as short as possible
+        let mut path_resolver = self.path_resolver.write().await;
+        path_resolver.reset();
+        for (parents, meta) in metas {
+            path_resolver.add_relationships_for_meta(parents, &meta)?;
+        }
+        path_resolver.commit()?;
+        *self.last_sync.lock().unwrap() = Some(Utc::now());
+        Ok(())
+    }
+    /// Lists all files again, like on the first start, for when the local state went wrong
+    #[instrument(skip(self))]
+    pub async fn resync(&self) -> Result<()> {
+        if self.offline_mode() {
+            return Err("Can not resync in offline mode".into());
+        }
+        let mut remote = self.syncing_remote.lock().await;
+        self.fetch_all_file_metas(&mut remote).await?;
+        // clients can not know what changed, so they have to drop everything
+        self.events.forget_all();
+        Ok(())
+    }
+    pub fn last_sync(&self) -> Option<DateTime<Utc>> {
+        *self.last_sync.lock().unwrap()
+    }
+    pub fn downloads_in_progress(&self) -> usize {
+        self.downloads_in_progress.lock().unwrap().len()
+    }
+    /// Waits for a sync that is running and writes everything that is only kept in memory to
+    /// disk
+    pub async fn flush(&self) -> Result<()> {
+        let _remote = self.syncing_remote.lock().await;
+        self.path_resolver.write().await.commit()
+    }
+    /// Waits until all downloads that were started are done
+    pub async fn wait_for_downloads(&self) -> Result<()> {
+        let _permits = self
+            .download_permits
+            .acquire_many(self.download_concurrency)
+            .await?;
         Ok(())
     }
+    /// Waits until all uploads that were started are done, uploads that start meanwhile wait
+    /// until this returns
+    pub async fn wait_for_uploads(&self) {
+        drop(self.uploads.write().await);
+    }
     pub async fn download_meta_for_file(&self, id: &DriveId) -> Result<()> {
-        let meta = self.google_drive.get_meta_for_file(id).await?;
+        let meta = self.remote.get_meta_for_file(id).await?;
         write_metadata_file(&meta.into_meta()?)?;
         Ok(())
     }
+    /// Downloads the metadata of many files with batch requests and returns the result per id
+    #[instrument(skip(self, ids))]
+    pub async fn download_metas_for_files(
+        &self,
+        ids: Vec<DriveId>,
+    ) -> Result<HashMap<DriveId, StdResult<(), BatchItemError>>> {
+        let results = self.remote.get_metas_for_files(ids).await?;
+        let mut written = HashMap::with_capacity(results.len());
+        for (id, result) in results {
+            let result = match result {
+                Ok(Some(file)) => {
+                    let mut meta = file.into_meta()?;
+                    // the remote does not know if the file is downloaded or kept local
+                    if let Ok(existing) = read_metadata_by_id(&id) {
+                        meta.state = existing.state;
+                        meta.extra_attributes = existing.extra_attributes;
+                    }
+                    write_metadata_file(&meta)?;
+                    Ok(())
+                }
+                Ok(None) => Err(BatchItemError::Missing),
+                Err(e) => {
+                    warn!("Could not get metadata for {}: {}", id, e);
+                    Err(e)
+                }
+            };
+            written.insert(id, result);
+        }
+        Ok(written)
+    }
+    #[instrument(skip(self))]
+    pub async fn download_content_for_file(&self, id: &DriveId) -> Result<()> {
+        let _permit = self.download_permits.acquire().await?;
+        download_content(&self.remote, id).await
+    }
+
+    /// Gets the metadata of all children of the folder that are not known yet with a single
+    /// listing and starts downloading the small files in it in the background.
+    ///
+    /// The listing waits for the remote, so this should not hold up answering a client.
+    #[instrument(skip(self))]
+    pub async fn prefetch_children(&self, id: &DriveId) -> Result<()> {
+        if self.offline_mode() {
+            return Ok(());
+        }
+        // cloned, so listing does not wait for the requests to the remote below
+        let children = self.path_resolver.read().await.get_children(id)?.clone();
+        let has_missing_metadata = children
+            .iter()
+            .any(|child| !SETTINGS.get_metadata_file_path(&child.id).exists());
+        if has_missing_metadata {
+            info!("Prefetching metadata for children of {}", id);
+            for file in self.remote.get_children_metas(id).await? {
+                let meta = file.into_meta()?;
+                // existing metadata has local state, like being downloaded, the listing lacks
+                if !SETTINGS.get_metadata_file_path(&meta.id).exists() {
+                    write_metadata_file(&meta)?;
+                }
+            }
+        }
+
+        let max_size = CONFIGURATION.current().prefetch_max_file_size;
+        if max_size == 0 {
+            return Ok(());
+        }
+        let small_files: Vec<DriveId> = children
+            .iter()
+            .filter(|child| child.kind == FileKind::File)
+            .filter_map(|child| read_metadata_by_id(&child.id).ok())
+            // files without size (like google docs) can not be downloaded directly
+            .filter(|meta| meta.state == FileState::MetadataOnly)
+            .filter(|meta| meta.size > 0 && meta.size <= max_size)
+            .map(|meta| meta.id)
+            .collect();
+        let small_files: Vec<DriveId> = {
+            let mut in_progress = self.downloads_in_progress.lock().unwrap();
+            small_files
+                .into_iter()
+                .filter(|id| in_progress.insert(id.clone()))
+                .collect()
+        };
+        if small_files.is_empty() {
+            return Ok(());
+        }
+        info!("Prefetching content of {} small files", small_files.len());
+        let remote = self.remote.clone();
+        let download_permits = self.download_permits.clone();
+        let downloads_in_progress = self.downloads_in_progress.clone();
+        tokio::spawn(async move {
+            let mut small_files = small_files.into_iter();
+            // a download only starts with a permit, so at most as many run as there are permits
+            while let Some(id) = small_files.next() {
+                let Ok(permit) = download_permits.clone().acquire_owned().await else {
+                    // the files are not downloaded, so they have to be allowed to start again
+                    let mut in_progress = downloads_in_progress.lock().unwrap();
+                    for id in std::iter::once(id).chain(small_files) {
+                        in_progress.remove(&id);
+                    }
+                    return;
+                };
+                let remote = remote.clone();
+                let downloads_in_progress = downloads_in_progress.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = download_content(&remote, &id).await {
+                        warn!("Could not prefetch {}: {}", id, e);
+                    }
+                    downloads_in_progress.lock().unwrap().remove(&id);
+                    drop(permit);
+                });
+            }
+        });
+        Ok(())
+    }
 
+    /// Gets the changes from the remote and applies them.
+    ///
+    /// Only one update runs at a time. An update that was running already when this was called
+    /// could have missed the latest changes, so this waits for it and then gets them again,
+    /// unless another update started after this call and applied them in the meantime.
     #[instrument(skip(self))]
-    pub async fn update(&mut self) -> Result<()> {
-        if self.offline_mode {
+    pub async fn update(&self) -> Result<()> {
+        if self.offline_mode() {
             info!("Offline mode, skipping update");
             return Ok(());
         }
-        let changes = self.google_drive.get_changes().await?;
+        let requested_after = self.started_syncs.load(Ordering::Acquire);
+        let mut remote = self.syncing_remote.lock().await;
+        if self.completed_sync.load(Ordering::Acquire) > requested_after {
+            info!("Another update got the changes since this one was requested");
+            return Ok(());
+        }
+        let sync = self.started_syncs.fetch_add(1, Ordering::AcqRel) + 1;
+        let changes = remote.get_changes().await?;
         if changes.is_empty() {
             info!("No changes");
+            self.completed_sync.store(sync, Ordering::Release);
+            *self.last_sync.lock().unwrap() = Some(Utc::now());
             return Ok(());
         }
-        for change in changes {
-            // dbg!(&change);
-            self.process_change(change)?;
+        let mut path_resolver = self.path_resolver.write().await;
+        let result = changes
+            .into_iter()
+            .try_for_each(|change| self.process_change(&mut path_resolver, change));
+        // the changes that were processed before an error are kept
+        path_resolver.commit()?;
+        if result.is_ok() {
+            self.completed_sync.store(sync, Ordering::Release);
+            *self.last_sync.lock().unwrap() = Some(Utc::now());
         }
-        Ok(()) //TODO: implement this
+        result
     }
-    #[instrument(skip(self, change))]
-    fn process_change(&mut self, change: Change) -> Result<()> {
-        let file_data =
-            FileData::convert_from_api_file(change.file.ok_or("change had no file data")?);
-        let parents = file_data
+    #[instrument(skip(self, path_resolver, change))]
+    fn process_change(&self, path_resolver: &mut PathResolver, change: RemoteChange) -> Result<()> {
+        let id = change.id;
+        // trashed files are only reported as changed, but they are gone from their folders
+        let trashed = change
+            .file
+            .as_ref()
+            .and_then(|file| file.trashed)
+            .unwrap_or_default();
+        if change.removed || trashed {
+            info!("File removed: {:?}", id);
+            return self.process_removal(path_resolver, &id);
+        }
+        let file_data = change.file.ok_or("change had no file data")?;
+        let parents: Vec<DriveId> = file_data
             .parents
             .clone()
             .into_iter()
@@ -95,28 +348,52 @@ impl Drive {
             .collect();
         let new_meta = file_data.into_meta()?;
         info!("Processing change: {:?}", new_meta);
-        let id: DriveId = change.file_id.clone().ok_or("No file id in change")?.into();
         let original_meta = read_metadata_by_id(&id);
         if original_meta.is_err() {
             info!("File not found so it has to be new: {:?}", id);
-            self.path_resolver
-                .add_relationships_for_meta(parents, &new_meta)?;
+            path_resolver.add_relationships_for_meta(parents.clone(), &new_meta)?;
             write_metadata_file(&new_meta)?;
-            return Ok(());
-        }
-        if change.removed.unwrap_or_default() {
-            info!("File removed: {:?}", id);
-            todo!("Do something when a file is removed from drive");
+            parents
+                .into_iter()
+                .for_each(|parent| self.events.push(BackendEvent::Changed(parent)));
+            self.events.push(BackendEvent::Changed(id));
             return Ok(());
         }
         //TODO: deal with locally and remotely changed files
         let mut original_meta = original_meta?;
-        self.process_parents_changes(parents, &id, &new_meta)?;
-        Self::process_meta_changes(new_meta, &mut original_meta)?;
+        let renamed = original_meta.name != new_meta.name;
+        let has_parents_changed =
+            self.process_parents_changes(path_resolver, parents, &id, &new_meta, renamed)?;
+        let has_meta_changed = Self::process_meta_changes(new_meta, &mut original_meta)?;
+        if has_parents_changed || has_meta_changed {
+            self.events.push(BackendEvent::Changed(id));
+        }
         Ok(())
     }
 
-    fn process_meta_changes(new_meta: Metadata, original_meta: &mut Metadata) -> Result<()> {
+    /// Removes a file that does not exist on the drive anymore from the parents and the metadata
+    fn process_removal(&self, path_resolver: &mut PathResolver, id: &DriveId) -> Result<()> {
+        let parents = match path_resolver.get_parents(id) {
+            Ok(parents) => parents.clone(),
+            Err(_) => {
+                info!("Removed file was not known: {:?}", id);
+                return Ok(());
+            }
+        };
+        path_resolver.remove_relationships_for_id(&parents, id)?;
+        let meta_path = SETTINGS.get_metadata_file_path(id);
+        if meta_path.exists() {
+            std::fs::remove_file(meta_path)?;
+        }
+        parents
+            .into_iter()
+            .for_each(|parent| self.events.push(BackendEvent::Changed(parent)));
+        self.events.push(BackendEvent::Removed(id.clone()));
+        Ok(())
+    }
+
+    /// Applies the changed metadata and returns if anything was changed
+    fn process_meta_changes(new_meta: Metadata, original_meta: &mut Metadata) -> Result<bool> {
         let mut has_meta_changed = false;
 
         apply_change!(original_meta, new_meta, last_modified, has_meta_changed, where: {
@@ -132,34 +409,251 @@ impl Drive {
         apply_change!(original_meta, new_meta, size, has_meta_changed);
         apply_change!(original_meta, new_meta, permissions, has_meta_changed);
         apply_change!(original_meta, new_meta, extra_attributes, has_meta_changed);
+        apply_change!(original_meta, new_meta, parent_count, has_meta_changed);
         info!("Has changed: {}", has_meta_changed);
         if has_meta_changed {
             write_metadata_file(&original_meta)?;
         }
-        Ok(())
+        Ok(has_meta_changed)
     }
 
+    /// Moves the file to its new parents and returns if they changed.
+    ///
+    /// The relationships also need to be updated when the file was renamed, since the parents
+    /// list their children by name.
     fn process_parents_changes(
-        &mut self,
+        &self,
+        path_resolver: &mut PathResolver,
         parents: Vec<DriveId>,
         id: &DriveId,
         new_meta: &Metadata,
-    ) -> Result<()> {
-        let original_parents = self.path_resolver.get_parents(&id)?.clone();
-        if original_parents != parents {
-            info!("Parents changed: {:?}", id);
-            self.path_resolver
-                .remove_relationships_for_id(&original_parents, &new_meta.id)?;
-            self.path_resolver
-                .add_relationships_for_meta(parents, &new_meta)?;
+        renamed: bool,
+    ) -> Result<bool> {
+        let original_parents = path_resolver.get_parents(&id)?.clone();
+        if original_parents == parents && !renamed {
+            return Ok(false);
+        }
+        info!("Parents changed: {:?}", id);
+        path_resolver.remove_relationships_for_id(&original_parents, &new_meta.id)?;
+        path_resolver.add_relationships_for_meta(parents.clone(), &new_meta)?;
+        original_parents
+            .into_iter()
+            .chain(parents)
+            .for_each(|parent| self.events.push(BackendEvent::Changed(parent)));
+        Ok(true)
+    }
+
+    /// Adds the folder as another parent of the file, the file keeps its name
+    #[instrument(skip(self))]
+    pub async fn add_parent(&self, id: &DriveId, parent: &DriveId) -> Result<()> {
+        if self.offline_mode() {
+            return Err("Parents can not be added in offline mode".into());
+        }
+        let update = MetaUpdate {
+            add_parents: vec![parent.clone()],
+            ..Default::default()
+        };
+        let file = self.remote.update_meta(id, update).await?;
+        let mut path_resolver = self.path_resolver.write().await;
+        let result = self.process_change(
+            &mut path_resolver,
+            RemoteChange {
+                id: id.clone(),
+                removed: false,
+                file: Some(file),
+            },
+        );
+        path_resolver.commit()?;
+        result
+    }
+
+    /// Downloads the file and everything below it and keeps it local until
+    /// [Drive::stop_keeping_local] is called.
+    ///
+    /// The metadata of all files is fetched with batch requests first. Returns the files that
+    /// could not be kept local.
+    #[instrument(skip(self))]
+    pub async fn keep_local(&self, id: &DriveId) -> Result<Vec<DriveId>> {
+        if self.offline_mode() {
+            return Err("Files can not be kept local in offline mode".into());
+        }
+        let ids = self.path_resolver.read().await.get_ids_below(id);
+        let mut failed = vec![];
+        let mut files = vec![];
+        for (id, result) in self.download_metas_for_files(ids).await? {
+            if result.is_err() {
+                failed.push(id);
+                continue;
+            }
+            if read_metadata_by_id(&id)?.kind == FileKind::File {
+                files.push(id);
+            }
+        }
+        let results =
+            futures::future::join_all(files.iter().map(|id| self.keep_file_local(id))).await;
+        for (id, result) in files.into_iter().zip(results) {
+            if let Err(e) = result {
+                warn!("Could not keep {} local: {}", id, e);
+                failed.push(id);
+            }
+        }
+        Ok(failed)
+    }
+    async fn keep_file_local(&self, id: &DriveId) -> Result<()> {
+        if read_metadata_by_id(id)?.state == FileState::MetadataOnly {
+            self.download_content_for_file(id).await?;
+        }
+        let mut meta = read_metadata_by_id(id)?;
+        if meta.state != FileState::Downloaded {
+            meta.state = FileState::Downloaded;
+            write_metadata_file(&meta)?;
+            self.events.push(BackendEvent::Changed(id.clone()));
+        }
+        Ok(())
+    }
+    /// Lets the file and everything below it be removed from the cache again
+    #[instrument(skip(self))]
+    pub async fn stop_keeping_local(&self, id: &DriveId) -> Result<()> {
+        let ids = self.path_resolver.read().await.get_ids_below(id);
+        for id in ids {
+            let Ok(mut meta) = read_metadata_by_id(&id) else {
+                continue;
+            };
+            if meta.state == FileState::Downloaded {
+                meta.state = FileState::Cached;
+                write_metadata_file(&meta)?;
+                self.events.push(BackendEvent::Changed(id));
+            }
         }
         Ok(())
     }
 
+    /// Moves the files to the trash with batch requests and returns the result per id
+    #[instrument(skip(self))]
+    pub async fn trash_files(
+        &self,
+        ids: Vec<DriveId>,
+    ) -> Result<HashMap<DriveId, StdResult<(), BatchItemError>>> {
+        let update = MetaUpdate {
+            trashed: Some(true),
+            ..Default::default()
+        };
+        let updates = ids.into_iter().map(|id| (id, update.clone())).collect();
+        self.update_metas(updates).await
+    }
+    /// Moves the files from one folder to another with batch requests and returns the result
+    /// per id
+    #[instrument(skip(self))]
+    pub async fn move_files(
+        &self,
+        ids: Vec<DriveId>,
+        from: &DriveId,
+        to: &DriveId,
+    ) -> Result<HashMap<DriveId, StdResult<(), BatchItemError>>> {
+        let update = MetaUpdate {
+            add_parents: vec![to.clone()],
+            remove_parents: vec![from.clone()],
+            ..Default::default()
+        };
+        let updates = ids.into_iter().map(|id| (id, update.clone())).collect();
+        self.update_metas(updates).await
+    }
+    /// Sends the changes to the remote at once and applies the files it returns
+    async fn update_metas(
+        &self,
+        updates: Vec<(DriveId, MetaUpdate)>,
+    ) -> Result<HashMap<DriveId, StdResult<(), BatchItemError>>> {
+        if self.offline_mode() {
+            return Err("Files can not be changed in offline mode".into());
+        }
+        let results = self.remote.update_metas(updates).await?;
+        let mut path_resolver = self.path_resolver.write().await;
+        let mut applied = HashMap::with_capacity(results.len());
+        for (id, result) in results {
+            let result = match result {
+                Ok(Some(file)) => {
+                    let change = RemoteChange {
+                        id: id.clone(),
+                        removed: false,
+                        file: Some(file),
+                    };
+                    if let Err(e) = self.process_change(&mut path_resolver, change) {
+                        path_resolver.commit()?;
+                        return Err(e);
+                    }
+                    Ok(())
+                }
+                Ok(None) => Err(BatchItemError::Missing),
+                Err(e) => {
+                    warn!("Could not change {}: {}", id, e);
+                    Err(e)
+                }
+            };
+            applied.insert(id, result);
+        }
+        path_resolver.commit()?;
+        Ok(applied)
+    }
+
+    /// Uploads the cached content of the file, unless it changed on the remote since it was
+    /// last synced. Returns false and sends [BackendEvent::Conflict] in that case.
+    ///
+    /// The remote does not report how far an upload is, so [BackendEvent::UploadProgress] is
+    /// sent once when the upload starts and once when it is done.
+    #[instrument(skip(self))]
+    pub async fn upload_local_change(&self, id: &DriveId) -> Result<bool> {
+        if self.offline_mode() {
+            return Err("Changes can not be uploaded in offline mode".into());
+        }
+        let _upload = self.uploads.read().await;
+        let meta = read_metadata_by_id(id)?;
+        let source = SETTINGS.get_cache_file_path(id);
+        let total = tokio::fs::metadata(&source).await?.len();
+        let remote_meta = self.remote.get_meta_for_file(id).await?.into_meta()?;
+        if remote_meta.last_modified > meta.last_modified {
+            warn!("{id} changed on the remote, not uploading the local change");
+            self.events.push(BackendEvent::Conflict(id.clone()));
+            return Ok(false);
+        }
+        self.events.push(BackendEvent::UploadProgress {
+            id: id.clone(),
+            uploaded: 0,
+            total,
+        });
+        let file = self.remote.upload_file(id, &source).await?;
+        self.events.push(BackendEvent::UploadProgress {
+            id: id.clone(),
+            uploaded: total,
+            total,
+        });
+        let mut path_resolver = self.path_resolver.write().await;
+        self.process_change(
+            &mut path_resolver,
+            RemoteChange {
+                id: id.clone(),
+                removed: false,
+                file: Some(file),
+            },
+        )?;
+        path_resolver.commit()?;
+        Ok(true)
+    }
+
     #[instrument(skip(self))]
     pub async fn ping(&self) -> Result<()> {
-        self.google_drive.ping().await
+        self.remote.ping().await
+    }
+}
+/// Downloads the content of the file into the cache and marks it as cached
+async fn download_content<R: RemoteDrive>(remote: &R, id: &DriveId) -> Result<()> {
+    let path = SETTINGS.get_cache_file_path(id);
+    remote.download_file(id, &path).await?;
+    let mut meta = read_metadata_by_id(id)?;
+    if meta.state == FileState::MetadataOnly {
+        meta.state = FileState::Cached;
+        write_metadata_file(&meta)?;
     }
+    Ok(())
 }
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TrackingState {
@@ -167,3 +661,402 @@ pub enum TrackingState {
     Tracked(DateTime<Utc>),
 }
 mod macros;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::memory_drive::MemoryDrive;
+    use crate::test_utils;
+    use gdriver_common::ipc::events::EventSubscription;
+    use std::time::Duration;
+
+    /// A drive that knows every file of the remote already
+    async fn synced_drive(remote: &MemoryDrive) -> Drive<MemoryDrive> {
+        test_utils::init_dirs();
+        let mut drive = Drive::with_remote(remote.clone(), Arc::new(EventLog::new()));
+        *drive.path_resolver.get_mut() = PathResolver::in_memory();
+        drive.get_all_file_metas().await.unwrap();
+        drive
+    }
+    async fn names_in(drive: &Drive<MemoryDrive>, parent: &DriveId) -> Vec<String> {
+        let path_resolver = drive.path_resolver.read().await;
+        match path_resolver.get_children(parent) {
+            Ok(children) => children.iter().map(|child| child.name.clone()).collect(),
+            Err(_) => vec![],
+        }
+    }
+    async fn parents_of(drive: &Drive<MemoryDrive>, id: &DriveId) -> Vec<DriveId> {
+        let path_resolver = drive.path_resolver.read().await;
+        path_resolver.get_parents(id).cloned().unwrap_or_default()
+    }
+    async fn events(drive: &Drive<MemoryDrive>) -> Vec<BackendEvent> {
+        let subscription = EventSubscription::default();
+        drive
+            .events
+            .wait_since(0, Duration::ZERO, &subscription)
+            .await
+            .events
+    }
+
+    #[tokio::test]
+    async fn initial_sync_lists_all_files() {
+        let remote = MemoryDrive::new();
+        let folder = remote.create_file(&ROOT_ID, "folder", FileKind::Directory);
+        let folder_id = DriveId::from(folder.id);
+        remote.create_file(&folder_id, "inner.txt", FileKind::File);
+        let drive = synced_drive(&remote).await;
+
+        assert_eq!(names_in(&drive, &ROOT_ID).await, vec!["folder"]);
+        assert_eq!(names_in(&drive, &folder_id).await, vec!["inner.txt"]);
+        assert!(drive.path_resolver.read().await.is_directory(&folder_id));
+    }
+
+    #[tokio::test]
+    async fn added_file_is_listed_in_its_parent() {
+        let remote = MemoryDrive::new();
+        let drive = synced_drive(&remote).await;
+        let file = remote.create_file(&ROOT_ID, "new.txt", FileKind::File);
+        let id = DriveId::from(file.id);
+        drive.update().await.unwrap();
+
+        assert_eq!(names_in(&drive, &ROOT_ID).await, vec!["new.txt"]);
+        assert_eq!(parents_of(&drive, &id).await, vec![ROOT_ID.clone()]);
+        assert!(SETTINGS.get_metadata_file_path(&id).exists());
+        let events = events(&drive).await;
+        assert!(events.contains(&BackendEvent::Changed(ROOT_ID.clone())));
+        assert!(events.contains(&BackendEvent::Changed(id)));
+    }
+
+    #[tokio::test]
+    async fn removed_file_is_gone_with_its_metadata() {
+        let remote = MemoryDrive::new();
+        let file = remote.create_file(&ROOT_ID, "gone.txt", FileKind::File);
+        let id = DriveId::from(file.id);
+        let drive = synced_drive(&remote).await;
+        remote.remove_file(&id);
+        drive.update().await.unwrap();
+
+        assert!(names_in(&drive, &ROOT_ID).await.is_empty());
+        assert!(parents_of(&drive, &id).await.is_empty());
+        assert!(!SETTINGS.get_metadata_file_path(&id).exists());
+        assert!(events(&drive).await.contains(&BackendEvent::Removed(id)));
+    }
+
+    #[tokio::test]
+    async fn moved_file_changes_its_parent() {
+        let remote = MemoryDrive::new();
+        let folder = remote.create_file(&ROOT_ID, "folder", FileKind::Directory);
+        let folder_id = DriveId::from(folder.id);
+        let file = remote.create_file(&ROOT_ID, "moving.txt", FileKind::File);
+        let id = DriveId::from(file.id);
+        let drive = synced_drive(&remote).await;
+        let update = MetaUpdate {
+            add_parents: vec![folder_id.clone()],
+            remove_parents: vec![ROOT_ID.clone()],
+            ..Default::default()
+        };
+        remote.update_meta(&id, update).await.unwrap();
+        drive.update().await.unwrap();
+
+        assert_eq!(names_in(&drive, &ROOT_ID).await, vec!["folder"]);
+        assert_eq!(names_in(&drive, &folder_id).await, vec!["moving.txt"]);
+        assert_eq!(parents_of(&drive, &id).await, vec![folder_id]);
+    }
+
+    #[tokio::test]
+    async fn renamed_file_is_listed_with_the_new_name() {
+        let remote = MemoryDrive::new();
+        let file = remote.create_file(&ROOT_ID, "old.txt", FileKind::File);
+        let id = DriveId::from(file.id);
+        let drive = synced_drive(&remote).await;
+        let update = MetaUpdate {
+            name: Some(String::from("new.txt")),
+            ..Default::default()
+        };
+        remote.update_meta(&id, update).await.unwrap();
+        drive.update().await.unwrap();
+
+        assert_eq!(names_in(&drive, &ROOT_ID).await, vec!["new.txt"]);
+        assert_eq!(read_metadata_by_id(&id).unwrap().name, "new.txt");
+    }
+
+    #[tokio::test]
+    async fn trashed_file_is_removed_from_its_folder() {
+        let remote = MemoryDrive::new();
+        let file = remote.create_file(&ROOT_ID, "trash.txt", FileKind::File);
+        let id = DriveId::from(file.id);
+        let drive = synced_drive(&remote).await;
+        let update = MetaUpdate {
+            trashed: Some(true),
+            ..Default::default()
+        };
+        remote.update_meta(&id, update).await.unwrap();
+        drive.update().await.unwrap();
+
+        assert!(names_in(&drive, &ROOT_ID).await.is_empty());
+        assert!(events(&drive).await.contains(&BackendEvent::Removed(id)));
+    }
+
+    #[tokio::test]
+    async fn trashed_files_are_skipped_by_the_initial_sync() {
+        let remote = MemoryDrive::new();
+        let mut file = remote.create_file(&ROOT_ID, "trash.txt", FileKind::File);
+        file.trashed = Some(true);
+        remote.insert_file(file, None);
+        let drive = synced_drive(&remote).await;
+
+        assert!(names_in(&drive, &ROOT_ID).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn file_with_several_parents_is_listed_in_all_of_them() {
+        let remote = MemoryDrive::new();
+        let folder = remote.create_file(&ROOT_ID, "folder", FileKind::Directory);
+        let folder_id = DriveId::from(folder.id);
+        let file = remote.create_file(&ROOT_ID, "linked.txt", FileKind::File);
+        let id = DriveId::from(file.id);
+        let drive = synced_drive(&remote).await;
+
+        let add = MetaUpdate {
+            add_parents: vec![folder_id.clone()],
+            ..Default::default()
+        };
+        remote.update_meta(&id, add).await.unwrap();
+        drive.update().await.unwrap();
+        assert_eq!(
+            parents_of(&drive, &id).await,
+            vec![ROOT_ID.clone(), folder_id.clone()]
+        );
+        assert_eq!(names_in(&drive, &folder_id).await, vec!["linked.txt"]);
+        assert!(names_in(&drive, &ROOT_ID)
+            .await
+            .contains(&String::from("linked.txt")));
+        assert_eq!(read_metadata_by_id(&id).unwrap().parent_count, 2);
+
+        let remove = MetaUpdate {
+            remove_parents: vec![ROOT_ID.clone()],
+            ..Default::default()
+        };
+        remote.update_meta(&id, remove).await.unwrap();
+        drive.update().await.unwrap();
+        assert_eq!(parents_of(&drive, &id).await, vec![folder_id]);
+        assert_eq!(names_in(&drive, &ROOT_ID).await, vec!["folder"]);
+    }
+
+    #[tokio::test]
+    async fn conflicting_names_get_a_stable_suffix() {
+        let remote = MemoryDrive::new();
+        let drive = synced_drive(&remote).await;
+        let first = remote.create_file(&ROOT_ID, "report.pdf", FileKind::File);
+        let second = remote.create_file(&ROOT_ID, "report.pdf", FileKind::File);
+        drive.update().await.unwrap();
+
+        let names = names_in(&drive, &ROOT_ID).await;
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[0], "report.pdf");
+        let suffixed = names[1].clone();
+        assert!(suffixed.starts_with("report (") && suffixed.ends_with(").pdf"));
+
+        // the other file keeps its name, so paths that were handed out stay valid
+        remote.remove_file(&DriveId::from(first.id));
+        drive.update().await.unwrap();
+        assert_eq!(names_in(&drive, &ROOT_ID).await, vec![suffixed.clone()]);
+        let path_resolver = drive.path_resolver.read().await;
+        assert_eq!(
+            path_resolver.get_id_from_parent_and_name(&suffixed, &ROOT_ID),
+            Some(DriveId::from(second.id))
+        );
+    }
+
+    #[tokio::test]
+    async fn changes_before_the_start_token_are_not_listed_again() {
+        let remote = MemoryDrive::new();
+        remote.create_file(&ROOT_ID, "listed.txt", FileKind::File);
+        let mut syncing = remote.clone();
+        syncing.get_change_start_token().await.unwrap();
+
+        assert!(syncing.get_changes().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn removal_of_an_unknown_file_is_ignored() {
+        let remote = MemoryDrive::new();
+        let file = remote.create_file(&ROOT_ID, "kept.txt", FileKind::File);
+        let drive = synced_drive(&remote).await;
+        remote.push_change(RemoteChange {
+            id: DriveId::from("memory_unknown"),
+            removed: true,
+            file: None,
+        });
+        drive.update().await.unwrap();
+
+        assert_eq!(names_in(&drive, &ROOT_ID).await, vec!["kept.txt"]);
+        assert_eq!(
+            parents_of(&drive, &DriveId::from(file.id)).await,
+            vec![ROOT_ID.clone()]
+        );
+    }
+
+    #[tokio::test]
+    async fn changes_are_not_fetched_in_offline_mode() {
+        let remote = MemoryDrive::new();
+        let drive = synced_drive(&remote).await;
+        drive.set_offline_mode(true);
+        remote.create_file(&ROOT_ID, "later.txt", FileKind::File);
+        drive.update().await.unwrap();
+        assert!(names_in(&drive, &ROOT_ID).await.is_empty());
+
+        drive.set_offline_mode(false);
+        drive.update().await.unwrap();
+        assert_eq!(names_in(&drive, &ROOT_ID).await, vec!["later.txt"]);
+    }
+
+    #[tokio::test]
+    async fn prefetch_keeps_the_local_state_of_known_children() {
+        let remote = MemoryDrive::new();
+        let known = DriveId::from(remote.create_file(&ROOT_ID, "known.txt", FileKind::File).id);
+        let missing = DriveId::from(remote.create_file(&ROOT_ID, "new.txt", FileKind::File).id);
+        let drive = synced_drive(&remote).await;
+        let mut known_meta = read_metadata_by_id(&known).unwrap();
+        known_meta.state = FileState::Downloaded;
+        write_metadata_file(&known_meta).unwrap();
+        std::fs::remove_file(SETTINGS.get_metadata_file_path(&missing)).unwrap();
+        drive.prefetch_children(&ROOT_ID).await.unwrap();
+
+        assert_eq!(
+            read_metadata_by_id(&known).unwrap().state,
+            FileState::Downloaded
+        );
+        assert!(read_metadata_by_id(&missing).is_ok());
+    }
+
+    #[tokio::test]
+    async fn trashed_files_are_removed_from_their_folders() {
+        let remote = MemoryDrive::new();
+        let first = DriveId::from(remote.create_file(&ROOT_ID, "a.txt", FileKind::File).id);
+        let second = DriveId::from(remote.create_file(&ROOT_ID, "b.txt", FileKind::File).id);
+        let unknown = DriveId::from("unknown");
+        let drive = synced_drive(&remote).await;
+        let ids = vec![first, second, unknown.clone()];
+        let results = drive.trash_files(ids).await.unwrap();
+
+        assert!(names_in(&drive, &ROOT_ID).await.is_empty());
+        assert_eq!(results.values().filter(|result| result.is_ok()).count(), 2);
+        assert!(results[&unknown].is_err());
+    }
+
+    #[tokio::test]
+    async fn moved_files_are_only_in_the_new_folder() {
+        let remote = MemoryDrive::new();
+        let folder = remote.create_file(&ROOT_ID, "folder", FileKind::Directory);
+        let folder_id = DriveId::from(folder.id);
+        let first = DriveId::from(remote.create_file(&ROOT_ID, "a.txt", FileKind::File).id);
+        let second = DriveId::from(remote.create_file(&ROOT_ID, "b.txt", FileKind::File).id);
+        let drive = synced_drive(&remote).await;
+        let ids = vec![first.clone(), second];
+        let results = drive.move_files(ids, &ROOT_ID, &folder_id).await.unwrap();
+
+        assert!(results.values().all(|result| result.is_ok()));
+        assert_eq!(names_in(&drive, &ROOT_ID).await, vec!["folder"]);
+        let mut moved = names_in(&drive, &folder_id).await;
+        moved.sort();
+        assert_eq!(moved, vec!["a.txt", "b.txt"]);
+        assert_eq!(parents_of(&drive, &first).await, vec![folder_id]);
+    }
+
+    #[tokio::test]
+    async fn files_below_a_kept_folder_are_downloaded() {
+        let remote = MemoryDrive::new();
+        let folder = remote.create_file(&ROOT_ID, "folder", FileKind::Directory);
+        let folder_id = DriveId::from(folder.id);
+        let mut file = remote.create_file(&folder_id, "inner.txt", FileKind::File);
+        file.size = Some(5);
+        let id = DriveId::from(file.id.clone());
+        remote.insert_file(file, Some(b"inner".to_vec()));
+        let drive = synced_drive(&remote).await;
+
+        assert!(drive.keep_local(&folder_id).await.unwrap().is_empty());
+        assert_eq!(
+            read_metadata_by_id(&id).unwrap().state,
+            FileState::Downloaded
+        );
+        let cached = std::fs::read(SETTINGS.get_cache_file_path(&id)).unwrap();
+        assert_eq!(cached, b"inner");
+
+        drive.stop_keeping_local(&folder_id).await.unwrap();
+        assert_eq!(read_metadata_by_id(&id).unwrap().state, FileState::Cached);
+    }
+
+    #[tokio::test]
+    async fn uploaded_change_reports_its_progress() {
+        let remote = MemoryDrive::new();
+        let id = DriveId::from(remote.create_file(&ROOT_ID, "local.txt", FileKind::File).id);
+        let drive = synced_drive(&remote).await;
+        std::fs::write(SETTINGS.get_cache_file_path(&id), "local").unwrap();
+
+        assert!(drive.upload_local_change(&id).await.unwrap());
+        assert_eq!(remote.content(&id).unwrap(), b"local");
+        let events = events(&drive).await;
+        assert!(events.contains(&BackendEvent::UploadProgress {
+            id: id.clone(),
+            uploaded: 0,
+            total: 5,
+        }));
+        assert!(events.contains(&BackendEvent::UploadProgress {
+            id: id.clone(),
+            uploaded: 5,
+            total: 5,
+        }));
+        assert_eq!(read_metadata_by_id(&id).unwrap().size, 5);
+    }
+
+    #[tokio::test]
+    async fn waiting_for_uploads_waits_for_the_running_one() {
+        let remote = MemoryDrive::new();
+        let id = DriveId::from(remote.create_file(&ROOT_ID, "local.txt", FileKind::File).id);
+        let drive = Arc::new(synced_drive(&remote).await);
+        std::fs::write(SETTINGS.get_cache_file_path(&id), "local").unwrap();
+        let upload = drive.uploads.read().await;
+        let waiting = tokio::spawn({
+            let drive = drive.clone();
+            async move { drive.wait_for_uploads().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(!waiting.is_finished());
+        drop(upload);
+        waiting.await.unwrap();
+        assert!(drive.upload_local_change(&id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn change_is_not_uploaded_over_a_newer_remote_one() {
+        let remote = MemoryDrive::new();
+        let mut file = remote.create_file(&ROOT_ID, "both.txt", FileKind::File);
+        let id = DriveId::from(file.id.clone());
+        let drive = synced_drive(&remote).await;
+        file.modified_time = Some(Utc::now() + chrono::Duration::hours(1));
+        remote.insert_file(file, Some(b"remote".to_vec()));
+        std::fs::write(SETTINGS.get_cache_file_path(&id), "local").unwrap();
+
+        assert!(!drive.upload_local_change(&id).await.unwrap());
+        assert_eq!(remote.content(&id).unwrap(), b"remote");
+        assert!(events(&drive).await.contains(&BackendEvent::Conflict(id)));
+    }
+
+    #[tokio::test]
+    async fn update_waiting_for_a_running_one_gets_the_newer_changes() {
+        let remote = MemoryDrive::new();
+        let drive = synced_drive(&remote).await;
+        // stands in for an update that listed the changes already
+        let running = drive.syncing_remote.lock().await;
+        let update = drive.update();
+        tokio::pin!(update);
+        assert!(futures::poll!(&mut update).is_pending());
+        remote.create_file(&ROOT_ID, "meanwhile.txt", FileKind::File);
+        drop(running);
+        update.await.unwrap();
+
+        assert_eq!(names_in(&drive, &ROOT_ID).await, vec!["meanwhile.txt"]);
+    }
+}
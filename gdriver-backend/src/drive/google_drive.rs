@@ -1,55 +1,30 @@
+use crate::drive::remote::{BatchItemResult, FileData, MetaUpdate, RemoteChange, RemoteDrive};
 use crate::prelude::*;
 use chrono::{DateTime, Utc};
 use const_format::formatcp;
-use gdriver_common::drive_structure::meta::{FileKind, FileState, Metadata, DEFAULT_PERMISSIONS};
-use gdriver_common::time_utils::datetime_to_timestamp;
+use gdriver_common::drive_structure::meta::FileKind;
 use gdriver_common::{ipc::gdriver_service::SETTINGS, prelude::*};
 use google_drive3::api::File;
 use google_drive3::{
     api::{Change, Scope},
-    hyper::{client::HttpConnector, Client},
+    hyper::{self, body::HttpBody, client::HttpConnector, Client},
     hyper_rustls::{self, HttpsConnector},
     oauth2, DriveHub,
 };
-use serde::{Deserialize, Serialize};
 use std::any::type_name;
+use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
 
-const FIELDS_FILE: &'static str = "id, name, size, mimeType, kind, md5Checksum, parents, trashed, createdTime, modifiedTime, viewedByMeTime, capabilities";
-#[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Hash)]
-pub struct FileData {
-    pub id: String,
-    pub name: String,
-    pub size: Option<i64>,
-    pub mime_type: String,
-    pub kind: FileKind,
-    pub md5_checksum: Option<String>,
-    pub parents: Vec<String>,
-    pub trashed: Option<bool>,
-    pub created_time: Option<DateTime<Utc>>,
-    pub modified_time: Option<DateTime<Utc>>,
-    pub viewed_by_me_time: Option<DateTime<Utc>>,
-}
-
-impl FileData {
-    pub(crate) fn into_meta(self) -> Result<Metadata> {
-        let last_modified = datetime_to_timestamp(self.modified_time.unwrap_or_default())?;
-        Ok(Metadata {
-            id: self.id.into(),
-            kind: self.kind,
-            name: self.name,
-            size: self.size.unwrap_or_default() as u64,
-            last_accessed: datetime_to_timestamp(self.viewed_by_me_time.unwrap_or_default())?,
-            last_modified,
-            extra_attributes: Default::default(),
-            state: FileState::MetadataOnly,
-            permissions: DEFAULT_PERMISSIONS, //TODO: parse permissions
-            last_metadata_changed: last_modified,
-        })
-    }
-}
+mod batch;
+mod retry;
+use retry::RateLimiter;
 
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+const FIELDS_FILE: &'static str = "id, name, size, mimeType, kind, md5Checksum, parents, trashed, createdTime, modifiedTime, viewedByMeTime, capabilities";
 impl FileData {
     pub(crate) fn convert_from_api_file(file: File) -> Self {
         let kind = file.kind.unwrap_or_default();
@@ -57,7 +32,10 @@ impl FileData {
             "Converting file with id {:?} with parent: {:?}",
             file.id, file.parents
         );
+        // the API always returns `drive#file` as kind, folders are marked by their mime type
+        let is_folder = file.mime_type.as_deref() == Some(FOLDER_MIME_TYPE);
         let kind = match kind.as_str() {
+            _ if is_folder => FileKind::Directory,
             "drive#file" => FileKind::File,
             "drive#folder" => FileKind::Directory,
             "drive#link" => FileKind::Symlink,
@@ -82,28 +60,50 @@ const FIELDS_CHANGE: &str = formatcp!(
     "nextPageToken, newStartPageToken, changes(removed, fileId, changeType, file({}))",
     FIELDS_FILE
 );
+/// The root of all Google Drive API urls, unless it is replaced by the configuration
+const DEFAULT_ROOT_URL: &str = "https://www.googleapis.com/";
 #[derive(Clone)]
 pub struct GoogleDrive {
     hub: DriveHub<HttpsConnector<HttpConnector>>,
     changes_start_page_token: Option<String>,
+    /// Where [GoogleDrive::changes_start_page_token] is kept between runs
+    changes_file_path: PathBuf,
     root_alt_id: DriveId,
+    /// The url all API urls are relative to, ends with a slash
+    root_url: String,
+    rate_limiter: Arc<RateLimiter>,
 }
 
 impl GoogleDrive {
     #[instrument]
     pub(crate) async fn get_all_file_metas(&self) -> Result<Vec<FileData>> {
+        self.list_files(None).await
+    }
+    /// Gets the metadata of all direct children of the folder in one paged request
+    #[instrument]
+    pub(crate) async fn get_children_metas(&self, parent: &DriveId) -> Result<Vec<FileData>> {
+        let query = format!("'{}' in parents and trashed = false", parent.as_ref());
+        self.list_files(Some(&query)).await
+    }
+    async fn list_files(&self, query: Option<&str>) -> Result<Vec<FileData>> {
         let mut page_token: Option<String> = None;
         let mut files = Vec::new();
         loop {
             let (response, body) = self
-                .hub
-                .files()
-                .list()
-                .supports_all_drives(false)
-                .spaces("drive")
-                .page_token(&page_token.unwrap_or_default())
-                .param("fields", &format!("nextPageToken, files({})", FIELDS_FILE))
-                .doit()
+                .retry("files.list", || {
+                    let mut request = self
+                        .hub
+                        .files()
+                        .list()
+                        .supports_all_drives(false)
+                        .spaces("drive")
+                        .page_token(page_token.as_deref().unwrap_or_default())
+                        .param("fields", &format!("nextPageToken, files({})", FIELDS_FILE));
+                    if let Some(query) = query {
+                        request = request.q(query);
+                    }
+                    request.doit()
+                })
                 .await?;
             page_token = body.next_page_token;
             if response.status().is_success() {
@@ -131,12 +131,14 @@ impl GoogleDrive {
     #[instrument]
     pub(crate) async fn get_meta_for_file(&self, id: &DriveId) -> Result<FileData> {
         let (response, mut body) = self
-            .hub
-            .files()
-            .get(id.as_ref())
-            .supports_all_drives(false)
-            .param("fields", &FIELDS_FILE)
-            .doit()
+            .retry("files.get", || {
+                self.hub
+                    .files()
+                    .get(id.as_ref())
+                    .supports_all_drives(false)
+                    .param("fields", &FIELDS_FILE)
+                    .doit()
+            })
             .await?;
         if response.status().is_success() {
             self.map_in_file(Some(&mut body));
@@ -144,21 +146,126 @@ impl GoogleDrive {
         }
         Err("Error while fetching metadata".into())
     }
+    /// Downloads the content of the file to the target path.
+    ///
+    /// The content is written to a temporary file first, so the target is never incomplete.
+    #[instrument]
+    pub(crate) async fn download_file(&self, id: &DriveId, target: &Path) -> Result<()> {
+        let (response, _) = self
+            .retry("files.get media", || {
+                self.hub
+                    .files()
+                    .get(id.as_ref())
+                    .supports_all_drives(false)
+                    .param("alt", "media")
+                    .doit()
+            })
+            .await?;
+        if !response.status().is_success() {
+            error!("Could not download file: {:?}", response);
+            return Err("Could not download file".into());
+        }
+        let partial_target = target.with_extension("part");
+        let mut file = fs::File::create(&partial_target).await?;
+        // written chunk by chunk, so large files are never held in memory as a whole
+        let mut body = response.into_body();
+        while let Some(chunk) = body.data().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+        fs::rename(partial_target, target).await?;
+        Ok(())
+    }
+    #[instrument]
+    pub(crate) async fn upload_file(&self, id: &DriveId, source: &Path) -> Result<FileData> {
+        let (response, mut body) = self
+            .retry("files.update media", move || async move {
+                let content = std::fs::File::open(source).map_err(google_drive3::Error::Io)?;
+                self.hub
+                    .files()
+                    .update(File::default(), id.as_ref())
+                    .supports_all_drives(false)
+                    .param("fields", FIELDS_FILE)
+                    .upload(content, mime::APPLICATION_OCTET_STREAM)
+                    .await
+            })
+            .await?;
+        if response.status().is_success() {
+            self.map_in_file(Some(&mut body));
+            return Ok(FileData::convert_from_api_file(body));
+        }
+        Err("Could not upload file".into())
+    }
+    #[instrument]
+    pub(crate) async fn update_meta(&self, id: &DriveId, update: MetaUpdate) -> Result<FileData> {
+        let join_ids = |ids: &Vec<DriveId>| {
+            ids.iter()
+                .map(|id| self.unmap_id(id))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let add_parents = join_ids(&update.add_parents);
+        let remove_parents = join_ids(&update.remove_parents);
+        let (response, mut body) = self
+            .retry("files.update", || {
+                let file = File {
+                    name: update.name.clone(),
+                    trashed: update.trashed,
+                    ..Default::default()
+                };
+                let mut request = self
+                    .hub
+                    .files()
+                    .update(file, id.as_ref())
+                    .supports_all_drives(false)
+                    .param("fields", FIELDS_FILE);
+                if !add_parents.is_empty() {
+                    request = request.add_parents(&add_parents);
+                }
+                if !remove_parents.is_empty() {
+                    request = request.remove_parents(&remove_parents);
+                }
+                request.doit_without_upload()
+            })
+            .await?;
+        if response.status().is_success() {
+            self.map_in_file(Some(&mut body));
+            return Ok(FileData::convert_from_api_file(body));
+        }
+        Err("Could not update metadata".into())
+    }
+    #[instrument]
+    pub(crate) async fn delete_file(&self, id: &DriveId) -> Result<()> {
+        let response = self
+            .retry("files.delete", || {
+                self.hub
+                    .files()
+                    .delete(id.as_ref())
+                    .supports_all_drives(false)
+                    .doit()
+            })
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        Err("Could not delete file".into())
+    }
 }
 
 impl GoogleDrive {
     #[instrument]
     pub(crate) async fn new() -> Result<Self> {
-        trace!("Initializing GoogleDrive client.");
-        let auth = oauth2::read_application_secret("auth/client_secret.json").await?;
-
-        let auth = oauth2::InstalledFlowAuthenticator::builder(
-            auth,
-            oauth2::InstalledFlowReturnMethod::HTTPRedirect,
+        let config = CONFIGURATION.current();
+        Self::with_api(
+            config.drive_api_root_url.clone(),
+            config.drive_api_skip_auth,
         )
-        .persist_tokens_to_disk("auth/tokens.json")
-        .build()
-        .await?;
+        .await
+    }
+    /// Connects to the API at `root_url` instead of the one from the configuration
+    #[instrument]
+    pub(crate) async fn with_api(root_url: Option<String>, skip_auth: bool) -> Result<Self> {
+        trace!("Initializing GoogleDrive client.");
         let http_client = Client::builder().build(
             hyper_rustls::HttpsConnectorBuilder::new()
                 .with_native_roots()
@@ -167,12 +274,39 @@ impl GoogleDrive {
                 // .enable_http2()
                 .build(),
         );
-        let hub = DriveHub::new(http_client, auth);
+        let mut hub = if skip_auth {
+            warn!("Sending requests to the Google Drive API without authentication");
+            DriveHub::new(http_client, google_drive3::client::NoToken)
+        } else {
+            let auth = oauth2::read_application_secret("auth/client_secret.json").await?;
+            let auth = oauth2::InstalledFlowAuthenticator::builder(
+                auth,
+                oauth2::InstalledFlowReturnMethod::HTTPRedirect,
+            )
+            .persist_tokens_to_disk("auth/tokens.json")
+            .build()
+            .await?;
+            DriveHub::new(http_client, auth)
+        };
+        let root_url = match root_url {
+            Some(root_url) => {
+                info!("Using {} as root of the Google Drive API", root_url);
+                hub.root_url(root_url.clone());
+                hub.base_url(format!("{}drive/v3/", root_url));
+                root_url
+            }
+            None => DEFAULT_ROOT_URL.to_string(),
+        };
 
         let mut drive = GoogleDrive {
             hub,
             changes_start_page_token: None,
+            changes_file_path: SETTINGS.get_changes_file_path(),
             root_alt_id: ROOT_ID.clone(),
+            root_url,
+            rate_limiter: Arc::new(RateLimiter::new(
+                CONFIGURATION.current().api_requests_per_second,
+            )),
         };
         info!("Updating ROOT alt");
         drive.update_alt_root().await?;
@@ -182,11 +316,13 @@ impl GoogleDrive {
     }
     async fn update_alt_root(&mut self) -> Result<()> {
         let (response, body) = self
-            .hub
-            .files()
-            .get(ROOT_ID.as_ref())
-            .param("fields", "id")
-            .doit()
+            .retry("files.get root", || {
+                self.hub
+                    .files()
+                    .get(ROOT_ID.as_ref())
+                    .param("fields", "id")
+                    .doit()
+            })
             .await?;
         if response.status().is_success() {
             self.root_alt_id = body.id.unwrap_or(ROOT_ID.to_string()).into();
@@ -197,12 +333,14 @@ impl GoogleDrive {
     #[instrument]
     pub(crate) async fn ping(&self) -> Result<()> {
         let (response, body) = self
-            .hub
-            .about()
-            .get()
-            .param("fields", "user(emailAddress)")
-            .add_scope(Scope::Readonly)
-            .doit()
+            .retry("about.get", || {
+                self.hub
+                    .about()
+                    .get()
+                    .param("fields", "user(emailAddress)")
+                    .add_scope(Scope::Readonly)
+                    .doit()
+            })
             .await?;
         let status_code = response.status();
         let email = body
@@ -230,17 +368,19 @@ impl GoogleDrive {
         while let Some(current_page_token) = page_token {
             info!("Getting changes with page token: {}", current_page_token);
             let (response, body) = self
-                .hub
-                .changes()
-                .list(current_page_token.as_str())
-                .param("fields", FIELDS_CHANGE)
-                .page_size(2) //TODO: Change this to a more reasonable value
-                .include_corpus_removals(true) //TODO3: Check if this is useful
-                .supports_all_drives(false)
-                .restrict_to_my_drive(true)
-                .include_removed(true)
-                .include_items_from_all_drives(false)
-                .doit()
+                .retry("changes.list", || {
+                    self.hub
+                        .changes()
+                        .list(current_page_token.as_str())
+                        .param("fields", FIELDS_CHANGE)
+                        .page_size(2) //TODO: Change this to a more reasonable value
+                        .include_corpus_removals(true) //TODO3: Check if this is useful
+                        .supports_all_drives(false)
+                        .restrict_to_my_drive(true)
+                        .include_removed(true)
+                        .include_items_from_all_drives(false)
+                        .doit()
+                })
                 .await?;
             if let Some(token) = body.new_start_page_token {
                 self.set_change_start_token(token).await?;
@@ -265,7 +405,10 @@ impl GoogleDrive {
             return Ok(());
         }
         info!("Setting start page token: {}", token);
-        fs::write(SETTINGS.get_changes_file_path(), token.clone()).await?;
+        // written next to it first, so the token is never cut off if the backend gets killed
+        let temporary_path = self.changes_file_path.with_extension("tmp");
+        fs::write(&temporary_path, &token).await?;
+        fs::rename(&temporary_path, &self.changes_file_path).await?;
         self.changes_start_page_token = Some(token);
         Ok(())
     }
@@ -299,7 +442,7 @@ impl GoogleDrive {
     }
 
     pub async fn get_local_change_start_token(&mut self) -> Option<String> {
-        self.changes_start_page_token = fs::read_to_string(SETTINGS.get_changes_file_path())
+        self.changes_start_page_token = fs::read_to_string(&self.changes_file_path)
             .await
             .ok()
             .map(|s| s.trim().to_string());
@@ -308,11 +451,13 @@ impl GoogleDrive {
     async fn update_change_start_token_from_api(&mut self) -> Result<()> {
         info!("Getting start page token from API");
         let (response, body) = self
-            .hub
-            .changes()
-            .get_start_page_token()
-            .supports_all_drives(false)
-            .doit()
+            .retry("changes.getStartPageToken", || {
+                self.hub
+                    .changes()
+                    .get_start_page_token()
+                    .supports_all_drives(false)
+                    .doit()
+            })
             .await?;
         if response.status().is_success() {
             let token = body.start_page_token.clone().unwrap_or_default();
@@ -352,6 +497,63 @@ impl GoogleDrive {
     }
     //endregion
 }
+impl RemoteDrive for GoogleDrive {
+    async fn ping(&self) -> Result<()> {
+        GoogleDrive::ping(self).await
+    }
+    async fn get_all_file_metas(&self) -> Result<Vec<FileData>> {
+        GoogleDrive::get_all_file_metas(self).await
+    }
+    async fn get_children_metas(&self, parent: &DriveId) -> Result<Vec<FileData>> {
+        GoogleDrive::get_children_metas(self, parent).await
+    }
+    async fn get_meta_for_file(&self, id: &DriveId) -> Result<FileData> {
+        GoogleDrive::get_meta_for_file(self, id).await
+    }
+    async fn get_metas_for_files(
+        &self,
+        ids: Vec<DriveId>,
+    ) -> Result<HashMap<DriveId, BatchItemResult>> {
+        self.batch_get_metas(ids).await
+    }
+    async fn has_local_change_token(&mut self) -> bool {
+        GoogleDrive::has_local_change_token(self).await
+    }
+    async fn get_change_start_token(&mut self) -> Result<String> {
+        GoogleDrive::get_change_start_token(self).await
+    }
+    async fn get_changes(&mut self) -> Result<Vec<RemoteChange>> {
+        let changes = GoogleDrive::get_changes(self).await?;
+        changes
+            .into_iter()
+            .map(|change| {
+                Ok(RemoteChange {
+                    id: change.file_id.ok_or("No file id in change")?.into(),
+                    removed: change.removed.unwrap_or_default(),
+                    file: change.file.map(FileData::convert_from_api_file),
+                })
+            })
+            .collect()
+    }
+    async fn download_file(&self, id: &DriveId, target: &Path) -> Result<()> {
+        GoogleDrive::download_file(self, id, target).await
+    }
+    async fn upload_file(&self, id: &DriveId, source: &Path) -> Result<FileData> {
+        GoogleDrive::upload_file(self, id, source).await
+    }
+    async fn update_meta(&self, id: &DriveId, update: MetaUpdate) -> Result<FileData> {
+        GoogleDrive::update_meta(self, id, update).await
+    }
+    async fn update_metas(
+        &self,
+        updates: Vec<(DriveId, MetaUpdate)>,
+    ) -> Result<HashMap<DriveId, BatchItemResult>> {
+        self.batch_update_metas(updates).await
+    }
+    async fn delete_file(&self, id: &DriveId) -> Result<()> {
+        GoogleDrive::delete_file(self, id).await
+    }
+}
 //region debug & display traits
 impl Debug for GoogleDrive {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -368,3 +570,105 @@ impl Display for GoogleDrive {
     }
 }
 //endregion
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use gdriver_drive_stub::DriveStub;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A drive that talks to a new stub and keeps its change token in its own file, so the
+    /// tests do not continue from the changes of each other
+    pub(super) async fn stub_drive() -> (DriveStub, GoogleDrive) {
+        static NEXT_DRIVE: AtomicUsize = AtomicUsize::new(0);
+        test_utils::init_dirs();
+        let stub = DriveStub::start().await.unwrap();
+        let mut drive = GoogleDrive::with_api(Some(stub.root_url()), true)
+            .await
+            .unwrap();
+        let number = NEXT_DRIVE.fetch_add(1, Ordering::Relaxed);
+        drive.changes_file_path = drive
+            .changes_file_path
+            .with_file_name(format!("stub_changes_{number}"));
+        (stub, drive)
+    }
+
+    #[tokio::test]
+    async fn root_alias_is_replaced_by_the_root_id() {
+        let (stub, drive) = stub_drive().await;
+        let id = stub.create_file(&stub.root_id(), "file.txt", b"");
+
+        assert_eq!(drive.root_alt_id.as_ref(), stub.root_id());
+        let file = drive.get_meta_for_file(&id.into()).await.unwrap();
+        assert_eq!(file.name, "file.txt");
+        assert_eq!(file.parents, vec![ROOT_ID.0.clone()]);
+    }
+
+    #[tokio::test]
+    async fn all_pages_of_files_are_listed() {
+        let (stub, drive) = stub_drive().await;
+        // more than the 100 files that fit on one page
+        let folder = stub.create_folder("root", "folder");
+        for i in 0..150 {
+            stub.create_file(&folder, &format!("file_{}.txt", i), b"");
+        }
+
+        let files = drive.get_all_file_metas().await.unwrap();
+        assert_eq!(files.len(), 151);
+        let folder = files.iter().find(|file| file.id == folder).unwrap();
+        assert_eq!(folder.kind, FileKind::Directory);
+        assert_eq!(folder.parents, vec![ROOT_ID.0.clone()]);
+    }
+
+    #[tokio::test]
+    async fn only_children_of_the_folder_are_listed() {
+        let (stub, drive) = stub_drive().await;
+        let folder = stub.create_folder("root", "folder");
+        stub.create_file(&folder, "inner.txt", b"");
+        stub.create_file("root", "outer.txt", b"");
+
+        let children = drive.get_children_metas(&folder.into()).await.unwrap();
+        let names: Vec<&str> = children.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, vec!["inner.txt"]);
+    }
+
+    #[tokio::test]
+    async fn changes_are_listed_from_the_start_token_over_all_pages() {
+        let (stub, mut drive) = stub_drive().await;
+        let removed = stub.create_file("root", "removed.txt", b"");
+        drive.get_change_start_token().await.unwrap();
+        let added = stub.create_file("root", "added.txt", b"");
+        stub.rename(&added, "renamed.txt").unwrap();
+        stub.remove(&removed).unwrap();
+
+        let changes = RemoteDrive::get_changes(&mut drive).await.unwrap();
+        assert_eq!(changes.len(), 3);
+        let last_added = changes.iter().rev().find(|c| c.id.as_ref() == added);
+        let file = last_added.unwrap().file.as_ref().unwrap();
+        assert_eq!(file.name, "renamed.txt");
+        assert_eq!(file.parents, vec![ROOT_ID.0.clone()]);
+        let removal = changes.iter().find(|c| c.id.as_ref() == removed).unwrap();
+        assert!(removal.removed);
+        assert!(drive.get_changes().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn uploaded_content_is_downloaded_again() {
+        let (stub, drive) = stub_drive().await;
+        let id = DriveId::from(stub.create_file("root", "file.txt", b"old content"));
+        let target = SETTINGS.cache_path().join("google_drive_round_trip");
+
+        drive.download_file(&id, &target).await.unwrap();
+        assert_eq!(fs::read(&target).await.unwrap(), b"old content");
+
+        fs::write(&target, b"new content").await.unwrap();
+        let file = drive.upload_file(&id, &target).await.unwrap();
+        assert_eq!(file.size, Some(11));
+        assert_eq!(stub.content(id.as_ref()).unwrap(), b"new content");
+
+        fs::remove_file(&target).await.unwrap();
+        drive.download_file(&id, &target).await.unwrap();
+        assert_eq!(fs::read(&target).await.unwrap(), b"new content");
+    }
+}
@@ -0,0 +1,532 @@
+use super::retry::{backoff, is_retryable_error_value, is_retryable_status};
+use super::*;
+use crate::drive::remote::{BatchItemError, BatchItemResult};
+use google_drive3::client::GetToken;
+use google_drive3::hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use google_drive3::hyper::{Body, Method, Request, StatusCode, Uri};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// The path of the batch endpoint, relative to the root url of the API
+const BATCH_PATH: &str = "batch/drive/v3";
+/// The path of the files endpoint, relative to the host the batch request is sent to
+const FILES_PATH: &str = "/drive/v3/files";
+/// Drive does not accept more than 100 calls in a single batch request
+pub(crate) const MAX_BATCH_SIZE: usize = 100;
+const BOUNDARY: &str = "gdriver2_batch_boundary";
+const BATCH_CONTENT_TYPE: &str = formatcp!("multipart/mixed; boundary={}", BOUNDARY);
+
+/// A single metadata call that is sent as part of a batch request
+#[derive(Debug, Clone)]
+enum BatchCall {
+    Get,
+    Update(MetaUpdate),
+}
+/// A call of a batch request with the ids as the API knows them
+#[derive(Debug, Clone, PartialEq)]
+struct ApiCall {
+    id: String,
+    /// The fields of the file that are changed, [None] to get the file
+    update: Option<Map<String, Value>>,
+    /// Additional query parameters, like `addParents`
+    params: Vec<(&'static str, String)>,
+}
+impl ApiCall {
+    fn get(id: String) -> Self {
+        Self {
+            id,
+            update: None,
+            params: vec![],
+        }
+    }
+}
+/// The response to a single call of a batch request
+#[derive(Debug)]
+struct ResponsePart {
+    /// The index of the call in the batch request
+    index: usize,
+    result: StdResult<Option<File>, BatchItemError>,
+    /// The call failed in a way that goes away by itself, like exceeding the rate limit
+    retryable: bool,
+}
+
+impl GoogleDrive {
+    /// Gets the metadata of the files in as few round trips as possible and returns the
+    /// result of each call by id.
+    ///
+    /// Errors of single calls are reported per id, only failing to send a whole batch returns
+    /// an error.
+    #[instrument(skip(self, ids))]
+    pub(crate) async fn batch_get_metas(
+        &self,
+        ids: Vec<DriveId>,
+    ) -> Result<HashMap<DriveId, BatchItemResult>> {
+        info!("Getting the metadata of {} files as batch", ids.len());
+        let calls = ids.into_iter().map(|id| (id, BatchCall::Get)).collect();
+        self.batch(calls).await
+    }
+    /// Changes the metadata of the files in as few round trips as possible and returns the
+    /// changed file of each call by id, like [GoogleDrive::batch_get_metas]
+    #[instrument(skip(self, updates))]
+    pub(crate) async fn batch_update_metas(
+        &self,
+        updates: Vec<(DriveId, MetaUpdate)>,
+    ) -> Result<HashMap<DriveId, BatchItemResult>> {
+        info!("Updating the metadata of {} files as batch", updates.len());
+        let calls = updates
+            .into_iter()
+            .map(|(id, update)| (id, BatchCall::Update(update)))
+            .collect();
+        self.batch(calls).await
+    }
+
+    /// Sends the calls in batches of up to [MAX_BATCH_SIZE].
+    ///
+    /// Calls that failed in a way that goes away by itself are sent again in a new batch after
+    /// a backoff, up to the configured number of retries.
+    async fn batch(
+        &self,
+        calls: Vec<(DriveId, BatchCall)>,
+    ) -> Result<HashMap<DriveId, BatchItemResult>> {
+        let max_retries = CONFIGURATION.current().api_max_retries;
+        let mut results = HashMap::with_capacity(calls.len());
+        for chunk in calls.chunks(MAX_BATCH_SIZE) {
+            let api_calls: Vec<ApiCall> = chunk
+                .iter()
+                .map(|(id, call)| self.api_call(id, call))
+                .collect();
+            let mut pending: Vec<usize> = (0..chunk.len()).collect();
+            let mut attempt = 0;
+            while !pending.is_empty() {
+                let batch_calls: Vec<ApiCall> =
+                    pending.iter().map(|i| api_calls[*i].clone()).collect();
+                let parts = self.send_batch(&batch_calls).await?;
+                let mut retry = vec![];
+                for (index, (result, retryable)) in pending.into_iter().zip(parts) {
+                    if retryable && attempt < max_retries {
+                        retry.push(index);
+                    }
+                    results.insert(chunk[index].0.clone(), result);
+                }
+                pending = retry;
+                if !pending.is_empty() {
+                    let delay = backoff(attempt);
+                    attempt += 1;
+                    warn!(
+                        "{} calls of a batch failed, retrying in {:?} ({}/{})",
+                        pending.len(),
+                        delay,
+                        attempt,
+                        max_retries
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+        Ok(results)
+    }
+    fn api_call(&self, id: &DriveId, call: &BatchCall) -> ApiCall {
+        let mut api_call = ApiCall::get(self.unmap_id(id));
+        let BatchCall::Update(update) = call else {
+            return api_call;
+        };
+        let mut fields = Map::new();
+        if let Some(name) = &update.name {
+            fields.insert("name".to_string(), name.clone().into());
+        }
+        if let Some(trashed) = update.trashed {
+            fields.insert("trashed".to_string(), trashed.into());
+        }
+        api_call.update = Some(fields);
+        let join_ids = |ids: &Vec<DriveId>| {
+            ids.iter()
+                .map(|id| self.unmap_id(id))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        if !update.add_parents.is_empty() {
+            api_call
+                .params
+                .push(("addParents", join_ids(&update.add_parents)));
+        }
+        if !update.remove_parents.is_empty() {
+            api_call
+                .params
+                .push(("removeParents", join_ids(&update.remove_parents)));
+        }
+        api_call
+    }
+
+    /// Sends up to [MAX_BATCH_SIZE] calls with one request and returns the result of each
+    /// call and if it can be retried, in the same order as the calls
+    async fn send_batch(&self, calls: &[ApiCall]) -> Result<Vec<(BatchItemResult, bool)>> {
+        let batch_body = build_batch_body(calls);
+        let batch_url: Uri = format!("{}{}", self.root_url, BATCH_PATH).parse()?;
+        let response = self
+            .retry("batch", || {
+                let batch_body = batch_body.clone();
+                let batch_url = batch_url.clone();
+                async move {
+                    let token = self
+                        .hub
+                        .auth
+                        .get_token(&[Scope::Full.as_ref()])
+                        .await
+                        .map_err(google_drive3::Error::MissingToken)?;
+                    let mut request = Request::new(Body::from(batch_body));
+                    *request.method_mut() = Method::POST;
+                    *request.uri_mut() = batch_url;
+                    let headers = request.headers_mut();
+                    headers.insert(CONTENT_TYPE, HeaderValue::from_static(BATCH_CONTENT_TYPE));
+                    if let Some(token) = token {
+                        // a token that can not be sent is as good as none
+                        let authorization = HeaderValue::from_str(&format!("Bearer {}", token))
+                            .map_err(|e| google_drive3::Error::MissingToken(Box::new(e)))?;
+                        headers.insert(AUTHORIZATION, authorization);
+                    }
+                    let response = self
+                        .hub
+                        .client
+                        .request(request)
+                        .await
+                        .map_err(google_drive3::Error::HttpError)?;
+                    if response.status().is_success() {
+                        Ok(response)
+                    } else {
+                        Err(google_drive3::Error::Failure(response))
+                    }
+                }
+            })
+            .await?;
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        trace!("Batch request returned {}", status);
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let body = String::from_utf8_lossy(&body);
+        let boundary = content_type
+            .split(';')
+            .filter_map(|part| part.trim().strip_prefix("boundary="))
+            .next()
+            .ok_or("Batch response has no boundary")?
+            .trim_matches('"');
+
+        let mut results: Vec<(BatchItemResult, bool)> =
+            vec![(Err(BatchItemError::Missing), false); calls.len()];
+        for part in parse_batch_response(&body, boundary) {
+            match results.get_mut(part.index) {
+                Some(slot) => {
+                    let result = part.result.map(|file| {
+                        file.map(|mut file| {
+                            self.map_in_file(Some(&mut file));
+                            FileData::convert_from_api_file(file)
+                        })
+                    });
+                    *slot = (result, part.retryable);
+                }
+                None => warn!("Got a batch response for an unknown call: {}", part.index),
+            }
+        }
+        Ok(results)
+    }
+
+    /// The API only knows the real id of the root folder for some parameters
+    pub(super) fn unmap_id(&self, id: &DriveId) -> String {
+        if id == &*ROOT_ID {
+            self.root_alt_id.0.clone()
+        } else {
+            id.0.clone()
+        }
+    }
+}
+
+/// Builds the multipart body of a batch request, the `Content-ID` of each part is the index
+/// of its call
+fn build_batch_body(calls: &[ApiCall]) -> String {
+    let mut body = String::new();
+    for (index, call) in calls.iter().enumerate() {
+        let mut params = vec![("fields", FIELDS_FILE.to_string())];
+        params.extend(call.params.iter().cloned());
+        let query = params
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, encode_query_value(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let method = match call.update {
+            Some(_) => "PATCH",
+            None => "GET",
+        };
+
+        body.push_str(&format!("--{}\r\n", BOUNDARY));
+        body.push_str("Content-Type: application/http\r\n");
+        body.push_str(&format!("Content-ID: <item{}>\r\n\r\n", index));
+        body.push_str(&format!(
+            "{} {}/{}?{} HTTP/1.1\r\n",
+            method,
+            FILES_PATH,
+            encode_query_value(&call.id),
+            query
+        ));
+        match &call.update {
+            Some(fields) => {
+                body.push_str("Content-Type: application/json; charset=UTF-8\r\n\r\n");
+                body.push_str(&Value::Object(fields.clone()).to_string());
+                body.push_str("\r\n");
+            }
+            None => body.push_str("\r\n"),
+        }
+    }
+    body.push_str(&format!("--{}--\r\n", BOUNDARY));
+    body
+}
+/// Parses the parts of a multipart batch response, each with the index of the call it belongs
+/// to
+fn parse_batch_response(body: &str, boundary: &str) -> Vec<ResponsePart> {
+    let delimiter = format!("--{}", boundary);
+    body.split(delimiter.as_str())
+        .filter_map(|part| {
+            let (part_headers, http_response) = split_head(part)?;
+            let index = part_headers
+                .lines()
+                .filter_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.trim()
+                        .eq_ignore_ascii_case("content-id")
+                        .then_some(value.trim())
+                })
+                .next()?
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .strip_prefix("response-item")?
+                .parse::<usize>()
+                .ok()?;
+            Some(parse_http_response(index, http_response))
+        })
+        .collect()
+}
+fn parse_http_response(index: usize, response: &str) -> ResponsePart {
+    let (head, content) = split_head(response).unwrap_or((response, ""));
+    let content = content.trim();
+    let Some(status) = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+    else {
+        let error = BatchItemError::Parse(format!("Invalid status line: {head}"));
+        return ResponsePart {
+            index,
+            result: Err(error),
+            retryable: false,
+        };
+    };
+    if !(200..300).contains(&status) {
+        let error = serde_json::from_str::<Value>(content).ok();
+        // rate limits are reported as 403 with the reason in the error
+        let retryable = StatusCode::from_u16(status).is_ok_and(is_retryable_status)
+            || error.as_ref().is_some_and(is_retryable_error_value);
+        // errors come in the format of the API, the raw content is better than nothing
+        let message = error
+            .and_then(|error| error["error"]["message"].as_str().map(str::to_string))
+            .unwrap_or_else(|| content.to_string());
+        return ResponsePart {
+            index,
+            result: Err(BatchItemError::Status { status, message }),
+            retryable,
+        };
+    }
+    let result = if content.is_empty() {
+        Ok(None)
+    } else {
+        serde_json::from_str(content)
+            .map(Some)
+            .map_err(|e| BatchItemError::Parse(e.to_string()))
+    };
+    ResponsePart {
+        index,
+        result,
+        retryable: false,
+    }
+}
+/// Splits a http message (or a part of a multipart body) into the head and the content
+fn split_head(message: &str) -> Option<(&str, &str)> {
+    let message = message.trim_start_matches(['\r', '\n']);
+    match (message.find("\r\n\r\n"), message.find("\n\n")) {
+        (Some(crlf), Some(lf)) if lf < crlf => Some((&message[..lf], &message[lf + 2..])),
+        (Some(crlf), _) => Some((&message[..crlf], &message[crlf + 4..])),
+        (None, Some(lf)) => Some((&message[..lf], &message[lf + 2..])),
+        (None, None) => None,
+    }
+}
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE_BOUNDARY: &str = "batch_response";
+
+    fn response_part(index: usize, status: &str, content: &str) -> String {
+        format!(
+            "--{RESPONSE_BOUNDARY}\r\nContent-Type: application/http\r\n\
+            Content-ID: <response-item{index}>\r\n\r\n\
+            HTTP/1.1 {status}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n\
+            {content}\r\n"
+        )
+    }
+
+    #[test]
+    fn batch_body_has_a_get_call_per_id() {
+        let body = build_batch_body(&[
+            ApiCall::get("first".to_string()),
+            ApiCall::get("with space".to_string()),
+        ]);
+
+        assert_eq!(
+            body.matches("Content-Type: application/http\r\n").count(),
+            2
+        );
+        assert!(body.contains("Content-ID: <item0>\r\n\r\nGET /drive/v3/files/first?fields="));
+        assert!(body.contains("Content-ID: <item1>\r\n\r\nGET /drive/v3/files/with%20space?"));
+        assert!(body.contains(&format!("fields={}", encode_query_value(FIELDS_FILE))));
+        assert!(body.ends_with(&format!("--{}--\r\n", BOUNDARY)));
+    }
+
+    #[test]
+    fn update_calls_are_sent_as_patch_with_the_changed_fields() {
+        let mut fields = Map::new();
+        fields.insert("trashed".to_string(), true.into());
+        let call = ApiCall {
+            id: "file".to_string(),
+            update: Some(fields),
+            params: vec![("addParents", "new".to_string())],
+        };
+        let body = build_batch_body(&[call]);
+
+        assert!(body.contains("PATCH /drive/v3/files/file?fields="));
+        assert!(body.contains("&addParents=new HTTP/1.1\r\n"));
+        assert!(body.contains("Content-Type: application/json; charset=UTF-8\r\n\r\n"));
+        assert!(body.contains("{\"trashed\":true}\r\n"));
+    }
+
+    #[test]
+    fn batch_response_parts_are_mapped_to_their_call() {
+        let error = r#"{"error": {"code": 404, "message": "File not found: a."}}"#;
+        let body = [
+            response_part(1, "200 OK", r#"{"id": "b", "name": "b.txt"}"#),
+            response_part(2, "204 No Content", ""),
+            response_part(0, "404 Not Found", error),
+            format!("--{RESPONSE_BOUNDARY}--\r\n"),
+        ]
+        .concat();
+
+        let mut parts = parse_batch_response(&body, RESPONSE_BOUNDARY);
+        parts.sort_by_key(|part| part.index);
+        let indices: Vec<usize> = parts.iter().map(|part| part.index).collect();
+        assert_eq!(indices, vec![0, 1, 2]);
+        match &parts[0].result {
+            Err(BatchItemError::Status { status, message }) => {
+                assert_eq!(*status, 404);
+                assert_eq!(message, "File not found: a.");
+            }
+            other => panic!("expected a status error, got {other:?}"),
+        }
+        let file = parts[1].result.as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(file.name.as_deref(), Some("b.txt"));
+        assert!(matches!(parts[2].result, Ok(None)));
+        assert!(parts.iter().all(|part| !part.retryable));
+    }
+
+    #[test]
+    fn rate_limits_and_server_errors_can_be_retried() {
+        let rate_limit = r#"{"error": {"code": 403, "errors": [{"reason": "rateLimitExceeded"}]}}"#;
+        let forbidden = r#"{"error": {"code": 403, "errors": [{"reason": "forbidden"}]}}"#;
+        let body = [
+            response_part(0, "403 Forbidden", rate_limit),
+            response_part(1, "403 Forbidden", forbidden),
+            response_part(2, "429 Too Many Requests", ""),
+            response_part(3, "503 Service Unavailable", ""),
+        ]
+        .concat();
+
+        let mut parts = parse_batch_response(&body, RESPONSE_BOUNDARY);
+        parts.sort_by_key(|part| part.index);
+        let retryable: Vec<bool> = parts.iter().map(|part| part.retryable).collect();
+        assert_eq!(retryable, vec![true, false, true, true]);
+    }
+
+    #[test]
+    fn unreadable_batch_response_part_is_a_parse_error() {
+        let body = response_part(0, "200 OK", "not json");
+
+        let parts = parse_batch_response(&body, RESPONSE_BOUNDARY);
+        assert_eq!(parts.len(), 1);
+        assert!(matches!(parts[0].result, Err(BatchItemError::Parse(_))));
+    }
+
+    #[tokio::test]
+    async fn metadata_is_returned_per_id() {
+        let (stub, drive) = super::super::tests::stub_drive().await;
+        let existing = DriveId::from(stub.create_file("root", "file.txt", b""));
+        let missing = DriveId::from("missing");
+
+        let results = drive
+            .batch_get_metas(vec![existing.clone(), missing.clone()])
+            .await
+            .unwrap();
+        let file = results[&existing].as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(file.name, "file.txt");
+        assert_eq!(file.parents, vec![ROOT_ID.0.clone()]);
+        assert!(matches!(
+            results[&missing],
+            Err(BatchItemError::Status { status: 404, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn files_are_trashed_per_id() {
+        let (stub, drive) = super::super::tests::stub_drive().await;
+        let id = DriveId::from(stub.create_file("root", "file.txt", b""));
+        let update = MetaUpdate {
+            trashed: Some(true),
+            ..Default::default()
+        };
+
+        let results = drive
+            .batch_update_metas(vec![(id.clone(), update)])
+            .await
+            .unwrap();
+        let file = results[&id].as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(file.trashed, Some(true));
+        assert_eq!(stub.file(&id.0).unwrap()["trashed"], true);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_calls_are_sent_again() {
+        let (stub, drive) = super::super::tests::stub_drive().await;
+        let first = DriveId::from(stub.create_file("root", "first.txt", b""));
+        let second = DriveId::from(stub.create_file("root", "second.txt", b""));
+        stub.rate_limit_next_calls(1);
+
+        let results = drive
+            .batch_get_metas(vec![first.clone(), second.clone()])
+            .await
+            .unwrap();
+        assert!(matches!(results[&first], Ok(Some(_))));
+        assert!(matches!(results[&second], Ok(Some(_))));
+    }
+}
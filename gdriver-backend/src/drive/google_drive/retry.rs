@@ -0,0 +1,268 @@
+use super::*;
+use google_drive3::hyper::header::RETRY_AFTER;
+use google_drive3::hyper::{HeaderMap, StatusCode};
+use rand::Rng;
+use serde_json::Value;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The delay before the first retry, it doubles with every further retry
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(64);
+/// The error reasons of the Drive API that go away by themselves after some time
+const RETRYABLE_REASONS: &[&str] = &[
+    "userRateLimitExceeded",
+    "rateLimitExceeded",
+    "sharingRateLimitExceeded",
+    "backendError",
+    "internalError",
+];
+
+/// A token bucket that limits how many requests are sent to the API.
+///
+/// It is shared between all clones of [GoogleDrive], so all requests together stay under the
+/// quota.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    requests_per_second: f64,
+    /// How many requests can be sent at once after being idle
+    burst: f64,
+}
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+impl RateLimiter {
+    pub(crate) fn new(requests_per_second: u32) -> Self {
+        let requests_per_second = requests_per_second.max(1) as f64;
+        Self {
+            bucket: Mutex::new(Bucket {
+                tokens: requests_per_second,
+                last_refill: Instant::now(),
+            }),
+            requests_per_second,
+            burst: requests_per_second,
+        }
+    }
+    /// Waits until a request can be sent
+    pub(crate) async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+                bucket.tokens =
+                    (bucket.tokens + elapsed * self.requests_per_second).min(self.burst);
+                bucket.last_refill = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.requests_per_second)
+            };
+            trace!("Rate limit reached, waiting {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl GoogleDrive {
+    /// Sends the request created by `call` and sends it again if it failed with an error that
+    /// should go away by itself, like exceeding the rate limit.
+    ///
+    /// Every attempt waits for the [RateLimiter] first.
+    pub(crate) async fn retry<T, F, Fut>(&self, name: &str, mut call: F) -> google_drive3::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = google_drive3::Result<T>>,
+    {
+        let max_retries = CONFIGURATION.current().api_max_retries;
+        let mut attempt = 0;
+        loop {
+            self.rate_limiter.acquire().await;
+            let error = match call().await {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            let Some(delay) = retry_delay(&error, attempt) else {
+                return Err(error);
+            };
+            if attempt >= max_retries {
+                error!("{} failed after {} retries: {}", name, attempt, error);
+                return Err(error);
+            }
+            attempt += 1;
+            warn!(
+                "{} failed, retrying in {:?} ({}/{}): {}",
+                name, delay, attempt, max_retries, error
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Returns how long to wait before retrying or `None` if the error is permanent
+fn retry_delay(error: &google_drive3::Error, attempt: u32) -> Option<Duration> {
+    match error {
+        google_drive3::Error::HttpError(_) | google_drive3::Error::Io(_) => Some(backoff(attempt)),
+        google_drive3::Error::Failure(response) => {
+            if !is_retryable_status(response.status()) {
+                return None;
+            }
+            Some(retry_after(response.headers()).unwrap_or_else(|| backoff(attempt)))
+        }
+        google_drive3::Error::BadRequest(value) => {
+            is_retryable_error_value(value).then(|| backoff(attempt))
+        }
+        _ => None,
+    }
+}
+pub(super) fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+/// Checks the error the API returned as json, since rate limits are reported as 403
+pub(super) fn is_retryable_error_value(value: &Value) -> bool {
+    let error = &value["error"];
+    let status_retryable = error["code"]
+        .as_u64()
+        .and_then(|code| StatusCode::from_u16(code as u16).ok())
+        .map(is_retryable_status)
+        .unwrap_or(false);
+    let reason_retryable = error["errors"]
+        .as_array()
+        .map(|errors| {
+            errors.iter().any(|e| {
+                e["reason"]
+                    .as_str()
+                    .map(|reason| RETRYABLE_REASONS.contains(&reason))
+                    .unwrap_or(false)
+            })
+        })
+        .unwrap_or(false);
+    status_retryable || reason_retryable
+}
+/// Reads the `Retry-After` header, which is either a number of seconds or a date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+/// Exponential backoff with full jitter
+pub(super) fn backoff(attempt: u32) -> Duration {
+    let max = INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF);
+    let jitter = rand::thread_rng().gen_range(0.0..=1.0);
+    max.mul_f64(jitter).max(INITIAL_BACKOFF / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use google_drive3::hyper::header::HeaderValue;
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    fn retry_after_header(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+    fn forbidden(reason: &str) -> Value {
+        json!({
+            "error": {
+                "code": 403,
+                "message": reason,
+                "errors": [{ "domain": "usageLimits", "reason": reason }],
+            }
+        })
+    }
+
+    #[test]
+    fn retry_after_is_read_as_seconds() {
+        let headers = retry_after_header("12");
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn retry_after_is_read_as_http_date() {
+        let date = Utc::now() + chrono::Duration::seconds(30);
+        let headers = retry_after_header(&date.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+        let delay = retry_after(&headers).unwrap();
+        assert!(delay > Duration::from_secs(25) && delay <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn retry_after_in_the_past_or_unreadable_is_ignored() {
+        let past = retry_after_header("Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(retry_after(&past), None);
+        assert_eq!(retry_after(&retry_after_header("soon")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        for attempt in [7, 20, u32::MAX] {
+            let delay = backoff(attempt);
+            assert!(delay <= MAX_BACKOFF, "{attempt} waited {delay:?}");
+            assert!(delay >= INITIAL_BACKOFF / 2);
+        }
+        assert!(backoff(0) <= INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn backoff_is_jittered() {
+        let delays: HashSet<Duration> = (0..20).map(|_| backoff(10)).collect();
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn only_rate_limits_are_retryable_forbidden_errors() {
+        let retryable = |reason| is_retryable_error_value(&forbidden(reason));
+        assert!(retryable("rateLimitExceeded"));
+        assert!(retryable("userRateLimitExceeded"));
+        assert!(!retryable("insufficientFilePermissions"));
+        assert!(!retryable("dailyLimitExceeded"));
+    }
+
+    #[test]
+    fn server_errors_are_retryable_without_a_reason() {
+        let unavailable = json!({ "error": { "code": 503, "message": "Service Unavailable" } });
+        let not_found = json!({ "error": { "code": 404, "message": "File not found" } });
+        assert!(is_retryable_error_value(&unavailable));
+        assert!(!is_retryable_error_value(&not_found));
+    }
+
+    #[tokio::test]
+    async fn empty_bucket_waits_for_a_token() {
+        let limiter = RateLimiter::new(1);
+        limiter.acquire().await;
+        let second = tokio::time::timeout(Duration::from_millis(100), limiter.acquire()).await;
+        assert!(second.is_err());
+    }
+
+    #[tokio::test]
+    async fn bucket_is_refilled_over_time() {
+        let limiter = RateLimiter::new(2);
+        {
+            let mut bucket = limiter.bucket.lock().unwrap();
+            bucket.tokens = 0.0;
+            bucket.last_refill = Instant::now() - Duration::from_secs(10);
+        }
+        let acquired_at_once = || async {
+            tokio::time::timeout(Duration::from_millis(100), limiter.acquire())
+                .await
+                .is_ok()
+        };
+        assert!(acquired_at_once().await);
+        assert!(acquired_at_once().await);
+        // the bucket holds at most one second of requests, no matter how long it was idle
+        assert!(!acquired_at_once().await);
+    }
+}
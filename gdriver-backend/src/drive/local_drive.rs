@@ -0,0 +1,568 @@
+use crate::drive::remote::{
+    BatchItemError, BatchItemResult, FileData, MetaUpdate, RemoteChange, RemoteDrive,
+};
+use crate::prelude::*;
+use chrono::{DateTime, Utc};
+use gdriver_common::drive_structure::meta::FileKind;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+/// Files moved to the trash are kept in this folder below the root, it is never listed
+const TRASH_DIR_NAME: &str = ".gdriver-trash";
+
+/// A [RemoteDrive] that syncs with a local directory, like a mounted network share.
+///
+/// Files are identified by their device, inode number and birth time, so they keep their
+/// [DriveId] when they are renamed or moved, and a new file that reuses the inode of a removed
+/// one gets a new id. The root directory is always [ROOT_ID]. There is no change
+/// feed, [RemoteDrive::get_changes] scans the whole tree and compares it to the last scan,
+/// so every start begins with a full scan.
+#[derive(Debug, Clone)]
+pub struct LocalDrive {
+    root: PathBuf,
+    state: Arc<Mutex<LocalDriveState>>,
+}
+#[derive(Debug, Default)]
+struct LocalDriveState {
+    /// The result of the last scan, used to find the paths of the files
+    files: HashMap<DriveId, LocalFile>,
+    /// The files as they were last returned, changes are found by comparing with them
+    reported: HashMap<DriveId, FileData>,
+    /// The number of scans since the start, used as change token
+    change_token: Option<u64>,
+}
+#[derive(Debug, Clone)]
+struct LocalFile {
+    /// All paths of the file, more than one for hard links
+    paths: Vec<PathBuf>,
+    data: FileData,
+}
+
+impl LocalDrive {
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        if !root.is_dir() {
+            return Err(format!("{} is not a directory", root.display()).into());
+        }
+        Ok(Self {
+            root,
+            state: Arc::new(Mutex::new(LocalDriveState::default())),
+        })
+    }
+
+    /// Returns the first known path of the file, scanning the tree if the file is not known
+    async fn path_of(&self, id: &DriveId) -> Result<PathBuf> {
+        if id == &*ROOT_ID {
+            return Ok(self.root.clone());
+        }
+        if let Some(path) = self.known_path_of(id) {
+            return Ok(path);
+        }
+        self.rescan().await?;
+        self.known_path_of(id)
+            .ok_or_else(|| format!("File not found: {}", id).into())
+    }
+    fn known_path_of(&self, id: &DriveId) -> Option<PathBuf> {
+        let state = self.state.lock().unwrap();
+        state.files.get(id)?.paths.first().cloned()
+    }
+    /// Returns the path of the file in the folder, a file is only in more than one folder as
+    /// hard link
+    async fn link_in(&self, id: &DriveId, parent: &DriveId) -> Result<PathBuf> {
+        self.path_of(id).await?;
+        let paths = self
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .get(id)
+            .map(|file| file.paths.clone())
+            .unwrap_or_default();
+        for path in paths {
+            if &self.parent_id_of(&path)? == parent {
+                return Ok(path);
+            }
+        }
+        Err(format!("{} is not in {}", id, parent).into())
+    }
+    /// Scans the whole tree and replaces the known files
+    async fn rescan(&self) -> Result<()> {
+        let root = self.root.clone();
+        let files = tokio::task::spawn_blocking(move || scan_tree(&root)).await??;
+        self.state.lock().unwrap().files = files;
+        Ok(())
+    }
+    /// Reads the current metadata of a single file
+    async fn read_file(&self, id: &DriveId) -> Result<FileData> {
+        let path = self.path_of(id).await?;
+        let parent = self.parent_id_of(&path)?;
+        let mut file = read_file_data(&path, parent)?;
+        if let Some(known) = self.state.lock().unwrap().files.get(id) {
+            // the parents of hard links are only known from the scan
+            file.parents = known.data.parents.clone();
+        }
+        Ok(file)
+    }
+    fn parent_id_of(&self, path: &Path) -> Result<DriveId> {
+        let parent = path.parent().ok_or("The root has no parent")?;
+        id_of(&self.root, parent)
+    }
+    fn trash_dir(&self) -> PathBuf {
+        self.root.join(TRASH_DIR_NAME)
+    }
+}
+
+/// Returns the id of the file at the path, the root is always [ROOT_ID]
+fn id_of(root: &Path, path: &Path) -> Result<DriveId> {
+    if path == root {
+        return Ok(ROOT_ID.clone());
+    }
+    let metadata = fs::symlink_metadata(path)?;
+    Ok(id_from_metadata(&metadata))
+}
+fn id_from_metadata(metadata: &fs::Metadata) -> DriveId {
+    let birth = metadata
+        .created()
+        .ok()
+        .and_then(|created| created.duration_since(UNIX_EPOCH).ok());
+    match birth {
+        Some(birth) => format!(
+            "local_{}_{}_{}",
+            metadata.dev(),
+            metadata.ino(),
+            birth.as_nanos()
+        )
+        .into(),
+        // without a birth time a reused inode keeps the id of the removed file
+        None => format!("local_{}_{}", metadata.dev(), metadata.ino()).into(),
+    }
+}
+fn read_file_data(path: &Path, parent: DriveId) -> Result<FileData> {
+    let metadata = fs::symlink_metadata(path)?;
+    Ok(file_data_from_metadata(path, &metadata, parent))
+}
+fn file_data_from_metadata(path: &Path, metadata: &fs::Metadata, parent: DriveId) -> FileData {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        FileKind::Directory
+    } else if file_type.is_symlink() {
+        FileKind::Symlink
+    } else {
+        FileKind::File
+    };
+    FileData {
+        id: id_from_metadata(metadata).0,
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size: (kind == FileKind::File).then_some(metadata.len() as i64),
+        mime_type: String::new(),
+        kind,
+        md5_checksum: None,
+        parents: vec![parent.0],
+        trashed: Some(false),
+        created_time: metadata.created().ok().map(DateTime::<Utc>::from),
+        modified_time: metadata.modified().ok().map(DateTime::<Utc>::from),
+        // the access time changes with every download, which would show up as change
+        viewed_by_me_time: None,
+    }
+}
+/// Reads the metadata of every file below the root, except the root and the trash
+fn scan_tree(root: &Path) -> std::io::Result<HashMap<DriveId, LocalFile>> {
+    let mut files: HashMap<DriveId, LocalFile> = HashMap::new();
+    let mut folders = vec![(root.to_path_buf(), ROOT_ID.clone())];
+    while let Some((folder, folder_id)) = folders.pop() {
+        for entry in fs::read_dir(&folder)? {
+            let entry = entry?;
+            let path = entry.path();
+            if folder == root && entry.file_name() == TRASH_DIR_NAME {
+                continue;
+            }
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    // the file was removed while scanning
+                    trace!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let data = file_data_from_metadata(&path, &metadata, folder_id.clone());
+            let id: DriveId = data.id.clone().into();
+            if metadata.is_dir() {
+                folders.push((path.clone(), id.clone()));
+            }
+            match files.get_mut(&id) {
+                Some(file) => {
+                    // another hard link to a file that was already found
+                    file.paths.push(path);
+                    file.data.parents.push(folder_id.0.clone());
+                }
+                None => {
+                    files.insert(
+                        id,
+                        LocalFile {
+                            paths: vec![path],
+                            data,
+                        },
+                    );
+                }
+            }
+        }
+    }
+    for file in files.values_mut() {
+        // the order of read_dir is not stable and would look like a change of the parents
+        file.data.parents.sort();
+        file.paths.sort();
+        if let Some(name) = file.paths.first().and_then(|path| path.file_name()) {
+            file.data.name = name.to_string_lossy().into_owned();
+        }
+    }
+    Ok(files)
+}
+
+impl RemoteDrive for LocalDrive {
+    async fn ping(&self) -> Result<()> {
+        if !tokio::fs::metadata(&self.root).await?.is_dir() {
+            return Err(format!("{} is not a directory", self.root.display()).into());
+        }
+        Ok(())
+    }
+    async fn get_all_file_metas(&self) -> Result<Vec<FileData>> {
+        self.rescan().await?;
+        let mut state = self.state.lock().unwrap();
+        state.reported = state
+            .files
+            .iter()
+            .map(|(id, file)| (id.clone(), file.data.clone()))
+            .collect();
+        Ok(state.reported.values().cloned().collect())
+    }
+    async fn get_children_metas(&self, parent: &DriveId) -> Result<Vec<FileData>> {
+        let path = self.path_of(parent).await?;
+        let mut children = vec![];
+        let mut entries = tokio::fs::read_dir(&path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if path == self.root && entry.file_name() == TRASH_DIR_NAME {
+                continue;
+            }
+            let metadata = match tokio::fs::symlink_metadata(entry.path()).await {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    // the file was removed while listing
+                    trace!("Skipping {}: {}", entry.path().display(), e);
+                    continue;
+                }
+            };
+            children.push(file_data_from_metadata(
+                &entry.path(),
+                &metadata,
+                parent.clone(),
+            ));
+        }
+        Ok(children)
+    }
+    async fn get_meta_for_file(&self, id: &DriveId) -> Result<FileData> {
+        self.read_file(id).await
+    }
+    async fn get_metas_for_files(
+        &self,
+        ids: Vec<DriveId>,
+    ) -> Result<HashMap<DriveId, BatchItemResult>> {
+        let mut results = HashMap::with_capacity(ids.len());
+        for id in ids {
+            let result = self
+                .read_file(&id)
+                .await
+                .map(Some)
+                .map_err(|e| BatchItemError::Status {
+                    status: 404,
+                    message: e.to_string(),
+                });
+            results.insert(id, result);
+        }
+        Ok(results)
+    }
+
+    async fn has_local_change_token(&mut self) -> bool {
+        self.state.lock().unwrap().change_token.is_some()
+    }
+    async fn get_change_start_token(&mut self) -> Result<String> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state.change_token.get_or_insert(0).to_string())
+    }
+    async fn get_changes(&mut self) -> Result<Vec<RemoteChange>> {
+        self.rescan().await?;
+        let mut state = self.state.lock().unwrap();
+        *state.change_token.get_or_insert(0) += 1;
+        let current: HashMap<DriveId, FileData> = state
+            .files
+            .iter()
+            .map(|(id, file)| (id.clone(), file.data.clone()))
+            .collect();
+        let previous = std::mem::replace(&mut state.reported, current);
+        let mut changes: Vec<RemoteChange> = state
+            .reported
+            .iter()
+            .filter(|(id, file)| previous.get(*id) != Some(*file))
+            .map(|(id, file)| RemoteChange {
+                id: id.clone(),
+                removed: false,
+                file: Some(file.clone()),
+            })
+            .collect();
+        changes.extend(
+            previous
+                .into_keys()
+                .filter(|id| !state.reported.contains_key(id))
+                .map(|id| RemoteChange {
+                    id,
+                    removed: true,
+                    file: None,
+                }),
+        );
+        info!("Found {} changes in {}", changes.len(), self.root.display());
+        Ok(changes)
+    }
+
+    async fn download_file(&self, id: &DriveId, target: &Path) -> Result<()> {
+        let path = self.path_of(id).await?;
+        tokio::fs::copy(path, target).await?;
+        Ok(())
+    }
+    async fn upload_file(&self, id: &DriveId, source: &Path) -> Result<FileData> {
+        let path = self.path_of(id).await?;
+        // write next to the file and rename it, so nobody sees a half written file
+        let mut part_path = path.clone().into_os_string();
+        part_path.push(".gdriver-part");
+        let part_path = PathBuf::from(part_path);
+        tokio::fs::copy(source, &part_path).await?;
+        tokio::fs::rename(&part_path, &path).await?;
+        self.read_file(id).await
+    }
+    async fn update_meta(&self, id: &DriveId, update: MetaUpdate) -> Result<FileData> {
+        let mut path = self.path_of(id).await?;
+        if let Some(name) = &update.name {
+            let target = path.with_file_name(name);
+            tokio::fs::rename(&path, &target).await?;
+            path = target;
+            self.rescan().await?;
+        }
+        if !update.add_parents.is_empty() || !update.remove_parents.is_empty() {
+            let mut removed = vec![];
+            for parent in &update.remove_parents {
+                removed.push(self.link_in(id, parent).await?);
+            }
+            let link_count = self
+                .state
+                .lock()
+                .unwrap()
+                .files
+                .get(id)
+                .map_or(0, |file| file.paths.len());
+            if update.add_parents.is_empty() && removed.len() >= link_count {
+                return Err("A file can not be removed from all of its folders".into());
+            }
+            let name = path.file_name().unwrap_or_default().to_owned();
+            for parent in &update.add_parents {
+                let target = self.path_of(parent).await?.join(&name);
+                match removed.pop() {
+                    // moving also works for folders, which can not be hard linked
+                    Some(source) => {
+                        tokio::fs::rename(&source, &target).await?;
+                        if source == path {
+                            path = target;
+                        }
+                    }
+                    // a file can only be in more than one folder as a hard link
+                    None => tokio::fs::hard_link(&path, &target).await?,
+                }
+            }
+            for link in removed {
+                tokio::fs::remove_file(&link).await?;
+            }
+            self.rescan().await?;
+            path = self.path_of(id).await?;
+        }
+        match update.trashed {
+            Some(true) => {
+                let trash_dir = self.trash_dir();
+                tokio::fs::create_dir_all(&trash_dir).await?;
+                let target = trash_dir.join(id.as_ref());
+                tokio::fs::rename(&path, &target).await?;
+                let mut file = read_file_data(&target, ROOT_ID.clone())?;
+                file.parents = vec![];
+                file.trashed = Some(true);
+                self.rescan().await?;
+                return Ok(file);
+            }
+            Some(false) => {
+                return Err("Files can not be restored from the trash of a local directory".into())
+            }
+            None => {}
+        }
+        self.rescan().await?;
+        self.read_file(id).await
+    }
+    async fn update_metas(
+        &self,
+        updates: Vec<(DriveId, MetaUpdate)>,
+    ) -> Result<HashMap<DriveId, BatchItemResult>> {
+        let mut results = HashMap::with_capacity(updates.len());
+        for (id, update) in updates {
+            let result = match self.update_meta(&id, update).await {
+                Ok(file) => Ok(Some(file)),
+                Err(e) => Err(BatchItemError::Status {
+                    status: 400,
+                    message: e.to_string(),
+                }),
+            };
+            results.insert(id, result);
+        }
+        Ok(results)
+    }
+    async fn delete_file(&self, id: &DriveId) -> Result<()> {
+        let path = self.path_of(id).await?;
+        if tokio::fs::symlink_metadata(&path).await?.is_dir() {
+            tokio::fs::remove_dir_all(&path).await?;
+        } else {
+            tokio::fs::remove_file(&path).await?;
+        }
+        self.rescan().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A drive on a new empty directory
+    fn local_drive(name: &str) -> LocalDrive {
+        let root = std::env::temp_dir().join(format!(
+            "gdriver-local-drive-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        LocalDrive::new(root).unwrap()
+    }
+    fn id_at(drive: &LocalDrive, path: &str) -> DriveId {
+        id_of(&drive.root, &drive.root.join(path)).unwrap()
+    }
+
+    #[test]
+    fn a_new_file_on_a_reused_inode_gets_a_new_id() {
+        let drive = local_drive("reused-inode");
+        let path = drive.root.join("file.txt");
+        fs::write(&path, "old").unwrap();
+        let old = fs::symlink_metadata(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, "new").unwrap();
+        let new = fs::symlink_metadata(&path).unwrap();
+        if old.ino() != new.ino() || old.created().is_err() {
+            // the inode was not reused or there is no birth time to tell the files apart
+            return;
+        }
+
+        assert_ne!(id_from_metadata(&old), id_from_metadata(&new));
+    }
+
+    #[tokio::test]
+    async fn renamed_file_keeps_its_id() {
+        let drive = local_drive("rename");
+        fs::write(drive.root.join("old.txt"), "content").unwrap();
+        let id = id_at(&drive, "old.txt");
+
+        let update = MetaUpdate {
+            name: Some("new.txt".to_string()),
+            ..Default::default()
+        };
+        let file = drive.update_meta(&id, update).await.unwrap();
+
+        assert_eq!(file.id, id.0);
+        assert_eq!(file.name, "new.txt");
+        assert!(!drive.root.join("old.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn upload_does_not_touch_files_with_the_same_stem() {
+        let drive = local_drive("upload");
+        fs::write(drive.root.join("report.txt"), "old").unwrap();
+        fs::write(drive.root.join("report.gdriver-part"), "other").unwrap();
+        let source = drive.root.with_extension("source");
+        fs::write(&source, "new").unwrap();
+        let id = id_at(&drive, "report.txt");
+
+        drive.upload_file(&id, &source).await.unwrap();
+
+        assert_eq!(fs::read(drive.root.join("report.txt")).unwrap(), b"new");
+        let other = fs::read(drive.root.join("report.gdriver-part")).unwrap();
+        assert_eq!(other, b"other");
+        assert!(!drive.root.join("report.txt.gdriver-part").exists());
+    }
+
+    #[tokio::test]
+    async fn moved_file_is_only_in_the_new_folder() {
+        let drive = local_drive("move");
+        fs::create_dir(drive.root.join("folder")).unwrap();
+        fs::write(drive.root.join("file.txt"), "content").unwrap();
+        let folder = id_at(&drive, "folder");
+        let id = id_at(&drive, "file.txt");
+
+        let update = MetaUpdate {
+            add_parents: vec![folder.clone()],
+            remove_parents: vec![ROOT_ID.clone()],
+            ..Default::default()
+        };
+        let file = drive.update_meta(&id, update).await.unwrap();
+
+        assert_eq!(file.parents, vec![folder.0]);
+        assert!(drive.root.join("folder/file.txt").exists());
+        assert!(!drive.root.join("file.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn removing_a_parent_removes_only_that_link() {
+        let drive = local_drive("remove-parent");
+        fs::create_dir(drive.root.join("folder")).unwrap();
+        fs::write(drive.root.join("file.txt"), "content").unwrap();
+        fs::hard_link(
+            drive.root.join("file.txt"),
+            drive.root.join("folder/file.txt"),
+        )
+        .unwrap();
+        let folder = id_at(&drive, "folder");
+        let id = id_at(&drive, "file.txt");
+
+        let update = MetaUpdate {
+            remove_parents: vec![ROOT_ID.clone()],
+            ..Default::default()
+        };
+        let file = drive.update_meta(&id, update).await.unwrap();
+
+        assert_eq!(file.parents, vec![folder.0]);
+        assert!(drive.root.join("folder/file.txt").exists());
+        assert!(!drive.root.join("file.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn the_last_parent_can_not_be_removed() {
+        let drive = local_drive("last-parent");
+        fs::write(drive.root.join("file.txt"), "content").unwrap();
+        let id = id_at(&drive, "file.txt");
+
+        let update = MetaUpdate {
+            remove_parents: vec![ROOT_ID.clone()],
+            ..Default::default()
+        };
+
+        assert!(drive.update_meta(&id, update).await.is_err());
+        assert!(drive.root.join("file.txt").exists());
+    }
+}
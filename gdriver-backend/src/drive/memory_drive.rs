@@ -0,0 +1,246 @@
+use crate::drive::remote::{
+    BatchItemError, BatchItemResult, FileData, MetaUpdate, RemoteChange, RemoteDrive,
+};
+use crate::prelude::*;
+use chrono::Utc;
+use gdriver_common::drive_structure::meta::FileKind;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Ids are unique across all instances, so tests that run at the same time never share the
+/// metadata file of an id
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A [RemoteDrive] that keeps all files in memory.
+///
+/// Changes can be scripted with [MemoryDrive::insert_file], [MemoryDrive::remove_file] and
+/// [MemoryDrive::push_change], they are returned by the next call to
+/// [RemoteDrive::get_changes]. Clones share the same state, so a clone can be kept to script
+/// changes while the original is used by the [Drive](super::Drive).
+#[derive(Debug, Clone, Default)]
+pub struct MemoryDrive {
+    state: Arc<Mutex<MemoryDriveState>>,
+}
+#[derive(Debug, Default)]
+struct MemoryDriveState {
+    files: BTreeMap<DriveId, FileData>,
+    contents: HashMap<DriveId, Vec<u8>>,
+    /// Changes that were not returned by [RemoteDrive::get_changes] yet
+    pending_changes: Vec<RemoteChange>,
+    /// The number of times changes were listed, used as change token
+    change_token: Option<u64>,
+    offline: bool,
+}
+
+impl MemoryDrive {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Creates a new file with a generated id and returns it
+    pub fn create_file(&self, parent: &DriveId, name: &str, kind: FileKind) -> FileData {
+        let id = format!("memory_{}", NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        let file = FileData {
+            id,
+            name: name.to_string(),
+            size: (kind == FileKind::File).then_some(0),
+            mime_type: String::new(),
+            kind,
+            md5_checksum: None,
+            parents: vec![parent.0.clone()],
+            trashed: Some(false),
+            created_time: Some(Utc::now()),
+            modified_time: Some(Utc::now()),
+            viewed_by_me_time: None,
+        };
+        self.insert_file(file.clone(), None);
+        file
+    }
+    /// Adds or replaces the file and records a change for it
+    pub fn insert_file(&self, file: FileData, content: Option<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        let id: DriveId = file.id.clone().into();
+        if let Some(content) = content {
+            state.contents.insert(id.clone(), content);
+        }
+        state.files.insert(id.clone(), file.clone());
+        state.pending_changes.push(RemoteChange {
+            id,
+            removed: false,
+            file: Some(file),
+        });
+    }
+    /// Removes the file and records a change for it
+    pub fn remove_file(&self, id: &DriveId) {
+        let mut state = self.state.lock().unwrap();
+        state.files.remove(id);
+        state.contents.remove(id);
+        state.pending_changes.push(RemoteChange {
+            id: id.clone(),
+            removed: true,
+            file: None,
+        });
+    }
+    /// Records a change without changing the files, to simulate inconsistent changes
+    pub fn push_change(&self, change: RemoteChange) {
+        self.state.lock().unwrap().pending_changes.push(change);
+    }
+    /// Returns the content of the file, [None] if nothing was uploaded or inserted for it
+    pub fn content(&self, id: &DriveId) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().contents.get(id).cloned()
+    }
+    /// Makes every call fail as if the remote could not be reached
+    pub fn set_offline(&self, offline: bool) {
+        self.state.lock().unwrap().offline = offline;
+    }
+
+    fn check_online(&self) -> Result<()> {
+        if self.state.lock().unwrap().offline {
+            return Err("MemoryDrive is offline".into());
+        }
+        Ok(())
+    }
+    fn get_file(&self, id: &DriveId) -> Result<FileData> {
+        self.check_online()?;
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .get(id)
+            .cloned()
+            .ok_or_else(|| format!("File not found: {}", id).into())
+    }
+    /// Applies a change to a file and records it
+    fn change_file(&self, id: &DriveId, change: impl FnOnce(&mut FileData)) -> Result<FileData> {
+        let mut file = self.get_file(id)?;
+        change(&mut file);
+        file.modified_time = Some(Utc::now());
+        self.insert_file(file.clone(), None);
+        Ok(file)
+    }
+}
+
+impl RemoteDrive for MemoryDrive {
+    async fn ping(&self) -> Result<()> {
+        self.check_online()
+    }
+    async fn get_all_file_metas(&self) -> Result<Vec<FileData>> {
+        self.check_online()?;
+        Ok(self.state.lock().unwrap().files.values().cloned().collect())
+    }
+    async fn get_children_metas(&self, parent: &DriveId) -> Result<Vec<FileData>> {
+        self.check_online()?;
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .values()
+            .filter(|file| file.parents.contains(&parent.0))
+            .filter(|file| !file.trashed.unwrap_or_default())
+            .cloned()
+            .collect())
+    }
+    async fn get_meta_for_file(&self, id: &DriveId) -> Result<FileData> {
+        self.get_file(id)
+    }
+    async fn get_metas_for_files(
+        &self,
+        ids: Vec<DriveId>,
+    ) -> Result<HashMap<DriveId, BatchItemResult>> {
+        self.check_online()?;
+        let state = self.state.lock().unwrap();
+        Ok(ids
+            .into_iter()
+            .map(|id| {
+                let result = match state.files.get(&id) {
+                    Some(file) => Ok(Some(file.clone())),
+                    None => Err(BatchItemError::Status {
+                        status: 404,
+                        message: format!("File not found: {}", id),
+                    }),
+                };
+                (id, result)
+            })
+            .collect())
+    }
+
+    async fn has_local_change_token(&mut self) -> bool {
+        self.state.lock().unwrap().change_token.is_some()
+    }
+    async fn get_change_start_token(&mut self) -> Result<String> {
+        let mut state = self.state.lock().unwrap();
+        // the changes until now are part of the listing that follows getting a start token
+        state.pending_changes.clear();
+        Ok(state.change_token.get_or_insert(0).to_string())
+    }
+    async fn get_changes(&mut self) -> Result<Vec<RemoteChange>> {
+        self.check_online()?;
+        let mut state = self.state.lock().unwrap();
+        *state.change_token.get_or_insert(0) += 1;
+        Ok(std::mem::take(&mut state.pending_changes))
+    }
+
+    async fn download_file(&self, id: &DriveId, target: &Path) -> Result<()> {
+        self.check_online()?;
+        let content = self
+            .state
+            .lock()
+            .unwrap()
+            .contents
+            .get(id)
+            .cloned()
+            .unwrap_or_default();
+        tokio::fs::write(target, content).await?;
+        Ok(())
+    }
+    async fn upload_file(&self, id: &DriveId, source: &Path) -> Result<FileData> {
+        self.check_online()?;
+        let content = tokio::fs::read(source).await?;
+        let size = content.len() as i64;
+        self.state
+            .lock()
+            .unwrap()
+            .contents
+            .insert(id.clone(), content);
+        self.change_file(id, |file| file.size = Some(size))
+    }
+    async fn update_meta(&self, id: &DriveId, update: MetaUpdate) -> Result<FileData> {
+        self.change_file(id, |file| {
+            if let Some(name) = update.name {
+                file.name = name;
+            }
+            if let Some(trashed) = update.trashed {
+                file.trashed = Some(trashed);
+            }
+            file.parents
+                .retain(|parent| !update.remove_parents.iter().any(|id| id.0 == *parent));
+            file.parents
+                .extend(update.add_parents.into_iter().map(|id| id.0));
+        })
+    }
+    async fn update_metas(
+        &self,
+        updates: Vec<(DriveId, MetaUpdate)>,
+    ) -> Result<HashMap<DriveId, BatchItemResult>> {
+        self.check_online()?;
+        let mut results = HashMap::with_capacity(updates.len());
+        for (id, update) in updates {
+            let result = match self.update_meta(&id, update).await {
+                Ok(file) => Ok(Some(file)),
+                Err(e) => Err(BatchItemError::Status {
+                    status: 404,
+                    message: e.to_string(),
+                }),
+            };
+            results.insert(id, result);
+        }
+        Ok(results)
+    }
+    async fn delete_file(&self, id: &DriveId) -> Result<()> {
+        self.get_file(id)?;
+        self.remove_file(id);
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use crate::prelude::*;
+use chrono::{DateTime, Utc};
+use gdriver_common::drive_structure::meta::{FileKind, FileState, Metadata, DEFAULT_PERMISSIONS};
+use gdriver_common::time_utils::datetime_to_timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+
+/// The storage the files are synced with.
+///
+/// [GoogleDrive](super::google_drive::GoogleDrive) is the real implementation, the
+/// `MemoryDrive` of the tests keeps everything in memory and can be used to script changes.
+pub trait RemoteDrive: Clone + Send + Sync + 'static {
+    /// Checks if the remote can be reached
+    fn ping(&self) -> impl Future<Output = Result<()>> + Send;
+    fn get_all_file_metas(&self) -> impl Future<Output = Result<Vec<FileData>>> + Send;
+    fn get_children_metas(
+        &self,
+        parent: &DriveId,
+    ) -> impl Future<Output = Result<Vec<FileData>>> + Send;
+    fn get_meta_for_file(&self, id: &DriveId) -> impl Future<Output = Result<FileData>> + Send;
+    /// Gets the metadata of many files at once and returns the result per id
+    fn get_metas_for_files(
+        &self,
+        ids: Vec<DriveId>,
+    ) -> impl Future<Output = Result<HashMap<DriveId, BatchItemResult>>> + Send;
+
+    /// Returns true if there is a change token from an earlier run
+    fn has_local_change_token(&mut self) -> impl Future<Output = bool> + Send;
+    /// Gets the token changes are listed from, all changes after it are returned by
+    /// [RemoteDrive::get_changes]
+    fn get_change_start_token(&mut self) -> impl Future<Output = Result<String>> + Send;
+    /// Returns all changes since the last call and moves the change token forward
+    fn get_changes(&mut self) -> impl Future<Output = Result<Vec<RemoteChange>>> + Send;
+
+    fn download_file(
+        &self,
+        id: &DriveId,
+        target: &Path,
+    ) -> impl Future<Output = Result<()>> + Send;
+    /// Replaces the content of the file with the content of the source file
+    fn upload_file(
+        &self,
+        id: &DriveId,
+        source: &Path,
+    ) -> impl Future<Output = Result<FileData>> + Send;
+    fn update_meta(
+        &self,
+        id: &DriveId,
+        update: MetaUpdate,
+    ) -> impl Future<Output = Result<FileData>> + Send;
+    /// Changes the metadata of many files at once and returns the changed file per id
+    fn update_metas(
+        &self,
+        updates: Vec<(DriveId, MetaUpdate)>,
+    ) -> impl Future<Output = Result<HashMap<DriveId, BatchItemResult>>> + Send;
+    fn delete_file(&self, id: &DriveId) -> impl Future<Output = Result<()>> + Send;
+}
+
+#[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize, Clone, Hash)]
+pub struct FileData {
+    pub id: String,
+    pub name: String,
+    pub size: Option<i64>,
+    pub mime_type: String,
+    pub kind: FileKind,
+    pub md5_checksum: Option<String>,
+    pub parents: Vec<String>,
+    pub trashed: Option<bool>,
+    pub created_time: Option<DateTime<Utc>>,
+    pub modified_time: Option<DateTime<Utc>>,
+    pub viewed_by_me_time: Option<DateTime<Utc>>,
+}
+
+impl FileData {
+    pub(crate) fn into_meta(self) -> Result<Metadata> {
+        let last_modified = datetime_to_timestamp(self.modified_time.unwrap_or_default())?;
+        Ok(Metadata {
+            id: self.id.into(),
+            kind: self.kind,
+            name: self.name,
+            size: self.size.unwrap_or_default() as u64,
+            last_accessed: datetime_to_timestamp(self.viewed_by_me_time.unwrap_or_default())?,
+            last_modified,
+            extra_attributes: Default::default(),
+            state: FileState::MetadataOnly,
+            permissions: DEFAULT_PERMISSIONS, //TODO: parse permissions
+            last_metadata_changed: last_modified,
+            parent_count: self.parents.len() as u32,
+        })
+    }
+}
+
+/// A change of a single file on the remote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteChange {
+    pub id: DriveId,
+    /// The file does not exist anymore or is not accessible anymore
+    pub removed: bool,
+    /// The current state of the file, if it was not removed
+    pub file: Option<FileData>,
+}
+
+/// The metadata fields that should be changed, fields that are `None` are left as they are
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetaUpdate {
+    pub name: Option<String>,
+    pub trashed: Option<bool>,
+    pub add_parents: Vec<DriveId>,
+    pub remove_parents: Vec<DriveId>,
+}
+
+/// The error of a single call when many calls are sent at once
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum BatchItemError {
+    #[error("Request failed with status {status}: {message}")]
+    Status { status: u16, message: String },
+    #[error("Could not parse the response: {0}")]
+    Parse(String),
+    #[error("The batch response did not contain a response for this request")]
+    Missing,
+}
+
+/// The result of a single call when many calls are sent at once.
+///
+/// Contains the file if the call returns one.
+pub type BatchItemResult = StdResult<Option<FileData>, BatchItemError>;
@@ -0,0 +1,106 @@
+use crate::prelude::*;
+use gdriver_common::ipc::events::{BackendEvent, EventBatch, EventSubscription};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// How many events are remembered for clients that are waiting for events
+const MAX_REMEMBERED_EVENTS: usize = 10_000;
+
+/// Collects the events of the backend, so clients can get them with
+/// [GDriverService::next_events](gdriver_common::ipc::gdriver_service::GDriverService::next_events).
+///
+/// Every event gets a consecutive event number, clients ask for all events after the last
+/// event number they have seen.
+#[derive(Debug, Default)]
+pub struct EventLog {
+    entries: Mutex<EventLogEntries>,
+    notify: Notify,
+}
+#[derive(Debug, Default)]
+struct EventLogEntries {
+    /// The event number of the first element in `events`
+    first: u64,
+    events: VecDeque<BackendEvent>,
+}
+impl EventLogEntries {
+    fn next(&self) -> u64 {
+        self.first + self.events.len() as u64
+    }
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    #[instrument(skip(self))]
+    pub fn push(&self, event: BackendEvent) {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.events.push_back(event);
+            if entries.events.len() > MAX_REMEMBERED_EVENTS {
+                entries.events.pop_front();
+                entries.first += 1;
+            }
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Forgets all remembered events, so every client gets an incomplete batch next and throws
+    /// away everything it cached
+    pub fn forget_all(&self) {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            // skips an event number, so clients that have seen every event notice it as well
+            entries.first = entries.next() + 1;
+            entries.events.clear();
+        }
+        self.notify.notify_waiters();
+    }
+
+    fn get_since(&self, since: u64) -> EventBatch {
+        let entries = self.entries.lock().unwrap();
+        let next = entries.next();
+        if since > next {
+            // the client does not know the current event number (or the backend was restarted)
+            return EventBatch {
+                next,
+                ..Default::default()
+            };
+        }
+        let skip = since.saturating_sub(entries.first) as usize;
+        EventBatch {
+            next,
+            events: entries.events.iter().skip(skip).cloned().collect(),
+            incomplete: since < entries.first,
+        }
+    }
+
+    /// Returns the events after `since` the subscription matches, waiting up to `timeout` if
+    /// there are none yet.
+    pub async fn wait_since(
+        &self,
+        since: u64,
+        timeout: Duration,
+        subscription: &EventSubscription,
+    ) -> EventBatch {
+        let deadline = Instant::now() + timeout;
+        let mut since = since;
+        loop {
+            // this needs to be created before checking, otherwise an event could slip through
+            let notified = self.notify.notified();
+            let mut batch = self.get_since(since);
+            batch.events.retain(|event| subscription.matches(event));
+            if !batch.events.is_empty() || batch.incomplete || since > batch.next {
+                return batch;
+            }
+            // skip the events the client is not interested in, so they don't wake it up
+            since = batch.next;
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return batch;
+            }
+        }
+    }
+}
@@ -9,11 +9,16 @@ use tarpc::{
     tokio_serde::formats::Json,
 };
 
+mod clients;
 mod drive;
+mod events;
 mod path_resolver;
 mod prelude;
 mod sample;
 mod service;
+mod shutdown;
+#[cfg(test)]
+mod test_utils;
 
 pub(crate) async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
     tokio::spawn(fut);
@@ -1,18 +1,45 @@
 use crate::prelude::*;
-use gdriver_common::drive_structure::meta::Metadata;
+use gdriver_common::drive_structure::meta::{FileKind, Metadata};
 use gdriver_common::ipc::gdriver_service::{ReadDirResult, SETTINGS};
+use gdriver_common::name_encoding::{encode_name, truncate_name};
 use gdriver_common::path_resolve_error::PathResolveError;
 use gdriver_common::prelude::*;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-#[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+mod store;
+use store::{RelationChange, RelationStore};
+
+/// Files with more paths than this are reported with the first ones only
+const MAX_PATHS_PER_ID: usize = 1000;
+/// How many characters of the id are added to a name that exists more than once in a folder
+const DISAMBIGUATION_ID_LENGTH: usize = 6;
+
+#[derive(Debug)]
 pub struct PathResolver {
     /// A map of children to their parents
     parents: HashMap<DriveId, Vec<DriveId>>,
-    /// A map of parents to their children with id, name and type (folder/file/symlink)
+    /// A map of parents to their children with id, name and type (folder/file/symlink).
+    ///
+    /// The names are encoded with [encode_name] and made unique within the parent.
+    children: HashMap<DriveId, Vec<ReadDirResult>>,
+    /// A map of parents to the ids of their children by name, in the order they were added.
+    ///
+    /// Has more than one id for a name if a folder contains files with the same name.
+    names: HashMap<DriveId, HashMap<String, Vec<DriveId>>>,
+    /// The kind of every file that has at least one parent
+    kinds: HashMap<DriveId, FileKind>,
+    /// Changes that are written to the database with the next [PathResolver::commit]
+    pending: Vec<RelationChange>,
+    /// Opened on first use, the mutex only makes the resolver [Sync]
+    store: Option<Mutex<RelationStore>>,
+}
+/// The format of the relations.json file the resolver was stored in before
+#[derive(Deserialize)]
+struct LegacyRelations {
     children: HashMap<DriveId, Vec<ReadDirResult>>,
 }
 
@@ -30,39 +57,176 @@ impl PathResolver {
         Self {
             parents: HashMap::new(),
             children: HashMap::new(),
+            names: HashMap::new(),
+            kinds: HashMap::new(),
+            pending: vec![],
+            store: None,
+        }
+    }
+    /// A resolver that stores its relationships in memory instead of the database, for tests
+    #[cfg(test)]
+    pub(crate) fn in_memory() -> Self {
+        let store = RelationStore::open_in_memory().expect("an in-memory store can be opened");
+        Self {
+            store: Some(Mutex::new(store)),
+            ..Self::new()
         }
     }
+    /// Resolves a path from the root with POSIX semantics.
+    ///
+    /// Leading, repeated and trailing slashes are ignored, but a trailing slash requires the
+    /// path to end at a directory. `..` goes back to the folder the path came from, so it is
+    /// unambiguous for files with multiple parents, and stays at the root like `/..` does.
     pub async fn get_id_from_path(
-        &mut self,
+        &self,
         path: &Path,
     ) -> StdResult<Option<DriveId>, PathResolveError> {
-        let segments: Vec<_> = path
-            .to_str()
-            .ok_or(PathResolveError::InvalidPath)?
-            .split('/')
-            .collect();
+        let path = path.to_str().ok_or(PathResolveError::InvalidUtf8)?;
+        // the folders that lead to the current file, starting at the root
+        let mut folders: Vec<DriveId> = vec![];
         let mut current = ROOT_ID.clone();
-        for segment in segments {
-            current = self.get_id_from_parent_and_name(segment, &current).ok_or(
-                PathResolveError::Other("path-segment not found".to_string()),
-            )?;
+        let mut current_name = "/";
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            if !self.is_directory(&current) {
+                return Err(PathResolveError::NotADirectory {
+                    segment: current_name.to_string(),
+                });
+            }
+            match segment {
+                "." => {}
+                ".." => {
+                    if let Some(parent) = folders.pop() {
+                        current = parent;
+                    }
+                }
+                name => {
+                    let child = self
+                        .get_id_from_parent_and_name(name, &current)
+                        .ok_or_else(|| PathResolveError::NotFound {
+                            segment: name.to_string(),
+                        })?;
+                    folders.push(std::mem::replace(&mut current, child));
+                }
+            }
+            current_name = segment;
+        }
+        if path.ends_with('/') && !self.is_directory(&current) {
+            return Err(PathResolveError::NotADirectory {
+                segment: current_name.to_string(),
+            });
         }
-        return Ok(Some(current));
+        Ok(Some(current))
     }
-    pub fn get_id_from_parent_and_name(&self, name: &str, parent: &DriveId) -> Option<DriveId> {
-        if let Some(children) = self.children.get(parent) {
-            if let Some(x) = children.into_iter().find(|x| x.name.eq(name)) {
-                return Some(x.id.clone());
+    /// Returns every path the file can be reached at from the root.
+    ///
+    /// Every parent of the file, and of its parents, leads to another path. Parents that
+    /// lead back to a folder that is already on the way to the file are skipped, so cycles
+    /// end. Files that are not below the root have no paths.
+    pub fn get_paths_for_id(&self, id: &DriveId) -> Result<Vec<PathBuf>> {
+        if *id == *ROOT_ID {
+            return Ok(vec![PathBuf::from("/")]);
+        }
+        if !self.parents.contains_key(id) {
+            return Err("Item with ID not found".into());
+        }
+        let mut paths = vec![];
+        self.collect_paths(id, &mut vec![id.clone()], &mut vec![], &mut paths);
+        Ok(paths)
+    }
+    /// Walks up from the first file of `chain` and adds a path for every way to the root.
+    ///
+    /// `chain` contains the files from the current one down to the requested one, `names`
+    /// their names in the same order.
+    fn collect_paths(
+        &self,
+        id: &DriveId,
+        chain: &mut Vec<DriveId>,
+        names: &mut Vec<String>,
+        paths: &mut Vec<PathBuf>,
+    ) {
+        for parent in self.parents.get(id).into_iter().flatten() {
+            if paths.len() >= MAX_PATHS_PER_ID {
+                warn!("{} has more than {} paths", id, MAX_PATHS_PER_ID);
+                return;
+            }
+            if chain.contains(parent) {
+                trace!("Skipping cycle through {}", parent);
+                continue;
+            }
+            let Some(name) = self.get_name_in_parent(id, parent) else {
+                continue;
+            };
+            names.push(name);
+            if *parent == *ROOT_ID {
+                let mut path = PathBuf::from("/");
+                path.extend(names.iter().rev());
+                paths.push(path);
+            } else {
+                chain.push(parent.clone());
+                self.collect_paths(parent, chain, names, paths);
+                chain.pop();
             }
+            names.pop();
         }
-        None
+    }
+    /// Returns the name the file is listed with in the parent
+    fn get_name_in_parent(&self, id: &DriveId, parent: &DriveId) -> Option<String> {
+        self.children
+            .get(parent)?
+            .iter()
+            .find(|entry| entry.id == *id)
+            .map(|entry| entry.name.clone())
+    }
+    /// Returns the file and everything below it, parents before their children.
+    ///
+    /// Files that are in more than one of the folders are returned once.
+    pub fn get_ids_below(&self, id: &DriveId) -> Vec<DriveId> {
+        let mut seen = HashSet::from([id.clone()]);
+        let mut ids = vec![id.clone()];
+        let mut next = 0;
+        while let Some(current) = ids.get(next).cloned() {
+            next += 1;
+            for child in self.children.get(&current).into_iter().flatten() {
+                if seen.insert(child.id.clone()) {
+                    ids.push(child.id.clone());
+                }
+            }
+        }
+        ids
+    }
+    pub fn is_directory(&self, id: &DriveId) -> bool {
+        *id == *ROOT_ID || self.kinds.get(id) == Some(&FileKind::Directory)
+    }
+    /// Finds a child by the name it is listed with, or by its name on the drive if no child
+    /// is listed with the name
+    pub fn get_id_from_parent_and_name(&self, name: &str, parent: &DriveId) -> Option<DriveId> {
+        if let Some(id) = self.get_ids_from_parent_and_name(name, parent).first() {
+            return Some(id.clone());
+        }
+        let encoded = encode_name(name);
+        if encoded == name {
+            return None;
+        }
+        self.get_ids_from_parent_and_name(&encoded, parent)
+            .first()
+            .cloned()
+    }
+    /// Returns the ids of all children of the parent with the name
+    pub fn get_ids_from_parent_and_name(&self, name: &str, parent: &DriveId) -> &[DriveId] {
+        self.names
+            .get(parent)
+            .and_then(|names| names.get(name))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
     }
 
-    pub fn reset(&mut self) -> Result<()> {
+    /// Removes all relationships, the database is cleared with the next [PathResolver::commit]
+    pub fn reset(&mut self) {
         self.parents.clear();
         self.children.clear();
-        self.write_to_disk()?;
-        Ok(())
+        self.names.clear();
+        self.kinds.clear();
+        self.pending = vec![RelationChange::Clear];
     }
     pub(crate) fn add_relationships_for_meta(
         &mut self,
@@ -71,7 +235,7 @@ impl PathResolver {
     ) -> Result<()> {
         let entry = ReadDirResult {
             id: meta.id.clone().into(),
-            name: meta.name.to_string(),
+            name: encode_name(&meta.name),
             kind: meta.kind.clone(),
         };
         for parent in parents {
@@ -79,21 +243,20 @@ impl PathResolver {
         }
         Ok(())
     }
-    /// Add a relationship between a parent and a child and write to disk
-    pub(crate) fn add_relationship(&mut self, parent: DriveId, entry: ReadDirResult) -> Result<()> {
-        match self.parents.get_mut(&entry.id) {
-            Some(x) => x.push(parent.clone()),
-            None => {
-                self.parents.insert(entry.id.clone(), vec![parent.clone()]);
-            }
-        };
-        match self.children.get_mut(&parent) {
-            Some(x) => x.push(entry.clone()),
-            None => {
-                self.children.insert(parent.clone(), vec![entry.clone()]);
-            }
-        }
-        self.write_to_disk()?;
+    /// Add a relationship between a parent and a child, it is written to disk with the next
+    /// [PathResolver::commit]
+    ///
+    /// If the parent already has a different child with the same name, the name of the new
+    /// child gets a suffix from its id, like `report (1ab2c3).pdf`. The stored name is kept
+    /// even if the other child is removed, so the path of a file does not change.
+    pub(crate) fn add_relationship(
+        &mut self,
+        parent: DriveId,
+        mut entry: ReadDirResult,
+    ) -> Result<()> {
+        entry.name = self.unique_name(&parent, &entry.name, &entry.id);
+        self.add_to_maps(parent.clone(), entry.clone());
+        self.pending.push(RelationChange::Add { parent, entry });
         Ok(())
     }
 
@@ -107,30 +270,354 @@ impl PathResolver {
         }
         Ok(())
     }
-    /// Remove the relationship between a parent and a child and write to disk
+    /// Remove the relationship between a parent and a child, it is removed from disk with the
+    /// next [PathResolver::commit]
     pub(crate) fn remove_relationship(&mut self, parent: &DriveId, id: &DriveId) -> Result<()> {
-        self.parents.get_mut(id).map(|x| x.retain(|e| e != parent));
-        self.children
-            .get_mut(parent)
-            .map(|x| x.retain(|e| e.id != *id));
-        self.write_to_disk()?;
+        if let Some(parents) = self.parents.get_mut(id) {
+            parents.retain(|e| e != parent);
+            if parents.is_empty() {
+                self.kinds.remove(id);
+            }
+        }
+        if let Some(children) = self.children.get_mut(parent) {
+            let mut removed_names = vec![];
+            children.retain(|e| {
+                if e.id == *id {
+                    removed_names.push(e.name.clone());
+                }
+                e.id != *id
+            });
+            self.remove_from_name_index(parent, id, removed_names);
+        }
+        self.pending.push(RelationChange::Remove {
+            parent: parent.clone(),
+            id: id.clone(),
+        });
         Ok(())
     }
 
-    pub fn write_to_disk(&self) -> Result<()> {
-        let path = SETTINGS.get_path_resolver_file_path();
-        let reader = File::create(path)?;
-        Ok(serde_json::to_writer_pretty(reader, self)?)
+    /// Writes all changes since the last commit to disk in a single transaction
+    #[instrument(skip(self))]
+    pub fn commit(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        trace!("Committing {} relationship changes", pending.len());
+        let result = self.store().and_then(|store| store.apply(&pending));
+        if result.is_err() {
+            // keep the changes, so the next commit can try again
+            self.pending = pending;
+        }
+        result
     }
-    pub fn read_from_disk() -> Result<Self> {
-        let path = SETTINGS.get_path_resolver_file_path();
-        let reader = File::open(path)?;
-        Ok(serde_json::from_reader(reader)?)
+    fn store(&mut self) -> Result<&mut RelationStore> {
+        if self.store.is_none() {
+            let store = RelationStore::open(&SETTINGS.get_database_file_path())?;
+            self.store = Some(Mutex::new(store));
+        }
+        let store = self.store.as_mut().expect("the store was just opened");
+        Ok(store.get_mut().unwrap())
     }
+    /// Replaces all relationships with the ones stored on disk
+    #[instrument(skip(self))]
     pub fn load_from_disk(&mut self) -> Result<()> {
-        let other = Self::read_from_disk()?;
-        self.parents = other.parents;
-        self.children = other.children;
+        self.parents.clear();
+        self.children.clear();
+        self.names.clear();
+        self.kinds.clear();
+        self.pending.clear();
+        let legacy_path = SETTINGS.get_path_resolver_file_path();
+        if legacy_path.exists() && self.store()?.is_empty()? {
+            return self.import_legacy_file(&legacy_path);
+        }
+        for (parent, entry) in self.store()?.load()? {
+            self.add_to_maps(parent, entry);
+        }
         Ok(())
     }
+    /// Moves the relationships from the relations.json of older versions into the database
+    fn import_legacy_file(&mut self, path: &Path) -> Result<()> {
+        info!("Importing relationships from {}", path.display());
+        let legacy: LegacyRelations = serde_json::from_reader(File::open(path)?)?;
+        for (parent, entries) in legacy.children {
+            for mut entry in entries {
+                // the legacy file has the names as they are on the drive
+                entry.name = encode_name(&entry.name);
+                self.add_relationship(parent.clone(), entry)?;
+            }
+        }
+        self.commit()?;
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+    fn add_to_maps(&mut self, parent: DriveId, entry: ReadDirResult) {
+        self.parents
+            .entry(entry.id.clone())
+            .or_default()
+            .push(parent.clone());
+        self.names
+            .entry(parent.clone())
+            .or_default()
+            .entry(entry.name.clone())
+            .or_default()
+            .push(entry.id.clone());
+        self.kinds.insert(entry.id.clone(), entry.kind.clone());
+        self.children.entry(parent).or_default().push(entry);
+    }
+    /// Returns the name, with a suffix from the id if another child of the parent has it
+    fn unique_name(&self, parent: &DriveId, name: &str, id: &DriveId) -> String {
+        let is_free = |name: &str| {
+            self.get_ids_from_parent_and_name(name, parent)
+                .iter()
+                .all(|existing| existing == id)
+        };
+        if is_free(name) {
+            return name.to_string();
+        }
+        // use more of the id until the name is free, the whole id is always unique
+        let id_chars: Vec<char> = id.as_ref().chars().collect();
+        (DISAMBIGUATION_ID_LENGTH..id_chars.len())
+            .map(|length| disambiguated_name(name, &id_chars[..length].iter().collect::<String>()))
+            .find(|candidate| is_free(candidate))
+            .unwrap_or_else(|| disambiguated_name(name, id.as_ref()))
+    }
+    fn remove_from_name_index(&mut self, parent: &DriveId, id: &DriveId, names: Vec<String>) {
+        let Some(parent_names) = self.names.get_mut(parent) else {
+            return;
+        };
+        for name in names {
+            if let Some(ids) = parent_names.get_mut(&name) {
+                ids.retain(|e| e != id);
+                if ids.is_empty() {
+                    parent_names.remove(&name);
+                }
+            }
+        }
+        if parent_names.is_empty() {
+            self.names.remove(parent);
+        }
+    }
+}
+
+/// Adds the suffix before the extension: `report.pdf` becomes `report (suffix).pdf`
+fn disambiguated_name(name: &str, suffix: &str) -> String {
+    let name = match name.rfind('.') {
+        // a leading dot marks a hidden file and is no extension
+        Some(index) if index > 0 => format!("{} ({}){}", &name[..index], suffix, &name[index..]),
+        _ => format!("{} ({})", name, suffix),
+    };
+    truncate_name(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(resolver: &mut PathResolver, parent: &str, id: &str, name: &str, kind: FileKind) {
+        let parent = match parent {
+            "/" => ROOT_ID.clone(),
+            parent => DriveId::from(parent),
+        };
+        let entry = ReadDirResult {
+            id: id.into(),
+            name: name.to_string(),
+            kind,
+        };
+        resolver.add_relationship(parent, entry).unwrap();
+    }
+    fn add_file(resolver: &mut PathResolver, parent: &str, id: &str, name: &str) {
+        add(resolver, parent, id, name, FileKind::File);
+    }
+    fn add_folder(resolver: &mut PathResolver, parent: &str, id: &str, name: &str) {
+        add(resolver, parent, id, name, FileKind::Directory);
+    }
+    fn names_in(resolver: &PathResolver, parent: &DriveId) -> Vec<String> {
+        let children = resolver.get_children(parent).unwrap();
+        children.iter().map(|child| child.name.clone()).collect()
+    }
+    /// `/folder/file.txt`, `/file.txt` and `/a/shared` and `/b/shared` for the same folder
+    fn tree() -> PathResolver {
+        let mut resolver = PathResolver::in_memory();
+        add_folder(&mut resolver, "/", "folder", "folder");
+        add_file(&mut resolver, "folder", "inner", "file.txt");
+        add_file(&mut resolver, "/", "file", "file.txt");
+        add_folder(&mut resolver, "/", "a", "a");
+        add_folder(&mut resolver, "/", "b", "b");
+        add_folder(&mut resolver, "a", "shared", "shared");
+        add_folder(&mut resolver, "b", "shared", "shared");
+        resolver
+    }
+    async fn resolve(resolver: &PathResolver, path: &str) -> StdResult<DriveId, PathResolveError> {
+        let id = resolver.get_id_from_path(Path::new(path)).await?;
+        Ok(id.expect("paths resolve to a file or an error"))
+    }
+
+    #[test]
+    fn second_file_with_a_name_gets_a_suffix_from_its_id() {
+        let mut resolver = PathResolver::in_memory();
+        add_file(&mut resolver, "/", "first_id", "report.pdf");
+        add_file(&mut resolver, "/", "second_id", "report.pdf");
+
+        assert_eq!(
+            names_in(&resolver, &ROOT_ID),
+            vec!["report.pdf", "report (second).pdf"]
+        );
+    }
+    #[test]
+    fn suffix_is_longer_if_the_short_one_is_taken() {
+        let mut resolver = PathResolver::in_memory();
+        add_file(&mut resolver, "/", "other", "report.pdf");
+        add_file(&mut resolver, "/", "abcdef1", "report.pdf");
+        add_file(&mut resolver, "/", "abcdef2", "report.pdf");
+
+        assert_eq!(
+            names_in(&resolver, &ROOT_ID),
+            vec!["report.pdf", "report (abcdef).pdf", "report (abcdef2).pdf"]
+        );
+    }
+    #[test]
+    fn suffix_is_kept_when_the_other_file_is_removed() {
+        let mut resolver = PathResolver::in_memory();
+        add_file(&mut resolver, "/", "first_id", "report.pdf");
+        add_file(&mut resolver, "/", "second_id", "report.pdf");
+        resolver
+            .remove_relationship(&ROOT_ID, &"first_id".into())
+            .unwrap();
+
+        assert_eq!(names_in(&resolver, &ROOT_ID), vec!["report (second).pdf"]);
+    }
+    #[test]
+    fn other_names_are_kept_when_a_sibling_is_added_again() {
+        let mut resolver = PathResolver::in_memory();
+        add_file(&mut resolver, "/", "first_id", "report.pdf");
+        add_file(&mut resolver, "/", "second_id", "report.pdf");
+        add_file(&mut resolver, "/", "third_id", "report.pdf");
+        resolver
+            .remove_relationship(&ROOT_ID, &"second_id".into())
+            .unwrap();
+        add_file(&mut resolver, "/", "second_id", "report.pdf");
+
+        assert_eq!(
+            names_in(&resolver, &ROOT_ID),
+            vec!["report.pdf", "report (third_).pdf", "report (second).pdf"]
+        );
+    }
+
+    #[test]
+    fn ids_below_contain_files_in_several_folders_once() {
+        let resolver = tree();
+        let below = resolver.get_ids_below(&ROOT_ID);
+        assert_eq!(below.len(), 7);
+        assert_eq!(below[0], *ROOT_ID);
+        assert_eq!(below.iter().filter(|id| id.0 == "shared").count(), 1);
+        let folder = resolver.get_ids_below(&DriveId::from("folder"));
+        assert_eq!(folder, ["folder", "inner"].map(DriveId::from));
+    }
+
+    #[test]
+    fn imported_legacy_names_are_encoded() {
+        let path = std::env::temp_dir().join(format!(
+            "gdriver-legacy-relations-{}.json",
+            std::process::id()
+        ));
+        let entry = ReadDirResult {
+            id: "legacy".into(),
+            name: "a/b".to_string(),
+            kind: FileKind::File,
+        };
+        let root = ROOT_ID.0.clone();
+        let legacy = serde_json::json!({ "children": { root: [entry] } });
+        std::fs::write(&path, legacy.to_string()).unwrap();
+        let mut resolver = PathResolver::in_memory();
+        resolver.import_legacy_file(&path).unwrap();
+
+        assert_eq!(names_in(&resolver, &ROOT_ID), vec![encode_name("a/b")]);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn root_is_found_by_empty_paths_and_slashes() {
+        let resolver = tree();
+
+        assert_eq!(resolve(&resolver, "").await.unwrap(), *ROOT_ID);
+        assert_eq!(resolve(&resolver, "/").await.unwrap(), *ROOT_ID);
+        assert_eq!(resolve(&resolver, "//").await.unwrap(), *ROOT_ID);
+    }
+    #[tokio::test]
+    async fn dot_dot_stays_at_the_root() {
+        let resolver = tree();
+
+        assert_eq!(resolve(&resolver, "/..").await.unwrap(), *ROOT_ID);
+        assert_eq!(resolve(&resolver, "/../..").await.unwrap(), *ROOT_ID);
+        assert_eq!(
+            resolve(&resolver, "/../folder").await.unwrap(),
+            DriveId::from("folder")
+        );
+    }
+    #[tokio::test]
+    async fn dot_dot_goes_back_the_way_the_path_came() {
+        let resolver = tree();
+
+        assert_eq!(
+            resolve(&resolver, "/a/shared/..").await.unwrap(),
+            DriveId::from("a")
+        );
+        assert_eq!(
+            resolve(&resolver, "/b/shared/..").await.unwrap(),
+            DriveId::from("b")
+        );
+        assert_eq!(
+            resolve(&resolver, "/folder/.").await.unwrap(),
+            DriveId::from("folder")
+        );
+    }
+    #[tokio::test]
+    async fn empty_components_are_ignored() {
+        let resolver = tree();
+
+        assert_eq!(
+            resolve(&resolver, "//folder///file.txt").await.unwrap(),
+            DriveId::from("inner")
+        );
+        assert_eq!(
+            resolve(&resolver, "folder/file.txt").await.unwrap(),
+            DriveId::from("inner")
+        );
+    }
+    #[tokio::test]
+    async fn trailing_slash_needs_a_directory() {
+        let resolver = tree();
+
+        assert_eq!(
+            resolve(&resolver, "/folder/").await.unwrap(),
+            DriveId::from("folder")
+        );
+        assert!(matches!(
+            resolve(&resolver, "/file.txt/").await,
+            Err(PathResolveError::NotADirectory { segment }) if segment == "file.txt"
+        ));
+    }
+    #[tokio::test]
+    async fn file_in_the_middle_of_a_path_is_not_a_directory() {
+        let resolver = tree();
+
+        assert!(matches!(
+            resolve(&resolver, "/file.txt/inner").await,
+            Err(PathResolveError::NotADirectory { segment }) if segment == "file.txt"
+        ));
+        assert!(matches!(
+            resolve(&resolver, "/file.txt/..").await,
+            Err(PathResolveError::NotADirectory { segment }) if segment == "file.txt"
+        ));
+    }
+    #[tokio::test]
+    async fn missing_component_is_not_found() {
+        let resolver = tree();
+
+        assert!(matches!(
+            resolve(&resolver, "/folder/missing/file.txt").await,
+            Err(PathResolveError::NotFound { segment }) if segment == "missing"
+        ));
+    }
 }
@@ -0,0 +1,135 @@
+use crate::prelude::*;
+use gdriver_common::drive_structure::meta::FileKind;
+use gdriver_common::ipc::gdriver_service::ReadDirResult;
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+/// A change of the relationships that was not written to the database yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RelationChange {
+    Add {
+        parent: DriveId,
+        entry: ReadDirResult,
+    },
+    Remove {
+        parent: DriveId,
+        id: DriveId,
+    },
+    Clear,
+}
+
+/// Stores the relationships between parents and children in a SQLite database
+#[derive(Debug)]
+pub(crate) struct RelationStore {
+    connection: Connection,
+}
+
+impl RelationStore {
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        // WAL keeps reads fast while a large batch is written
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        Self::with_connection(connection)
+    }
+    /// A store that only lives as long as it is used, for tests
+    #[cfg(test)]
+    pub(crate) fn open_in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+    fn with_connection(connection: Connection) -> Result<Self> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS relations (
+                parent TEXT NOT NULL,
+                child TEXT NOT NULL,
+                name TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                PRIMARY KEY (parent, child)
+            );
+            CREATE INDEX IF NOT EXISTS relations_child ON relations (child);",
+        )?;
+        Ok(Self { connection })
+    }
+    pub(crate) fn is_empty(&self) -> Result<bool> {
+        let count: i64 =
+            self.connection
+                .query_row("SELECT COUNT(*) FROM relations", [], |row| row.get(0))?;
+        Ok(count == 0)
+    }
+    /// Returns all relationships as parent and child, in the order they were added
+    pub(crate) fn load(&self) -> Result<Vec<(DriveId, ReadDirResult)>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT parent, child, name, kind FROM relations ORDER BY rowid")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?;
+        let mut relations = vec![];
+        for row in rows {
+            let (parent, child, name, kind) = row?;
+            let entry = ReadDirResult {
+                id: child.into(),
+                kind: kind_from_str(&kind)?,
+                name,
+            };
+            relations.push((parent.into(), entry));
+        }
+        Ok(relations)
+    }
+    /// Writes all changes in a single transaction
+    pub(crate) fn apply(&mut self, changes: &[RelationChange]) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut add = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO relations (parent, child, name, kind) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            let mut remove = transaction
+                .prepare_cached("DELETE FROM relations WHERE parent = ?1 AND child = ?2")?;
+            for change in changes {
+                match change {
+                    RelationChange::Add { parent, entry } => {
+                        add.execute(params![
+                            parent.as_ref(),
+                            entry.id.as_ref(),
+                            entry.name,
+                            kind_to_str(&entry.kind)
+                        ])?;
+                    }
+                    RelationChange::Remove { parent, id } => {
+                        remove.execute(params![parent.as_ref(), id.as_ref()])?;
+                    }
+                    RelationChange::Clear => {
+                        transaction.execute("DELETE FROM relations", [])?;
+                    }
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+fn kind_to_str(kind: &FileKind) -> &'static str {
+    match kind {
+        FileKind::File => "file",
+        FileKind::Directory => "directory",
+        FileKind::Symlink => "symlink",
+    }
+}
+fn kind_from_str(kind: &str) -> rusqlite::Result<FileKind> {
+    match kind {
+        "file" => Ok(FileKind::File),
+        "directory" => Ok(FileKind::Directory),
+        "symlink" => Ok(FileKind::Symlink),
+        _ => Err(rusqlite::Error::InvalidColumnType(
+            3,
+            format!("kind {}", kind),
+            rusqlite::types::Type::Text,
+        )),
+    }
+}
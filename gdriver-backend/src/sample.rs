@@ -12,7 +12,7 @@ impl World for HelloServer {
 }
 pub(super) async fn main() -> Result<()> {
     println!("Hello, world!");
-    let config = &CONFIGURATION;
+    let config = CONFIGURATION.current();
     let server_addr = (config.ip, config.port);
     let mut listener = tarpc::serde_transport::tcp::listen(&server_addr, Json::default).await?;
 
@@ -1,27 +1,105 @@
 use super::*;
+use crate::clients::{ClientRegistry, ConnectedClient};
+use crate::drive::local_drive::LocalDrive;
+use crate::drive::remote::RemoteDrive;
 use crate::drive::Drive;
-use chrono::Duration;
+use crate::events::EventLog;
+use crate::shutdown::{Shutdown, UntilShutdown};
 use gdriver_common::{
-    drive_structure::drive_id::{DriveId, ROOT_ID},
+    config::{RemoteKind, TlsMode, TransportKind},
+    drive_structure::drive_id::DriveId,
+    drive_structure::meta::{read_metadata_by_id, FileKind},
+    ipc::admin::BackendStatus,
+    ipc::clients::{ClientId, ClientInfo},
+    ipc::events::{EventBatch, EventSubscription, NEXT_EVENTS_TIMEOUT},
     ipc::gdriver_service::{errors::*, *},
+    ipc::transport::{codec, framed, socket_path},
+    ipc::version::VersionInfo,
+    name_encoding::{decode_name, encode_name, is_truncated},
 };
+use std::collections::HashMap;
 use std::ffi::OsString;
-use std::{path::PathBuf, sync::Arc, thread};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use tarpc::context::Context;
-use tokio::sync::Mutex;
+use tokio::net::TcpListener;
+
+mod tcp;
+mod tls;
+mod unix_socket;
+
+/// How long the requests that were still in progress at the shutdown deadline get to answer
+const ANSWER_GRACE_PERIOD: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
-struct GdriverServer {
-    socket_address: SocketAddr,
-    drive: Arc<Mutex<Drive>>,
+struct GdriverServer<R: RemoteDrive> {
+    /// The connection this server answers
+    client: Arc<ConnectedClient>,
+    clients: Arc<ClientRegistry>,
+    drive: Arc<Drive<R>>,
+    events: Arc<EventLog>,
+    shutdown: Shutdown,
 }
-impl GDriverService for GdriverServer {
+impl<R: RemoteDrive> GdriverServer<R> {
+    /// Registers a new connection, it has to be removed from the clients when it closes
+    fn connect(peer: String, shared: &Shared<R>) -> Self {
+        Self {
+            client: shared.clients.register(peer),
+            clients: shared.clients.clone(),
+            drive: shared.drive.clone(),
+            events: shared.events.clone(),
+            shutdown: shared.shutdown.clone(),
+        }
+    }
+    /// Answers the requests of the client until it disconnects or the backend shuts down.
+    ///
+    /// The transport of the channel has to be wrapped in [UntilShutdown], so no new requests
+    /// are read after the shutdown was requested. This returns once the requests in progress
+    /// are answered.
+    async fn run<C>(self, channel: C)
+    where
+        C: Channel<Req = GDriverServiceRequest, Resp = GDriverServiceResponse> + Send + 'static,
+    {
+        let id = self.client.id;
+        let clients = self.clients.clone();
+        channel
+            .max_concurrent_requests(CONFIGURATION.current().max_requests_per_client)
+            .execute(self.serve())
+            .for_each(spawn)
+            .await;
+        clients.remove(id);
+    }
+}
+/// What the connections of all clients share
+#[derive(Clone)]
+struct Shared<R: RemoteDrive> {
+    clients: Arc<ClientRegistry>,
+    drive: Arc<Drive<R>>,
+    events: Arc<EventLog>,
+    shutdown: Shutdown,
+}
+impl<R: RemoteDrive> GDriverService for GdriverServer<R> {
+    #[instrument(skip(self, _context))]
+    async fn handshake(self, _context: Context, client: VersionInfo) -> VersionInfo {
+        let own = VersionInfo::current();
+        info!(
+            "Client {} has version {} (protocol {})",
+            self.client, client.crate_version, client.protocol_version
+        );
+        // the client refuses to continue on its own, this is only for the log of the backend
+        if let Err(e) = own.check_compatible(&client) {
+            warn!("Client {} is incompatible: {e}", self.client);
+        }
+        own
+    }
+
     async fn set_offline_mode(
         self,
         _context: Context,
         offline_mode: bool,
     ) -> StdResult<(), GDriverServiceError> {
-        self.drive.lock().await.set_offline_mode(offline_mode);
+        self.drive.set_offline_mode(offline_mode);
         Ok(())
     }
 
@@ -32,11 +110,13 @@ impl GDriverService for GdriverServer {
         name: OsString,
         parent: DriveId,
     ) -> StdResult<DriveId, GetFileByPathError> {
-        let drive = self.drive.lock().await;
         let name = name.to_str().ok_or(GetFileByPathError::InvalidName)?;
         info!("Getting file with name '{}' under parent {}", name, parent);
-        let x = drive
+        let x = self
+            .drive
             .path_resolver
+            .read()
+            .await
             .get_id_from_parent_and_name(name, &parent);
         match x {
             None => {
@@ -52,22 +132,88 @@ impl GDriverService for GdriverServer {
         context: Context,
         path: PathBuf,
     ) -> StdResult<DriveId, GetFileByPathError> {
-        let mut drive_lock = self.drive.lock().await;
-        let x = drive_lock.path_resolver.get_id_from_path(&path).await?;
+        let path_resolver = self.drive.path_resolver.read().await;
+        let x = path_resolver.get_id_from_path(&path).await?;
         match x {
             None => Err(GetFileByPathError::NotFound),
             Some(id) => Ok(id),
         }
     }
 
-    #[instrument(skip(self, context))]
+    #[instrument(skip(self, _context))]
+    async fn get_paths_for_file(
+        self,
+        _context: Context,
+        id: DriveId,
+    ) -> StdResult<Vec<PathBuf>, GetPathsError> {
+        let path_resolver = self.drive.path_resolver.read().await;
+        let paths = path_resolver.get_paths_for_id(&id).map_err(|e| {
+            info!("Could not get paths for {id}: {e}");
+            GetPathsError::NotFound
+        })?;
+        trace!("Found {} paths for {id}", paths.len());
+        Ok(paths)
+    }
+
+    #[instrument(skip(self, _context))]
+    async fn add_parent_to_file(
+        self,
+        _context: Context,
+        id: DriveId,
+        parent: DriveId,
+        name: OsString,
+    ) -> StdResult<(), AddParentError> {
+        let meta = read_metadata_by_id(&id).map_err(|_| AddParentError::NotFound)?;
+        if meta.kind == FileKind::Directory {
+            return Err(AddParentError::IsADirectory);
+        }
+        let name = name.to_str().ok_or(AddParentError::NameMismatch)?;
+        {
+            let path_resolver = self.drive.path_resolver.read().await;
+            path_resolver
+                .get_children(&parent)
+                .map_err(|_| AddParentError::NotFound)?;
+            if !path_resolver.is_directory(&parent) {
+                return Err(AddParentError::NotADirectory);
+            }
+            // truncated names can not be decoded, only compared to the encoded name
+            let same_name = if is_truncated(name) {
+                name == encode_name(&meta.name)
+            } else {
+                decode_name(name) == meta.name
+            };
+            if !same_name {
+                info!("{id} is called {:?}, not {name:?}", meta.name);
+                return Err(AddParentError::NameMismatch);
+            }
+            if path_resolver
+                .get_id_from_parent_and_name(name, &parent)
+                .is_some()
+            {
+                return Err(AddParentError::AlreadyExists);
+            }
+        }
+        self.drive.add_parent(&id, &parent).await.map_err(|e| {
+            error!("Could not add {parent} as parent of {id}: {e}");
+            AddParentError::Remote(e.to_string())
+        })
+    }
+
+    #[instrument(skip(self, _context))]
     async fn write_local_change(
         self,
-        context: Context,
+        _context: Context,
         id: DriveId,
     ) -> StdResult<(), WriteLocalChangeError> {
-        error!("Not implemented");
-        Err(WriteLocalChangeError::Other)
+        read_metadata_by_id(&id).map_err(|_| WriteLocalChangeError::UnknownId)?;
+        let uploaded = self.drive.upload_local_change(&id).await.map_err(|e| {
+            error!("Could not upload the local change of {id}: {e}");
+            WriteLocalChangeError::Other
+        })?;
+        if !uploaded {
+            return Err(WriteLocalChangeError::RemoteChanged);
+        }
+        Ok(())
     }
 
     async fn get_metadata_for_file(
@@ -82,22 +228,23 @@ impl GDriverService for GdriverServer {
             return Ok(());
         }
         info!("Meta was not downloaded. Getting from api");
-        let drive = self.drive.lock().await;
-        drive
+        self.drive
             .download_meta_for_file(&id)
             .await
             .map_err(|_| GetMetadataError::DownloadError)?;
         Ok(())
     }
 
-    #[instrument(skip(self, context))]
+    #[instrument(skip(self, _context))]
     async fn download_content_for_file(
         self,
-        context: Context,
+        _context: Context,
         id: DriveId,
     ) -> StdResult<(), GetContentError> {
-        error!("Not implemented");
-        Err(GetContentError::Other)
+        self.drive.download_content_for_file(&id).await.map_err(|e| {
+            error!("Could not download content for {id}: {e}");
+            GetContentError::Other
+        })
     }
 
     #[instrument(skip(self, context))]
@@ -116,10 +263,22 @@ impl GDriverService for GdriverServer {
         id: DriveId,
         offset: usize,
     ) -> StdResult<Vec<ReadDirResult>, GetFileListError> {
-        let drive = self.drive.lock().await;
         info!("Listing files in dir for id {id} with offset {offset}");
-        let children = drive
+        if offset == 0 {
+            // the listing only needs what is known already, the prefetch helps the calls after it
+            let drive = self.drive.clone();
+            let folder = id.clone();
+            tokio::spawn(async move {
+                if let Err(e) = drive.prefetch_children(&folder).await {
+                    warn!("Could not prefetch children of {folder}: {e}");
+                }
+            });
+        }
+        let children = self
+            .drive
             .path_resolver
+            .read()
+            .await
             .get_children(&id)
             .map_err(|_| GetFileListError::NotFound)?
             .clone();
@@ -134,28 +293,41 @@ impl GDriverService for GdriverServer {
         context: Context,
         id: DriveId,
     ) -> StdResult<(), MarkFileAsDeletedError> {
-        error!("Not implemented");
-        Err(MarkFileAsDeletedError::Other)
+        let mut failed = self
+            .mark_files_as_deleted(context, vec![id.clone()])
+            .await?;
+        match failed.remove(&id) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 
-    #[instrument(skip(self, context))]
+    #[instrument(skip(self, _context))]
     async fn mark_file_for_keeping_local(
         self,
-        context: Context,
+        _context: Context,
         id: DriveId,
     ) -> StdResult<(), MarkFileForKeepingLocalError> {
-        error!("Not implemented");
-        Err(MarkFileForKeepingLocalError::Other)
+        let failed = self.drive.keep_local(&id).await.map_err(|e| {
+            error!("Could not keep {id} local: {e}");
+            MarkFileForKeepingLocalError::Other
+        })?;
+        if !failed.is_empty() {
+            return Err(MarkFileForKeepingLocalError::Incomplete(failed));
+        }
+        Ok(())
     }
 
-    #[instrument(skip(self, context))]
+    #[instrument(skip(self, _context))]
     async fn unmark_file_for_keeping_local(
         self,
-        context: Context,
+        _context: Context,
         id: DriveId,
     ) -> StdResult<(), UnmarkFileForKeepingLocalError> {
-        error!("Not implemented");
-        Err(UnmarkFileForKeepingLocalError::Other)
+        self.drive.stop_keeping_local(&id).await.map_err(|e| {
+            error!("Could not stop keeping {id} local: {e}");
+            UnmarkFileForKeepingLocalError::Other
+        })
     }
 
     #[doc = " Returns true if the file was had remote changes and was updated"]
@@ -170,113 +342,371 @@ impl GDriverService for GdriverServer {
     }
 
     async fn update_changes(self, _context: Context) -> StdResult<(), UpdateChangesError> {
-        let drive = self.drive.try_lock();
-        match drive {
-            Ok(mut drive) => {
-                drive.update().await.map_err(|e| {
-                    info!("Error while updating: {e}");
-                    dbg!(e);
-                    UpdateChangesError::Remote
-                })?;
-                Ok(())
+        self.drive.update().await.map_err(|e| {
+            error!("Error while updating: {e:?}");
+            UpdateChangesError::Remote
+        })?;
+        Ok(())
+    }
+
+    #[instrument(skip(self, _context))]
+    async fn next_events(
+        self,
+        _context: Context,
+        since: u64,
+    ) -> StdResult<EventBatch, NextEventsError> {
+        let subscription = self.client.subscription();
+        let batch = tokio::select! {
+            batch = self.events.wait_since(since, NEXT_EVENTS_TIMEOUT, &subscription) => batch,
+            // answers with what happened so far, so the connection does not hold up the shutdown
+            _ = self.shutdown.wait() => {
+                self.events.wait_since(since, Duration::ZERO, &subscription).await
             }
-            Err(_) => {
-                info!("Drive is already updating");
-                Err(UpdateChangesError::Running)
+        };
+        trace!("Returning {} events", batch.events.len());
+        Ok(batch)
+    }
+
+    #[instrument(skip(self, _context))]
+    async fn set_client_name(self, _context: Context, name: String) -> ClientId {
+        info!("Client {} is {name}", self.client.id);
+        self.client.set_name(name);
+        self.client.id
+    }
+
+    #[instrument(skip(self, _context))]
+    async fn set_event_subscription(self, _context: Context, subscription: EventSubscription) {
+        info!("Client {} subscribed to {subscription:?}", self.client);
+        self.client.set_subscription(subscription);
+    }
+
+    async fn list_clients(self, _context: Context) -> Vec<ClientInfo> {
+        self.clients.list()
+    }
+
+    async fn ping(self, _context: Context) -> VersionInfo {
+        VersionInfo::current()
+    }
+
+    #[instrument(skip(self, _context))]
+    async fn status(self, _context: Context) -> BackendStatus {
+        let cache_size = tokio::task::spawn_blocking(|| {
+            directory_size(SETTINGS.cache_path()) + directory_size(SETTINGS.downloaded_path())
+        })
+        .await
+        .unwrap_or_default();
+        BackendStatus {
+            offline_mode: self.drive.offline_mode(),
+            shutting_down: self.shutdown.is_requested(),
+            downloads_in_progress: self.drive.downloads_in_progress(),
+            cache_size,
+            last_sync: self.drive.last_sync(),
+            connected_clients: self.clients.list().len(),
+        }
+    }
+
+    #[instrument(skip(self, _context))]
+    async fn shutdown(self, _context: Context) -> StdResult<(), ShutdownError> {
+        info!("Client {} asked to shut down", self.client);
+        if !self.shutdown.request() {
+            return Err(ShutdownError::AlreadyShuttingDown);
+        }
+        let deadline = self
+            .shutdown
+            .deadline()
+            .expect("the shutdown was just requested");
+        let work_done = async {
+            self.drive.wait_for_uploads().await;
+            self.drive.wait_for_downloads().await
+        };
+        match tokio::time::timeout_at(deadline, work_done).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                error!("Could not wait for the work in progress: {e}");
+                Err(ShutdownError::Unfinished)
             }
+            Err(_) => Err(ShutdownError::Unfinished),
         }
     }
 
-    #[instrument(skip(self))]
-    async fn do_something2(
+    #[instrument(skip(self, _context))]
+    async fn flush_uploads(self, _context: Context) -> StdResult<(), FlushError> {
+        info!("Client {} asked to flush the uploads", self.client);
+        self.drive.wait_for_uploads().await;
+        self.drive.flush().await.map_err(|e| {
+            error!("Could not flush the state: {e}");
+            FlushError::Store(e.to_string())
+        })
+    }
+
+    #[instrument(skip(self, _context))]
+    async fn force_resync(self, _context: Context) -> StdResult<(), ResyncError> {
+        if self.drive.offline_mode() {
+            return Err(ResyncError::Offline);
+        }
+        info!("Client {} asked for a resync", self.client);
+        self.drive.resync().await.map_err(|e| {
+            error!("Could not resync: {e}");
+            ResyncError::Remote(e.to_string())
+        })
+    }
+
+    #[instrument(skip(self, _context))]
+    async fn reload_config(self, _context: Context) -> StdResult<Vec<String>, ReloadConfigError> {
+        let changed = CONFIGURATION.reload().map_err(|e| {
+            warn!("Could not reload the configuration: {e}");
+            ReloadConfigError::Invalid(e.to_string())
+        })?;
+        info!("Reloaded the configuration, changed settings: {changed:?}");
+        Ok(changed)
+    }
+
+    #[instrument(skip(self, _context))]
+    async fn mark_files_as_deleted(
         self,
-        _: Context,
-        req: BackendActionRequest,
-    ) -> std::result::Result<String, BackendActionError> {
-        info!("You are connected from {}", self.socket_address);
-
-        match req {
-            BackendActionRequest::ShutdownGracefully => {
-                info!("Shutdown request received, but I dont want to.");
-                Err(BackendActionError::CouldNotComplete)
-                //Ok(String::from("OK. Shutting down"))
-            }
-            BackendActionRequest::UpdateChanges => {
-                info!("UpdateChanges request received");
-                let drive = &self.drive;
-                print_sample_tracking_state(drive).await;
+        _context: Context,
+        ids: Vec<DriveId>,
+    ) -> StdResult<HashMap<DriveId, MarkFileAsDeletedError>, MarkFileAsDeletedError> {
+        let results = self.drive.trash_files(ids).await.map_err(|e| {
+            error!("Could not move the files to the trash: {e}");
+            MarkFileAsDeletedError::Other
+        })?;
+        Ok(results
+            .into_iter()
+            .filter_map(|(id, result)| {
+                let e = result.err()?;
+                Some((id, MarkFileAsDeletedError::Remote(e.to_string())))
+            })
+            .collect())
+    }
 
-                Ok(String::from("OK"))
-            }
-            BackendActionRequest::Ping => {
-                info!("Ping request received");
-                Ok(String::from("Pong"))
-            }
-            BackendActionRequest::RunLong => {
-                info!("RunLong request received");
-                long_running_task(&self.drive).await;
-                Ok(String::from("OK"))
+    #[instrument(skip(self, _context))]
+    async fn move_files(
+        self,
+        _context: Context,
+        ids: Vec<DriveId>,
+        from: DriveId,
+        to: DriveId,
+    ) -> StdResult<HashMap<DriveId, MoveFilesError>, MoveFilesError> {
+        {
+            let path_resolver = self.drive.path_resolver.read().await;
+            // empty folders are not listed with children, so the folder is found by its parents
+            if to != *ROOT_ID && path_resolver.get_parents(&to).is_err() {
+                return Err(MoveFilesError::NotFound);
             }
-            BackendActionRequest::StartLong => {
-                info!("StartLong request received");
-                tokio::spawn(async move { long_running_task(&self.drive).await });
-                Ok(String::from("OK"))
+            if !path_resolver.is_directory(&to) {
+                return Err(MoveFilesError::NotADirectory);
             }
         }
+        let results = self.drive.move_files(ids, &from, &to).await.map_err(|e| {
+            error!("Could not move the files from {from} to {to}: {e}");
+            MoveFilesError::Other
+        })?;
+        Ok(results
+            .into_iter()
+            .filter_map(|(id, result)| {
+                let e = result.err()?;
+                Some((id, MoveFilesError::Remote(e.to_string())))
+            })
+            .collect())
     }
 }
-async fn long_running_task(drive: &Arc<Mutex<Drive>>) {
-    thread::sleep(Duration::seconds(10).to_std().unwrap());
-    print_sample_tracking_state(drive).await;
-}
-async fn print_sample_tracking_state(drive: &Arc<Mutex<Drive>>) {
-    let drive_lock = drive.lock().await;
-    let state = drive_lock.get_file_tracking_state(&ROOT_ID);
-    dbg!(state);
+/// Adds up the sizes of all files below the directory, unreadable entries count as empty
+fn directory_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| Some((entry.path(), entry.metadata().ok()?)))
+        .map(|(path, metadata)| {
+            if metadata.is_dir() {
+                directory_size(&path)
+            } else {
+                metadata.len()
+            }
+        })
+        .sum()
 }
 pub async fn start() -> Result<()> {
     info!("Hello, world!");
-    let config = &CONFIGURATION;
-    info!("Config: {:?}", **config);
+    let config = CONFIGURATION.current();
+    info!("Config: {:?}", config);
 
-    let mut drive = Drive::new().await?;
+    let events = Arc::new(EventLog::new());
+    match config.remote {
+        RemoteKind::GoogleDrive => {
+            let drive = Drive::new(events.clone()).await?;
+            serve(drive, events).await
+        }
+        RemoteKind::Local => {
+            let path = config
+                .local_remote_path
+                .as_ref()
+                .ok_or("local_remote_path has to be set to use a local remote")?;
+            info!("Syncing with local directory {}", path.display());
+            let drive = Drive::with_remote(LocalDrive::new(path)?, events.clone());
+            serve(drive, events).await
+        }
+    }
+}
+async fn serve<R: RemoteDrive>(drive: Drive<R>, events: Arc<EventLog>) -> Result<()> {
+    let config = CONFIGURATION.current();
+    let shutdown = Shutdown::new();
+    shutdown.request_on_signals()?;
     match drive.ping().await {
         Ok(_) => {
-            info!("Can reach google drive api.");
+            info!("Can reach the remote.");
         }
         Err(e) => {
-            error!("Cannot reach google drive api.");
+            error!("Cannot reach the remote.");
             return Err(e);
         }
     }
     drive.get_all_file_metas().await?;
     drive.update().await?;
-    let drive = Arc::new(Mutex::new(drive));
+    let shared = Shared {
+        clients: Arc::new(ClientRegistry::new()),
+        drive: Arc::new(drive),
+        events,
+        shutdown,
+    };
 
+    match config.transport {
+        TransportKind::Unix => serve_unix(&shared).await?,
+        TransportKind::Tcp => serve_tcp(&shared).await?,
+    }
+    finish_work(&shared).await
+}
+/// Runs the connections until the clients are gone, but after a shutdown request only until
+/// its deadline and a bit longer, so the requests that stopped at the deadline can answer
+async fn until_deadline(connections: impl Future<Output = ()>, shutdown: &Shutdown) {
+    tokio::select! {
+        _ = connections => {}
+        _ = async {
+            shutdown.wait_for_deadline().await;
+            tokio::time::sleep(ANSWER_GRACE_PERIOD).await;
+        } => {
+            warn!("Closed the connections with requests that were still in progress");
+        }
+    }
+}
+/// Waits for the work in progress and writes everything to disk, after the clients are gone
+async fn finish_work<R: RemoteDrive>(shared: &Shared<R>) -> Result<()> {
+    info!("Shutting down, waiting for the work in progress");
+    let drive = &shared.drive;
+    // the clients can be gone without a shutdown request, the timeout starts now then
+    shared.shutdown.request();
+    let deadline = shared
+        .shutdown
+        .deadline()
+        .expect("the shutdown was just requested");
+    let work_done = async {
+        drive.wait_for_uploads().await;
+        drive.wait_for_downloads().await
+    };
+    let finished = tokio::time::timeout_at(deadline, work_done).await;
+    // waits for a sync that is still running
+    drive.flush().await?;
+    match finished {
+        Ok(result) => result?,
+        Err(_) => {
+            return Err("Uploads or downloads did not finish in time, downloads start again".into())
+        }
+    }
+    info!("Shut down cleanly");
+    Ok(())
+}
+async fn serve_tcp<R: RemoteDrive>(shared: &Shared<R>) -> Result<()> {
+    let config = CONFIGURATION.current();
+    if config.tls == TlsMode::Off && !config.ip.is_loopback() {
+        return Err(format!("tls has to be enabled to listen on {}", config.ip).into());
+    }
+    if config.auth_token.is_none() && !config.ip.is_loopback() {
+        warn!("No auth_token is set, every client with a valid certificate can connect");
+    }
+    let acceptor = tls::acceptor(&config)?;
     let server_addr = (config.ip, config.port);
-    let mut listener = tarpc::serde_transport::tcp::listen(&server_addr, Json::default).await?;
+    let listener = TcpListener::bind(&server_addr).await?;
+
+    if acceptor.is_none() {
+        warn!("Listening on {server_addr:?} without TLS, only use this on localhost");
+    } else {
+        info!("Listening on {server_addr:?} with {:?} TLS", config.tls);
+    }
+    let connections = futures::stream::unfold(listener, |listener| async move {
+        let connection = listener.accept().await;
+        Some((connection, listener))
+    })
+    .take_until(shared.shutdown.wait())
+    // Ignore accept errors.
+    .filter_map(|r| future::ready(r.ok()))
+    .map(|(stream, address)| tcp::secure_connection(stream, address, acceptor.clone()))
+    // Handshake with up to 10 clients at the same time.
+    .buffer_unordered(10)
+    .filter_map(future::ready)
+    // a client that hung up already has no address anymore
+    .filter_map(|connection| {
+        future::ready(match tcp::peer_addr(&connection) {
+            Ok(_) => Some(connection),
+            Err(e) => {
+                warn!("Dropping a connection without peer address: {e}");
+                None
+            }
+        })
+    })
+    .map(|connection| UntilShutdown::new(framed(connection), &shared.shutdown))
+    .map(server::BaseChannel::with_defaults)
+    .max_channels_per_key(config.max_clients_per_peer, |t| {
+        let connection = t.transport().get_ref().get_ref();
+        tcp::peer_addr(connection).ok().map(|address| address.ip())
+    })
+    .map(|channel| {
+        let peer = match tcp::peer_addr(channel.transport().get_ref().get_ref()) {
+            Ok(address) => address.to_string(),
+            Err(_) => String::from("unknown address"),
+        };
+        GdriverServer::connect(peer, shared).run(channel)
+    })
+    .buffer_unordered(config.max_clients)
+    .for_each(|_| async {});
+    until_deadline(connections, &shared.shutdown).await;
+    Ok(())
+}
+async fn serve_unix<R: RemoteDrive>(shared: &Shared<R>) -> Result<()> {
+    let config = CONFIGURATION.current();
+    let path = socket_path();
+    unix_socket::prepare_socket_path(&path)?;
+    let mut listener = {
+        let _umask = unix_socket::RestrictiveUmask::set();
+        tarpc::serde_transport::unix::listen(&path, codec).await?
+    };
+    unix_socket::check_permissions(&path)?;
     listener.config_mut().max_frame_length(usize::MAX);
 
-    info!("Listening");
-    listener
+    info!("Listening on {}", path.display());
+    let connections = listener
+        .take_until(shared.shutdown.wait())
         // Ignore accept errors.
         .filter_map(|r| future::ready(r.ok()))
+        // Only the user running the backend may use it.
+        .filter_map(|t| future::ready(unix_socket::check_peer(t.get_ref()).map(|_| t)))
+        .map(|t| UntilShutdown::new(t, &shared.shutdown))
         .map(server::BaseChannel::with_defaults)
-        // Limit channels to 1 per IP.
-        .max_channels_per_key(1, |t| t.transport().peer_addr().unwrap().ip())
-        // serve is generated by the service attribute. It takes as input any type implementing
-        // the generated World trait.
+        .max_channels_per_key(config.max_clients_per_peer, |t| {
+            let stream = t.transport().get_ref().get_ref();
+            stream.peer_cred().ok().and_then(|c| c.pid())
+        })
         .map(|channel| {
-            let c = channel.transport().peer_addr().unwrap();
-            let server = GdriverServer {
-                socket_address: c,
-                drive: drive.clone(),
+            let peer = match channel.transport().get_ref().get_ref().peer_cred() {
+                Ok(credentials) => unix_socket::describe_peer(&credentials),
+                Err(_) => String::from("unknown process"),
             };
-            channel.execute(server.serve()).for_each(spawn)
+            GdriverServer::connect(peer, shared).run(channel)
         })
-        // Max 10 channels.
-        .buffer_unordered(10)
-        .for_each(|_| async {})
-        .await;
+        .buffer_unordered(config.max_clients)
+        .for_each(|_| async {});
+    until_deadline(connections, &shared.shutdown).await;
+    std::fs::remove_file(&path)?;
     Ok(())
 }
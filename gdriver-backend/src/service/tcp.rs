@@ -0,0 +1,52 @@
+use crate::prelude::*;
+use gdriver_common::ipc::transport::{check_token, HANDSHAKE_TIMEOUT};
+use std::io;
+use std::net::SocketAddr;
+use tarpc::tokio_util::either::Either;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// A TCP connection that is encrypted if [Configuration::tls] is on
+pub(super) type TcpConnection = Either<TcpStream, TlsStream<TcpStream>>;
+
+/// Finishes the TLS handshake and checks the auth token.
+///
+/// Returns [None] if the client is not allowed to use the backend.
+pub(super) async fn secure_connection(
+    stream: TcpStream,
+    address: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+) -> Option<TcpConnection> {
+    let auth_token = CONFIGURATION.current().auth_token.clone();
+    let handshake = async {
+        let mut connection = match acceptor {
+            None => Either::Left(stream),
+            Some(acceptor) => Either::Right(acceptor.accept(stream).await?),
+        };
+        let accepted = check_token(&mut connection, auth_token.as_deref()).await?;
+        Result::<_>::Ok(accepted.then_some(connection))
+    };
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(Some(connection))) => Some(connection),
+        Ok(Ok(None)) => {
+            warn!("Rejecting connection from {address}, the auth token does not match");
+            None
+        }
+        Ok(Err(e)) => {
+            warn!("Handshake with {address} failed: {e}");
+            None
+        }
+        Err(_) => {
+            warn!("Handshake with {address} timed out");
+            None
+        }
+    }
+}
+
+pub(super) fn peer_addr(connection: &TcpConnection) -> io::Result<SocketAddr> {
+    match connection {
+        Either::Left(stream) => stream.peer_addr(),
+        Either::Right(stream) => stream.get_ref().0.peer_addr(),
+    }
+}
@@ -0,0 +1,136 @@
+use crate::prelude::*;
+use gdriver_common::config::TlsMode;
+use gdriver_common::ipc::transport::{
+    load_certs, load_key, tls_dir, CA_CERT_FILE, CLIENT_CERT_FILE, CLIENT_KEY_FILE,
+    SERVER_CERT_FILE, SERVER_KEY_FILE,
+};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose,
+};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// The key of the authority is only needed to sign new certificates, so it never leaves the
+/// backend
+const CA_KEY_FILE: &str = "ca.key";
+const KEY_PERMISSIONS: u32 = 0o600;
+const TLS_DIR_PERMISSIONS: u32 = 0o700;
+
+/// Builds what is needed to accept TLS connections, [None] if [Configuration::tls] is off.
+///
+/// Creates the certificate authority and the certificates signed by it on first run.
+pub(super) fn acceptor(config: &Configuration) -> Result<Option<TlsAcceptor>> {
+    if config.tls == TlsMode::Off {
+        return Ok(None);
+    }
+    let dir = tls_dir();
+    if !dir.join(CA_CERT_FILE).exists() {
+        create_certificates(&dir, &config.tls_server_name)?;
+    }
+    let certs = load_certs(&dir.join(SERVER_CERT_FILE))?;
+    let key = load_key(&dir.join(SERVER_KEY_FILE))?;
+    let builder = ServerConfig::builder();
+    let server_config = match config.tls {
+        TlsMode::Off => unreachable!("checked above"),
+        TlsMode::Server => builder.with_no_client_auth().with_single_cert(certs, key)?,
+        TlsMode::Mutual => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&dir.join(CA_CERT_FILE))? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+            builder
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(certs, key)?
+        }
+    };
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+/// Creates a self-signed certificate authority and a certificate for the backend and one for
+/// clients that are signed by it
+#[instrument]
+fn create_certificates(dir: &Path, server_name: &str) -> Result<()> {
+    info!("Creating a certificate authority in {}", dir.display());
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(TLS_DIR_PERMISSIONS)
+        .create(dir)?;
+
+    let mut ca_params = CertificateParams::new(Vec::<String>::new())?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "gdriver certificate authority");
+    ca_params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    let ca_key = KeyPair::generate()?;
+    let ca_cert = ca_params.self_signed(&ca_key)?;
+
+    let server_key = KeyPair::generate()?;
+    let server_cert = sign(
+        vec![server_name.to_string()],
+        ExtendedKeyUsagePurpose::ServerAuth,
+        &server_key,
+        &ca_cert,
+        &ca_key,
+    )?;
+    let client_key = KeyPair::generate()?;
+    let client_cert = sign(
+        Vec::new(),
+        ExtendedKeyUsagePurpose::ClientAuth,
+        &client_key,
+        &ca_cert,
+        &ca_key,
+    )?;
+
+    write_file(&dir.join(SERVER_CERT_FILE), &server_cert.pem())?;
+    write_file(&dir.join(SERVER_KEY_FILE), &server_key.serialize_pem())?;
+    write_file(&dir.join(CLIENT_CERT_FILE), &client_cert.pem())?;
+    write_file(&dir.join(CLIENT_KEY_FILE), &client_key.serialize_pem())?;
+    write_file(&dir.join(CA_KEY_FILE), &ca_key.serialize_pem())?;
+    // written last, so an interrupted run is repeated on the next start
+    write_file(&dir.join(CA_CERT_FILE), &ca_cert.pem())?;
+    info!(
+        "Copy {} (and {} and {} for mutual TLS) to the clients",
+        CA_CERT_FILE, CLIENT_CERT_FILE, CLIENT_KEY_FILE
+    );
+    Ok(())
+}
+
+fn sign(
+    names: Vec<String>,
+    usage: ExtendedKeyUsagePurpose,
+    key: &KeyPair,
+    ca_cert: &Certificate,
+    ca_key: &KeyPair,
+) -> Result<Certificate> {
+    let mut params = CertificateParams::new(names)?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "gdriver");
+    params.extended_key_usages = vec![usage];
+    Ok(params.signed_by(key, ca_cert, ca_key)?)
+}
+
+/// Writes a file only the owner can read, the keys must not leak
+fn write_file(path: &Path, content: &str) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(KEY_PERMISSIONS)
+        .open(path)?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
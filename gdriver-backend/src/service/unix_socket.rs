@@ -0,0 +1,145 @@
+use crate::prelude::*;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
+use std::path::Path;
+use tokio::net::unix::UCred;
+use tokio::net::UnixStream;
+
+/// Only the owner can read and write the socket, which is what connecting needs
+const SOCKET_PERMISSIONS: u32 = 0o600;
+const SOCKET_DIR_PERMISSIONS: u32 = 0o700;
+
+/// Sets a umask that creates files with [SOCKET_PERMISSIONS] and restores the previous one when
+/// dropped, so the socket is never accessible by others, not even right after binding it.
+///
+/// The umask is shared by the whole process, so this should only be held for the bind.
+pub(super) struct RestrictiveUmask(libc::mode_t);
+impl RestrictiveUmask {
+    pub(super) fn set() -> Self {
+        // SAFETY: umask can not fail and only changes the mode of files created afterwards
+        Self(unsafe { libc::umask(0o777 & !SOCKET_PERMISSIONS as libc::mode_t) })
+    }
+}
+impl Drop for RestrictiveUmask {
+    fn drop(&mut self) {
+        // SAFETY: see RestrictiveUmask::set
+        unsafe { libc::umask(self.0) };
+    }
+}
+
+/// Creates the directory of the socket and removes a socket that was left by a backend that
+/// did not stop cleanly.
+///
+/// Fails if another backend is still listening on the socket.
+pub(super) fn prepare_socket_path(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        if !dir.exists() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(SOCKET_DIR_PERMISSIONS)
+                .create(dir)?;
+        }
+    }
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(format!("{} exists and is not a socket", path.display()).into());
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(format!("Another backend is already listening on {}", path.display()).into());
+    }
+    info!("Removing stale socket {}", path.display());
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Makes sure no other user can connect before accepting connections, the directory of the
+/// socket might be shared
+pub(super) fn check_permissions(path: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mode = metadata.mode() & 0o777;
+    if metadata.uid() != own_uid() || mode != SOCKET_PERMISSIONS {
+        return Err(format!(
+            "{} is not private, it belongs to user {} and has mode {:o}",
+            path.display(),
+            metadata.uid(),
+            mode
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn own_uid() -> libc::uid_t {
+    // SAFETY: geteuid can not fail and has no side effects
+    unsafe { libc::geteuid() }
+}
+
+/// Returns the credentials of the connected process if it is the user running the backend
+pub(super) fn check_peer(stream: &UnixStream) -> Option<UCred> {
+    let credentials = match stream.peer_cred() {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            warn!("Could not get the credentials of a client: {e}");
+            return None;
+        }
+    };
+    let own_uid = own_uid();
+    if credentials.uid() != own_uid {
+        warn!(
+            "Rejecting connection from user {} (pid {:?}), only user {} is allowed",
+            credentials.uid(),
+            credentials.pid(),
+            own_uid
+        );
+        return None;
+    }
+    Some(credentials)
+}
+
+/// Describes the connected process for logging
+pub(super) fn describe_peer(credentials: &UCred) -> String {
+    match credentials.pid() {
+        Some(pid) => format!("process {pid} of user {}", credentials.uid()),
+        None => format!("user {}", credentials.uid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+
+    fn socket_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("gdriver-socket-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn socket_bound_with_the_umask_is_private() {
+        let path = socket_dir("umask").join("test.sock");
+        let _listener = {
+            let _umask = RestrictiveUmask::set();
+            UnixListener::bind(&path).unwrap()
+        };
+
+        assert!(check_permissions(&path).is_ok());
+    }
+
+    #[test]
+    fn socket_others_can_use_is_rejected() {
+        let path = socket_dir("open").join("test.sock");
+        let _listener = UnixListener::bind(&path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+
+        assert!(check_permissions(&path).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use crate::prelude::*;
+use futures::{Sink, Stream};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Tells every part of the backend that it should stop.
+///
+/// Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    /// When the work in progress is given up, set once the shutdown is requested
+    deadline: Arc<watch::Sender<Option<Instant>>>,
+}
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            deadline: Arc::new(watch::Sender::new(None)),
+        }
+    }
+    /// Asks the backend to stop, returns false if that was already asked for
+    pub fn request(&self) -> bool {
+        let timeout = Duration::from_secs(CONFIGURATION.current().shutdown_timeout_secs);
+        self.deadline.send_if_modified(|deadline| {
+            let first = deadline.is_none();
+            if first {
+                *deadline = Some(Instant::now() + timeout);
+            }
+            first
+        })
+    }
+    pub fn is_requested(&self) -> bool {
+        self.deadline.borrow().is_some()
+    }
+    /// When the work in progress is given up, [None] while no shutdown was requested
+    pub fn deadline(&self) -> Option<Instant> {
+        *self.deadline.borrow()
+    }
+    /// Requests the shutdown on SIGINT and SIGTERM.
+    ///
+    /// A second signal ends the process right away, without waiting for work in progress.
+    pub fn request_on_signals(&self) -> Result<()> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let name = tokio::select! {
+                    _ = interrupt.recv() => "SIGINT",
+                    _ = terminate.recv() => "SIGTERM",
+                };
+                if shutdown.request() {
+                    info!("Got {name}, shutting down. Send it again to stop right away.");
+                } else {
+                    warn!("Got {name} again, stopping without finishing the work in progress");
+                    std::process::exit(1);
+                }
+            }
+        });
+        Ok(())
+    }
+    /// Waits until [Shutdown::request] was called
+    pub async fn wait(&self) {
+        let mut receiver = self.deadline.subscribe();
+        // the sender lives as long as self, so this can not fail
+        let _ = receiver.wait_for(Option::is_some).await;
+    }
+    /// Waits until the shutdown was requested and its deadline passed
+    pub async fn wait_for_deadline(&self) {
+        self.wait().await;
+        if let Some(deadline) = self.deadline() {
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+/// A transport that stops reading requests once the shutdown is requested.
+///
+/// Writing still works, so tarpc answers the requests that are in progress and then closes the
+/// connection, instead of dropping it with the answers.
+pub struct UntilShutdown<T> {
+    inner: T,
+    shutdown: Pin<Box<dyn Future<Output = ()> + Send>>,
+    shutting_down: bool,
+}
+impl<T> UntilShutdown<T> {
+    pub fn new(inner: T, shutdown: &Shutdown) -> Self {
+        let shutdown = shutdown.clone();
+        Self {
+            inner,
+            shutdown: Box::pin(async move { shutdown.wait().await }),
+            shutting_down: false,
+        }
+    }
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+impl<T: Stream + Unpin> Stream for UntilShutdown<T> {
+    type Item = T::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T::Item>> {
+        let this = self.get_mut();
+        if !this.shutting_down && this.shutdown.as_mut().poll(cx).is_ready() {
+            this.shutting_down = true;
+        }
+        if this.shutting_down {
+            return Poll::Ready(None);
+        }
+        Pin::new(&mut this.inner).poll_next(cx)
+    }
+}
+impl<T: Sink<I> + Unpin, I> Sink<I> for UntilShutdown<T> {
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<StdResult<(), T::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_ready(cx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: I) -> StdResult<(), T::Error> {
+        Pin::new(&mut self.get_mut().inner).start_send(item)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<StdResult<(), T::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<StdResult<(), T::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,24 @@
+//! Helpers shared by the tests of the backend
+use crate::prelude::*;
+use gdriver_common::ipc::gdriver_service::SETTINGS;
+use std::sync::Once;
+
+/// Points the data, cache and runtime directories to a new temporary directory and creates
+/// them, so tests never touch the files of a real installation.
+///
+/// Has to be called before anything else uses [SETTINGS]. All tests of a run share the
+/// directory, so they have to use ids that are unique across tests.
+pub(crate) fn init_dirs() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let root =
+            std::env::temp_dir().join(format!("gdriver-backend-tests-{}", std::process::id()));
+        std::env::set_var("XDG_DATA_HOME", root.join("data"));
+        std::env::set_var("XDG_CACHE_HOME", root.join("cache"));
+        std::env::set_var("XDG_RUNTIME_DIR", root.join("run"));
+        SETTINGS
+            .initialize_dirs()
+            .expect("the test directories can be created");
+        debug!("Test directories are in {}", root.display());
+    });
+}
@@ -1,28 +1,38 @@
-use crate::filesystem::attributes::{read_inode_attributes_from_meta_file, ConvertFileType};
+use crate::filesystem::attributes::{
+    directory_hardlinks, read_inode_attributes_from_meta_file, ConvertFileType,
+};
 use crate::filesystem::errors::FilesystemError;
 use crate::prelude::macros::*;
 use crate::prelude::*;
 use anyhow::anyhow;
 use bimap::BiMap;
-use fuser::{KernelConfig, ReplyAttr, ReplyDirectory, ReplyEntry, Request};
+use fuser::{KernelConfig, ReplyAttr, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, Request};
 use gdriver_common::drive_structure::drive_id::DriveId;
 use gdriver_common::drive_structure::drive_id::ROOT_ID;
-use gdriver_common::ipc::gdriver_service::errors::GDriverServiceError;
+use gdriver_common::drive_structure::meta::FileKind;
+use gdriver_common::ipc::gdriver_service::errors::{AddParentError, GDriverServiceError};
 use gdriver_common::ipc::gdriver_service::GDriverServiceClient;
 use gdriver_common::ipc::gdriver_service::SETTINGS;
+use gdriver_common::ipc::version::{VersionInfo, CAPABILITY_HARD_LINKS};
 use lazy_static::lazy_static;
+use open_files::{OpenFiles, SharedOpenFiles};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
+use std::future::Future;
 use std::os::raw::c_int;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 use tarpc::context::current as current_context;
-use tokio::sync::mpsc::Receiver;
+use tokio::runtime::Handle;
+use tracing::Instrument;
 
+pub(crate) mod invalidation;
 mod macros;
+pub(crate) mod open_files;
 
-//TODO2: Decide if this is a good TTL
-const TTL: Duration = Duration::from_secs(2);
+/// The kernel gets notified by [invalidation] when something changes, so this can be quite long
+const TTL: Duration = Duration::from_secs(60);
 const GROUP_NAME: &str = "gdriver2";
 lazy_static! {
     pub static ref USER_ID: u32 = uzers::get_current_uid();
@@ -45,28 +55,75 @@ pub enum ShutdownRequest {
     Gracefully,
     Force,
 }
+/// Handles the requests of the kernel.
+///
+/// The fuser session runs every handler on a single thread, so the handlers only take what they
+/// need from the request and [spawn](Filesystem::spawn) the work on the tokio runtime, which
+/// replies once it is done. That way a slow request to the backend does not hold up the others.
+/// Cloning is cheap, every clone shares the same state.
+#[derive(Clone)]
 pub struct Filesystem {
     gdriver_client: GDriverServiceClient,
+    /// Tells which features the backend supports
+    backend_version: Arc<VersionInfo>,
+    runtime: Handle,
 
-    entry_ids: BiMap<Inode, DriveId>,
-    ino_to_file_handles: HashMap<Inode, Vec<u64>>,
-    next_ino: u64,
-    entry_name_parent_to_ino: BiMap<FileIdentifier, Inode>,
-    shutdown_signal_receiver: Receiver<ShutdownRequest>,
+    inodes: SharedInodeTable,
+    open_files: SharedOpenFiles,
 }
 
 impl Filesystem {
-    pub fn new(
-        gdriver_client: GDriverServiceClient,
-        shutdown_signal_receiver: Receiver<ShutdownRequest>,
-    ) -> Self {
+    /// Has to be called from within the tokio runtime the requests should be handled on
+    pub fn new(gdriver_client: GDriverServiceClient, backend_version: VersionInfo) -> Self {
         Self {
             gdriver_client,
+            backend_version: Arc::new(backend_version),
+            runtime: Handle::current(),
+            inodes: Arc::new(Mutex::new(InodeTable::new())),
+            open_files: Arc::new(Mutex::new(OpenFiles::default())),
+        }
+    }
+    /// The inode table of this filesystem, used to invalidate the kernel cache for changed ids
+    pub(crate) fn inode_table(&self) -> SharedInodeTable {
+        self.inodes.clone()
+    }
+    fn inodes(&self) -> MutexGuard<'_, InodeTable> {
+        self.inodes.lock().unwrap()
+    }
+    /// The files that are open, used to decide whether the filesystem can be unmounted
+    pub(crate) fn open_files(&self) -> SharedOpenFiles {
+        self.open_files.clone()
+    }
+    /// Runs an operation on the tokio runtime with a clone of this filesystem, so the session
+    /// thread can take the next request right away. The operation has to reply itself.
+    fn spawn<F>(&self, operation: impl FnOnce(Filesystem) -> F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.runtime
+            .spawn(operation(self.clone()).in_current_span());
+    }
+}
+
+pub(crate) type SharedInodeTable = Arc<Mutex<InodeTable>>;
+/// Keeps track of which inode belongs to which drive id and under which names it was looked up.
+///
+/// Every drive id has a single inode, a file with more than one parent is a hard link and can
+/// be looked up under more than one name.
+#[derive(Debug)]
+pub(crate) struct InodeTable {
+    entry_ids: BiMap<Inode, DriveId>,
+    next_ino: u64,
+    entry_name_parent_to_ino: HashMap<FileIdentifier, Inode>,
+    ino_to_entry_names: HashMap<Inode, Vec<FileIdentifier>>,
+}
+impl InodeTable {
+    fn new() -> Self {
+        Self {
             entry_ids: BiMap::new(),
-            ino_to_file_handles: HashMap::new(),
             next_ino: 222,
-            entry_name_parent_to_ino: BiMap::new(),
-            shutdown_signal_receiver,
+            entry_name_parent_to_ino: HashMap::new(),
+            ino_to_entry_names: HashMap::new(),
         }
     }
     fn generate_ino(&mut self) -> Inode {
@@ -77,10 +134,13 @@ impl Filesystem {
 }
 
 //region DriveFilesystem ino_to_id
-impl Filesystem {
+impl InodeTable {
     fn get_id_from_ino(&self, ino: Inode) -> Option<&DriveId> {
         self.entry_ids.get_by_left(&ino)
     }
+    pub(crate) fn get_existing_ino_from_id(&self, id: &DriveId) -> Option<Inode> {
+        self.entry_ids.get_by_right(id).copied()
+    }
     fn get_ino_from_id(&mut self, id: DriveId) -> Inode {
         let x = self.entry_ids.get_by_right(&id);
         if let Some(ino) = x {
@@ -106,7 +166,44 @@ impl Filesystem {
         self.entry_ids.insert(ino, id);
     }
 }
-
+//endregion
+//region DriveFilesystem entry names
+impl InodeTable {
+    fn get_ino_from_entry(&self, parent: Inode, name: OsString) -> Option<Inode> {
+        self.entry_name_parent_to_ino
+            .get(&FileIdentifier { parent, name })
+            .copied()
+    }
+    fn add_entry(&mut self, parent: Inode, name: OsString, ino: Inode) {
+        trace!("adding entry {:?} in {} => {}", name, parent, ino);
+        let entry = FileIdentifier { parent, name };
+        if let Some(previous) = self.entry_name_parent_to_ino.insert(entry.clone(), ino) {
+            if let Some(entries) = self.ino_to_entry_names.get_mut(&previous) {
+                entries.retain(|e| *e != entry);
+            }
+        }
+        self.ino_to_entry_names.entry(ino).or_default().push(entry);
+    }
+    /// Forgets all names the inode was looked up with and returns their parents and names
+    pub(crate) fn remove_entries(&mut self, ino: Inode) -> Vec<(Inode, OsString)> {
+        let entries = self.ino_to_entry_names.remove(&ino).unwrap_or_default();
+        entries
+            .into_iter()
+            .filter_map(|entry| {
+                self.entry_name_parent_to_ino.remove(&entry)?;
+                Some((entry.parent, entry.name))
+            })
+            .collect()
+    }
+    /// Forgets every name that was looked up, since all of them could be outdated
+    pub(crate) fn clear_entries(&mut self) -> Vec<(Inode, OsString)> {
+        self.ino_to_entry_names.clear();
+        std::mem::take(&mut self.entry_name_parent_to_ino)
+            .into_keys()
+            .map(|entry| (entry.parent, entry.name))
+            .collect()
+    }
+}
 //endregion
 mod attributes;
 
@@ -114,70 +211,130 @@ impl fuser::Filesystem for Filesystem {
     //region init
     #[instrument(skip(self, _req, _config))]
     fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> StdResult<(), c_int> {
-        self.add_id_to_inode(ROOT_ID.clone(), 1);
-
-        send_request!(self.gdriver_client.update_changes(current_context()))
-            .map_err(|e| {
-                error!("Got a connection error while updating changes for on init. ");
-                dbg!(e);
-                libc::ECANCELED
-            })?
-            .map_err(|e| {
-                error!("Error while updating changes on init");
-                dbg!(e);
-                libc::EIO
-            })?;
+        // the changes were updated before mounting, init must not wait for the backend
+        self.inodes().add_id_to_inode(ROOT_ID.clone(), 1);
         Ok(())
     }
     //endregion
     //region lookup
     #[instrument(skip(self, _req, reply))]
     fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let metadata = utils::lookup::lookup(self, parent, name.to_os_string());
-        match metadata {
-            Ok(metadata) => {
-                info!("Got metadata: {metadata:?}");
-                reply.entry(&TTL, &metadata.into(), 0);
-            }
-            Err(e) => {
-                error!("Got an error during lookup: {e:?}");
-                match e {
-                    FilesystemError::Rpc(_) => reply.error(libc::EREMOTEIO),
-                    FilesystemError::IO(_) => reply.error(libc::EIO),
-                    FilesystemError::Service(_) | FilesystemError::NotFound => {
-                        reply.error(libc::ENOENT)
-                    }
-                    FilesystemError::Other(e) => {
-                        dbg!(e);
-                        todo!("Handle other errors and decide what error code should be used here")
+        let name = name.to_os_string();
+        self.spawn(|fs| async move {
+            let metadata = utils::lookup::lookup(&fs, parent, name).await;
+            match metadata {
+                Ok(metadata) => {
+                    info!("Got metadata: {metadata:?}");
+                    reply.entry(&TTL, &metadata.into(), 0);
+                }
+                Err(e) => {
+                    error!("Got an error during lookup: {e:?}");
+                    match e {
+                        FilesystemError::Rpc(_) => reply.error(libc::EREMOTEIO),
+                        FilesystemError::IO(_) => reply.error(libc::EIO),
+                        FilesystemError::Service(_) | FilesystemError::NotFound => {
+                            reply.error(libc::ENOENT)
+                        }
+                        FilesystemError::Other(_) => reply.error(libc::EIO),
                     }
                 }
             }
+        });
+    }
+    //endregion
+    //region link
+    #[instrument(skip(self, _req, reply))]
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        if !self.backend_version.supports(CAPABILITY_HARD_LINKS) {
+            reply.error(libc::ENOSYS);
+            return;
         }
+        let newname = newname.to_os_string();
+        self.spawn(|fs| async move {
+            match utils::link::link(&fs, ino, newparent, newname).await {
+                Ok(attributes) => {
+                    reply.entry(&TTL, &attributes.into(), 0);
+                }
+                Err(e) => {
+                    error!("Got an error during link: {e:?}");
+                    let error_code = match e {
+                        FilesystemError::Rpc(_) => libc::EREMOTEIO,
+                        FilesystemError::NotFound => libc::ENOENT,
+                        FilesystemError::Service(GDriverServiceError::AddParent(e)) => match e {
+                            AddParentError::NotFound => libc::ENOENT,
+                            AddParentError::NotADirectory => libc::ENOTDIR,
+                            // Drive can not give a file a second name or a folder a second parent
+                            AddParentError::IsADirectory | AddParentError::NameMismatch => {
+                                libc::EPERM
+                            }
+                            AddParentError::AlreadyExists => libc::EEXIST,
+                            AddParentError::Remote(_) | AddParentError::Other => libc::EIO,
+                        },
+                        _ => libc::EIO,
+                    };
+                    reply.error(error_code);
+                }
+            }
+        });
+    }
+    //endregion
+    //region open
+    #[instrument(skip(self, _req, reply))]
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let _ = reply_error_o!(
+            self.inodes().get_id_from_ino(ino),
+            reply,
+            libc::ENOENT,
+            "Could not find the id of inode {}",
+            ino
+        );
+        let fh = self.open_files.lock().unwrap().open(ino);
+        reply.opened(fh, 0);
+    }
+    //endregion
+    //region release
+    #[instrument(skip(self, _req, reply))]
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.open_files.lock().unwrap().release(fh);
+        reply.ok();
     }
     //endregion
     #[instrument(skip(self, _req, reply))]
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        let id = self.get_id_from_ino(ino);
+        let id = self.inodes().get_id_from_ino(ino).cloned();
         info!("getting attributes: {id:?}/{ino}");
-        match id {
-            None => {
-                reply.error(libc::ENOENT);
-            }
-            Some(id) => {
-                let result = utils::get_attributes(self, id, ino);
-                match result {
-                    Ok(attr) => {
-                        reply.attr(&TTL, &attr.into());
-                    }
-                    Err(e) => {
-                        error!("Got an error during readdir: {}", e);
-                        dbg!(e);
-                        reply.error(libc::EIO);
-                    }
+        let Some(id) = id else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        self.spawn(|fs| async move {
+            let result = utils::get_attributes(&fs, &id, ino).await;
+            match result {
+                Ok(attr) => {
+                    reply.attr(&TTL, &attr.into());
+                }
+                Err(e) => {
+                    error!("Got an error during getattr: {e:?}");
+                    reply.error(libc::EIO);
                 }
             }
-        }
+        });
     }
     #[instrument(skip(self, _req, reply))]
     fn readdir(
@@ -188,7 +345,7 @@ impl fuser::Filesystem for Filesystem {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        let id = self.get_id_from_ino(ino);
+        let id = self.inodes().get_id_from_ino(ino).cloned();
         info!("Reading dir: {id:?}/{ino}");
         let mut counter = 0;
         if offset == 0 {
@@ -207,40 +364,44 @@ impl fuser::Filesystem for Filesystem {
                 return;
             }
         }
-        if let Err(e) = utils::update::update(self) {
-            error!("Got an error during update in readdir: {}", e);
-            dbg!(e);
-            reply.error(libc::EIO);
-            return;
-        }
+        self.spawn(|fs| async move {
+            if let Err(e) = utils::update::update(&fs).await {
+                error!("Got an error during update in readdir: {e:?}");
+                reply.error(libc::EIO);
+                return;
+            }
 
-        match id {
-            None => {}
-            Some(id) => {
-                let result = utils::readdir::readdir(self, id.clone(), (offset - counter) as u64);
-                match result {
-                    Ok(entries) => {
-                        for entry in entries {
-                            let ino = self.get_ino_from_id(entry.id);
-                            counter += 1;
-                            let buffer_full =
-                                reply.add(ino, offset + counter, entry.kind.into_ft(), entry.name);
-                            if buffer_full {
-                                debug!("Buffer full after {counter}");
-                                break;
+            match id {
+                None => {}
+                Some(id) => {
+                    let result = utils::readdir::readdir(&fs, id, (offset - counter) as u64).await;
+                    match result {
+                        Ok(entries) => {
+                            for entry in entries {
+                                let ino = fs.inodes().get_ino_from_id(entry.id);
+                                counter += 1;
+                                let buffer_full = reply.add(
+                                    ino,
+                                    offset + counter,
+                                    entry.kind.into_ft(),
+                                    entry.name,
+                                );
+                                if buffer_full {
+                                    debug!("Buffer full after {counter}");
+                                    break;
+                                }
                             }
+                            debug!("sending ok");
+                            reply.ok();
+                        }
+                        Err(e) => {
+                            error!("Got an error during readdir: {e:?}");
+                            reply.error(libc::EIO);
                         }
-                        debug!("sending ok");
-                        reply.ok();
-                    }
-                    Err(e) => {
-                        error!("Got an error during readdir: {}", e);
-                        dbg!(e);
-                        reply.error(libc::EIO);
                     }
                 }
             }
-        }
+        });
     }
 }
 mod errors {
@@ -268,9 +429,11 @@ mod utils {
     pub mod update {
         use super::*;
         #[instrument(skip(fs))]
-        pub fn update(fs: &Filesystem) -> StdResult<(), FilesystemError> {
+        pub async fn update(fs: &Filesystem) -> StdResult<(), FilesystemError> {
             info!("Updating changes");
-            send_request!(fs.gdriver_client.update_changes(current_context(),))?
+            fs.gdriver_client
+                .update_changes(current_context())
+                .await?
                 .map_err(GDriverServiceError::from)?;
             Ok(())
         }
@@ -283,81 +446,117 @@ mod utils {
         use gdriver_common::ipc::gdriver_service::errors::GetFileByPathError;
 
         #[instrument(skip(fs))]
-        pub fn lookup(
-            fs: &mut Filesystem,
+        pub async fn lookup(
+            fs: &Filesystem,
             parent: Inode,
             name: OsString,
         ) -> StdResult<InodeAttributes, FilesystemError> {
             let id: DriveId;
             let ino: Inode;
 
-            let ino_opt = fs.entry_name_parent_to_ino.get_by_left(&FileIdentifier {
-                parent,
-                name: name.clone(),
-            });
+            let ino_opt = fs.inodes().get_ino_from_entry(parent, name.clone());
 
             match ino_opt {
                 None => {
                     //we don't know this name with this parent already, so we have to look it up
                     let parent_id = fs
-                        .entry_ids
-                        .get_by_left(&parent)
-                        .ok_or(FilesystemError::NotFound)?;
+                        .inodes()
+                        .get_id_from_ino(parent)
+                        .ok_or(FilesystemError::NotFound)?
+                        .clone();
                     info!(
                         "looking for child of parent:{} with name: {:?}",
                         parent_id, name
                     );
-                    id = send_request!(fs.gdriver_client.get_file_by_name(
-                        current_context(),
-                        name,
-                        parent_id.clone()
-                    ))?
-                    .map_err(GDriverServiceError::from)?;
+                    id = fs
+                        .gdriver_client
+                        .get_file_by_name(current_context(), name.clone(), parent_id)
+                        .await?
+                        .map_err(GDriverServiceError::from)?;
 
-                    ino = fs.add_id(id.clone());
+                    let mut inodes = fs.inodes();
+                    // the same file can be found under other parents, it keeps its inode
+                    ino = inodes.get_ino_from_id(id.clone());
+                    inodes.add_entry(parent, name, ino);
                 }
                 Some(i) => {
                     info!("Found ino in cache: {}", i);
-                    ino = *i;
+                    ino = i;
                     id = fs
-                        .get_id_from_ino(*i)
+                        .inodes()
+                        .get_id_from_ino(i)
                         .ok_or(FilesystemError::NotFound)?
                         .clone();
                 }
             }
-            get_attributes(fs, &id, ino)
+            get_attributes(fs, &id, ino).await
+        }
+    }
+    pub mod link {
+        use super::*;
+
+        /// Adds the new parent to the file behind the inode, so it shows up in both folders
+        #[instrument(skip(fs))]
+        pub async fn link(
+            fs: &Filesystem,
+            ino: Inode,
+            new_parent: Inode,
+            name: OsString,
+        ) -> StdResult<InodeAttributes, FilesystemError> {
+            let (id, parent_id) = {
+                let inodes = fs.inodes();
+                let id = inodes
+                    .get_id_from_ino(ino)
+                    .ok_or(FilesystemError::NotFound)?
+                    .clone();
+                let parent_id = inodes
+                    .get_id_from_ino(new_parent)
+                    .ok_or(FilesystemError::NotFound)?
+                    .clone();
+                (id, parent_id)
+            };
+            info!("Adding {} as parent of {}", parent_id, id);
+            fs.gdriver_client
+                .add_parent_to_file(current_context(), id.clone(), parent_id, name.clone())
+                .await?
+                .map_err(GDriverServiceError::from)?;
+            fs.inodes().add_entry(new_parent, name, ino);
+            get_attributes(fs, &id, ino).await
         }
     }
     #[instrument(skip(fs))]
-    pub(crate) fn get_attributes(
+    pub(crate) async fn get_attributes(
         fs: &Filesystem,
         id: &DriveId,
         ino: Inode,
     ) -> StdResult<InodeAttributes, FilesystemError> {
-        let open_file_handles = fs.ino_to_file_handles.get(&ino).map(Vec::len).unwrap_or(0) as u64;
-        send_request!(fs
-            .gdriver_client
-            .get_metadata_for_file(current_context(), id.clone()))?
-        .map_err(GDriverServiceError::from)?;
+        let open_file_handles = fs.open_files.lock().unwrap().count_for_ino(ino) as u64;
+        fs.gdriver_client
+            .get_metadata_for_file(current_context(), id.clone())
+            .await?
+            .map_err(GDriverServiceError::from)?;
         let meta_path = SETTINGS.get_metadata_file_path(&id);
-        let metadata = read_inode_attributes_from_meta_file(&meta_path, ino, open_file_handles)
+        let mut metadata = read_inode_attributes_from_meta_file(&meta_path, ino, open_file_handles)
             .map_err(FilesystemError::IO)?;
+        if metadata.kind == FileKind::Directory {
+            let children = readdir::readdir(fs, id.clone(), 0).await?;
+            metadata.hardlinks = directory_hardlinks(&children);
+        }
         Ok(metadata)
     }
     pub mod readdir {
         use super::*;
-        pub fn readdir(
-            fs: &mut Filesystem,
+        pub async fn readdir(
+            fs: &Filesystem,
             id: DriveId,
             offset: u64,
         ) -> StdResult<Vec<gdriver_common::ipc::gdriver_service::ReadDirResult>, FilesystemError>
         {
-            let res = send_request!(fs.gdriver_client.list_files_in_directory_with_offset(
-                current_context(),
-                id,
-                offset as usize
-            ))?
-            .map_err(GDriverServiceError::from)?;
+            let res = fs
+                .gdriver_client
+                .list_files_in_directory_with_offset(current_context(), id, offset as usize)
+                .await?
+                .map_err(GDriverServiceError::from)?;
             Ok(res)
         }
     }
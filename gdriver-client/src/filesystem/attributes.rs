@@ -2,6 +2,7 @@ use crate::filesystem::{GDRIVER_GROUP_ID, USER_ID};
 use crate::prelude::*;
 use fuser::FileType;
 use gdriver_common::drive_structure::meta::{read_metadata_file, FileKind, Metadata, TIMESTAMP};
+use gdriver_common::ipc::gdriver_service::ReadDirResult;
 use gdriver_common::time_utils;
 use gdriver_common::time_utils::time_from_system_time;
 use std::collections::BTreeMap;
@@ -107,12 +108,26 @@ pub(crate) fn read_inode_attributes_from_metadata(
         last_metadata_changed: metadata.last_metadata_changed,
         kind: metadata.kind,
         permissions: metadata.permissions,
-        hardlinks: 0,
+        hardlinks: match metadata.kind {
+            // counted from the children with [directory_hardlinks]
+            FileKind::Directory => 2,
+            // the root and files from older metadata files have no parents
+            _ => metadata.parent_count.max(1),
+        },
         uid: *USER_ID,
         gid: *GDRIVER_GROUP_ID,
         xattrs: metadata.extra_attributes,
     }
 }
+/// A directory is linked from its parent, from its own `.` and from the `..` of every
+/// subdirectory
+pub(crate) fn directory_hardlinks(children: &[ReadDirResult]) -> u32 {
+    let subdirectories = children
+        .iter()
+        .filter(|child| child.kind == FileKind::Directory)
+        .count();
+    2 + subdirectories as u32
+}
 pub(crate) fn read_inode_attributes_from_meta_file(
     meta_path: &Path,
     inode: Inode,
@@ -0,0 +1,92 @@
+use crate::filesystem::SharedInodeTable;
+use crate::prelude::*;
+use fuser::Notifier;
+use gdriver_common::ipc::events::{
+    BackendEvent, BackendEventKind, EventBatch, EventSubscription, LATEST_EVENT,
+    NEXT_EVENTS_TIMEOUT,
+};
+use gdriver_common::ipc::gdriver_service::GDriverServiceClient;
+use std::time::{Duration, Instant};
+use tarpc::context;
+
+/// How long to wait before asking again after the backend could not be reached
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The events [run_invalidation] reacts to, the others don't need to wake it up
+pub(crate) fn subscription() -> EventSubscription {
+    EventSubscription {
+        kinds: vec![BackendEventKind::Changed, BackendEventKind::Removed],
+        ids: Vec::new(),
+    }
+}
+
+/// Waits for events from the backend and tells the kernel to drop its cached attributes,
+/// directory entries and content for the files that changed.
+///
+/// This runs until the program ends.
+#[instrument(skip_all)]
+pub(crate) async fn run_invalidation(
+    gdriver_client: GDriverServiceClient,
+    inodes: SharedInodeTable,
+    notifier: Notifier,
+) {
+    let mut since = LATEST_EVENT;
+    loop {
+        let mut context = context::current();
+        context.deadline = Instant::now() + NEXT_EVENTS_TIMEOUT + RETRY_DELAY;
+        let batch = match gdriver_client.next_events(context, since).await {
+            Ok(Ok(batch)) => batch,
+            Ok(Err(e)) => {
+                error!("Backend could not get the next events: {e}");
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+            Err(e) => {
+                warn!("Could not get the next events: {e}");
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+        if since != LATEST_EVENT {
+            invalidate(&inodes, &notifier, &batch);
+        }
+        since = batch.next;
+    }
+}
+
+fn invalidate(inodes: &SharedInodeTable, notifier: &Notifier, batch: &EventBatch) {
+    // the lock can not be held while notifying the kernel, since the kernel might be waiting
+    // on a request that needs the lock itself
+    let (changed_inodes, changed_entries) = {
+        let mut inodes = inodes.lock().unwrap();
+        let mut changed_entries = vec![];
+        if batch.incomplete {
+            info!("Missed some events, invalidating all entries");
+            changed_entries.extend(inodes.clear_entries());
+        }
+        let mut changed_inodes = vec![];
+        for event in batch.events.iter() {
+            let id = match event {
+                BackendEvent::Changed(id) | BackendEvent::Removed(id) => id,
+                _ => continue,
+            };
+            // if the kernel never saw this id, there is nothing cached
+            if let Some(ino) = inodes.get_existing_ino_from_id(id) {
+                trace!("Invalidating {} ({})", id, ino);
+                changed_inodes.push(ino);
+                changed_entries.extend(inodes.remove_entries(ino));
+            }
+        }
+        (changed_inodes, changed_entries)
+    };
+    for ino in changed_inodes {
+        if let Err(e) = notifier.inval_inode(ino, 0, 0) {
+            debug!("Could not invalidate inode {}: {}", ino, e);
+        }
+    }
+    for (parent, name) in changed_entries {
+        if let Err(e) = notifier.inval_entry(parent, &name) {
+            debug!("Could not invalidate entry {:?} in {}: {}", name, parent, e);
+        }
+    }
+}
@@ -32,45 +32,3 @@ mod reply {
         }};
     }
 }
-
-mod send_requests {
-    #[macro_export]
-    macro_rules! send_request {
-        ($func:expr ) => {{
-            let handle = ::tokio::runtime::Handle::current();
-            handle.block_on($func)
-        }};
-    }
-    #[macro_export]
-    macro_rules! send_request_handled_internal {
-        ($func:expr ,$reply:ident, $error_code:expr, $error_msg:expr, $($arg:tt)*) => {{
-            let x = send_request!($func);
-            reply_error_e!(x, $reply, $error_code, $error_msg, $($arg)*)
-        }};
-    }
-    #[macro_export]
-    macro_rules! send_request_handled {
-        ($func:expr ,$reply:ident) => {
-            send_request_handled!($func, $reply, "")
-        };
-        ($func:expr ,$reply:ident, $error_msg:expr) => {
-            send_request_handled_internal!(
-                $func,
-                $reply,
-                ::libc::EREMOTEIO,
-                "Failed send request: {}",
-                $error_msg
-            )
-        };
-    }
-
-    #[macro_export]
-    macro_rules! send_request_handled_consuming {
-        ($func:expr ,$reply:ident) => {
-            send_request_handled_consuming!($func, $reply, "");
-        };
-        ($func:expr ,$reply:ident, $error_msg:expr) => {
-            let _ = send_request_handled!($func, $reply, $error_msg);
-        };
-    }
-}
@@ -0,0 +1,33 @@
+use super::Inode;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub(crate) type SharedOpenFiles = Arc<Mutex<OpenFiles>>;
+
+/// The files the kernel has open right now.
+///
+/// The mount is busy while any of them are open, so it is not unmounted gracefully.
+#[derive(Debug, Default)]
+pub(crate) struct OpenFiles {
+    /// The inode of every file handle
+    handles: HashMap<u64, Inode>,
+    next_fh: u64,
+}
+impl OpenFiles {
+    /// Returns the file handle for the kernel
+    pub(crate) fn open(&mut self, ino: Inode) -> u64 {
+        self.next_fh += 1;
+        let fh = self.next_fh;
+        self.handles.insert(fh, ino);
+        fh
+    }
+    pub(crate) fn release(&mut self, fh: u64) {
+        self.handles.remove(&fh);
+    }
+    pub(crate) fn count_for_ino(&self, ino: Inode) -> usize {
+        self.handles.values().filter(|file| **file == ino).count()
+    }
+    pub(crate) fn open_count(&self) -> usize {
+        self.handles.len()
+    }
+}
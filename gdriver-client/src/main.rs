@@ -1,8 +1,10 @@
-use fuser::{MountOption, Session, SessionUnmounter};
+use fuser::{MountOption, Session};
 use std::{error::Error, net::IpAddr, result::Result as StdResult};
-use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::mpsc::{channel, Receiver};
 
 use crate::filesystem::{Filesystem, ShutdownRequest};
+use gdriver_common::ipc::gdriver_service::GDriverServiceClient;
+use gdriver_common::ipc::version::{CAPABILITY_CLIENTS, CAPABILITY_EVENTS, CAPABILITY_FLUSH};
 use gdriver_common::{ipc::sample::*, prelude::*};
 use tarpc::context::Context;
 use tarpc::{client, tokio_serde::formats::Json};
@@ -16,15 +18,39 @@ async fn main() -> Result<()> {
     check_setup()?;
     // service::start().await?;
     let mount_options = &[MountOption::RW];
-    let (tx, rx) = channel(1);
-    let gdriver_client = service::create_client(CONFIGURATION.ip, CONFIGURATION.port).await?;
+    let (tx, rx) = channel(2);
+    let gdriver_client = service::create_client(&CONFIGURATION.current()).await?;
+    let backend_version = service::handshake(&gdriver_client).await?;
+    if backend_version.supports(CAPABILITY_CLIENTS) {
+        let id = gdriver_client
+            .set_client_name(Context::current(), String::from("mount"))
+            .await?;
+        info!("Connected to the backend as client {id}");
+        gdriver_client
+            .set_event_subscription(Context::current(), filesystem::invalidation::subscription())
+            .await?;
+    }
+    // before mounting, the kernel blocks on init of the filesystem and init can not await this.
+    // The backend skips updates in offline mode, so this has to happen before switching to it.
+    gdriver_client.update_changes(Context::current()).await??;
     gdriver_client
         .set_offline_mode(Context::current(), true) //TODO make this configurable
         .await??;
-    let f = Filesystem::new(gdriver_client, rx);
-    mount(f, &"/var/tmp/gdriver2_mount", mount_options, tx)
-        .await?
-        .await?;
+    let events_supported = backend_version.supports(CAPABILITY_EVENTS);
+    let flush_supported = backend_version.supports(CAPABILITY_FLUSH);
+    let f = Filesystem::new(gdriver_client.clone(), backend_version);
+    tokio::spawn(shutdown::forward_signals(tx));
+    mount(
+        f,
+        gdriver_client,
+        events_supported,
+        flush_supported,
+        "/var/tmp/gdriver2_mount",
+        mount_options,
+        rx,
+    )
+    .await?
+    .await??;
     Ok(())
 }
 
@@ -42,18 +68,35 @@ mod sample;
 
 mod filesystem;
 mod service;
+mod shutdown;
 
 async fn mount(
     fs: Filesystem,
+    gdriver_client: GDriverServiceClient,
+    events_supported: bool,
+    flush_supported: bool,
     mountpoint: &str,
     options: &[MountOption],
-    sender: Sender<ShutdownRequest>,
-) -> Result<JoinHandle<()>> {
+    receiver: Receiver<ShutdownRequest>,
+) -> Result<JoinHandle<Result<()>>> {
+    let inodes = fs.inode_table();
+    let open_files = fs.open_files();
     let mut session = Session::new(fs, mountpoint.as_ref(), options)?;
     let session_ender = session.unmount_callable();
-    let end_program_signal_handle = tokio::spawn(async move {
-        let _ = end_program_signal_awaiter(sender, session_ender).await;
-    });
+    if events_supported {
+        tokio::spawn(filesystem::invalidation::run_invalidation(
+            gdriver_client.clone(),
+            inodes,
+            session.notifier(),
+        ));
+    }
+    let shutdown_handle = tokio::spawn(shutdown::handle_shutdown_requests(
+        receiver,
+        flush_supported.then_some(gdriver_client),
+        open_files,
+        session_ender,
+        mountpoint.to_string(),
+    ));
     debug!("Mounting fuse filesystem");
     tokio::task::spawn_blocking(move || {
         let _ = session.run();
@@ -61,24 +104,5 @@ async fn mount(
     .await?;
     debug!("Stopped with mounting");
     // Ok(session_ender)
-    Ok(end_program_signal_handle)
-}
-
-async fn end_program_signal_awaiter(
-    sender: Sender<ShutdownRequest>,
-    mut session_unmounter: SessionUnmounter,
-) -> Result<()> {
-    info!("Waiting for Ctrl-C");
-    println!("Waiting for Ctrl-C");
-    tokio::signal::ctrl_c()
-        .await
-        .expect("failed to listen for ctrl_c event");
-    println!(); //to not have ^C on the same line as the next log if it is directly in a console
-    info!("got signal to end program");
-    sender.send(ShutdownRequest::Gracefully).await?;
-    info!("sent stop command to file uploader");
-    info!("unmounting...");
-    session_unmounter.unmount()?;
-    info!("unmounted");
-    Ok(())
+    Ok(shutdown_handle)
 }
@@ -5,7 +5,7 @@ pub async fn start() -> Result<()> {
     println!("Hello, world!");
 
     let name = "test1".to_string();
-    let config = &CONFIGURATION;
+    let config = CONFIGURATION.current();
     let client: WorldClient = create_client(config.ip, config.port).await?;
 
     let hello = client
@@ -1,71 +1,124 @@
+use std::sync::Arc;
 use std::time;
 
-use gdriver_common::ipc::gdriver_service::{BackendActionRequest, GDriverServiceClient};
+use gdriver_common::config::{TlsMode, TransportKind};
+use gdriver_common::ipc::gdriver_service::GDriverServiceClient;
+use gdriver_common::ipc::transport::{
+    codec, framed, load_certs, load_key, send_token, socket_path, tls_dir, CA_CERT_FILE,
+    CLIENT_CERT_FILE, CLIENT_KEY_FILE,
+};
+use gdriver_common::ipc::version::VersionInfo;
+use tarpc::tokio_util::either::Either;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
 
 use super::*;
 
 pub async fn start() -> Result<()> {
     println!("Hello, world!");
-    let config = &CONFIGURATION;
-    println!("Config: {:?}", **config);
-    let client: GDriverServiceClient = create_client(config.ip, config.port).await?;
-    run_long_stuff_test(&client).await;
+    let config = CONFIGURATION.current();
+    println!("Config: {:?}", config);
+    let client: GDriverServiceClient = create_client(&config).await?;
+    handshake(&client).await?;
+    ping(&client).await?;
+    let status = client.status(tarpc::context::current()).await?;
+    info!("Backend status: {:?}", status);
     Ok(())
 }
 
 async fn ping(client: &GDriverServiceClient) -> Result<()> {
-    let hello = client
-        .do_something2(tarpc::context::current(), BackendActionRequest::Ping)
-        .await;
-    match hello {
-        Ok(hello) => info!("Yay: {:?}", hello),
-        Err(e) => {
-            error!(":( {:?}", (e));
-            dbg!(&e);
-            return Err(Box::new(e));
-        }
-    }
+    let start = time::Instant::now();
+    let version = client.ping(tarpc::context::current()).await?;
+    info!(
+        "Backend {} answered after {:?}",
+        version.crate_version,
+        start.elapsed()
+    );
     Ok(())
 }
-#[allow(unused)]
-async fn run_long_stuff_test(client: &GDriverServiceClient) {
-    let start = time::SystemTime::now();
-    let hello = client
-        .do_something2(tarpc::context::current(), BackendActionRequest::RunLong)
-        .await;
 
-    let seconds = (time::SystemTime::now().duration_since(start))
-        .unwrap()
-        .as_secs();
-
-    match hello {
-        Ok(hello) => info!("Run Long returned after {} seconds: {:?}", seconds, hello),
-        Err(e) => error!(":( {:?}", (e)),
-    }
-    let start = time::SystemTime::now();
-    let hello = client
-        .do_something2(tarpc::context::current(), BackendActionRequest::StartLong)
-        .await;
-    let seconds = (time::SystemTime::now().duration_since(start))
-        .unwrap()
-        .as_secs();
-
-    match hello {
-        Ok(hello) => info!("Start Long returned after {} seconds: {:?}", seconds, hello),
-        Err(e) => info!(":( {:?}", (e)),
-    }
+pub async fn create_client(config: &Configuration) -> Result<GDriverServiceClient> {
+    let service = match config.transport {
+        TransportKind::Unix => {
+            let path = socket_path();
+            let transport = tarpc::serde_transport::unix::connect(&path, codec)
+                .await
+                .map_err(|e| {
+                    info!(
+                        "Could not connect to backend at {}. Please make sure it is started before this app.",
+                        path.display()
+                    );
+                    e
+                })?;
+            GDriverServiceClient::new(client::Config::default(), transport)
+        }
+        TransportKind::Tcp => {
+            let server_addr = (config.ip, config.port);
+            let stream = TcpStream::connect(&server_addr).await.map_err(|e| {
+                info!("Could not connect to backend. Please make sure it is started before this app.");
+                e
+            })?;
+            let mut connection = match tls_connector(config)? {
+                None => Either::Left(stream),
+                Some(connector) => {
+                    let server_name = ServerName::try_from(config.tls_server_name.clone())?;
+                    Either::Right(connector.connect(server_name, stream).await?)
+                }
+            };
+            send_token(&mut connection, config.auth_token.as_deref()).await?;
+            GDriverServiceClient::new(client::Config::default(), framed(connection))
+        }
+    };
+    Ok(service.spawn())
 }
 
-pub async fn create_client(ip: IpAddr, port: u16) -> Result<GDriverServiceClient> {
-    let server_addr = (ip, port);
-    let transport = tarpc::serde_transport::tcp::connect(&server_addr, Json::default)
+/// Exchanges versions with the backend and fails with a message the user can act on if they
+/// can not work together.
+///
+/// Returns the version of the backend, so features it does not support can be turned off.
+pub async fn handshake(client: &GDriverServiceClient) -> Result<VersionInfo> {
+    let own = VersionInfo::current();
+    let backend = client
+        .handshake(tarpc::context::current(), own.clone())
         .await
         .map_err(|e| {
-            info!("Could not connect to backend. Please make sure it is started before this app.");
-            e
+            error!("Handshake with the backend failed: {e}");
+            "Could not exchange versions with the backend. It might be too old or use another \
+             ipc_format, please make sure both are the same version"
         })?;
-    let service = GDriverServiceClient::new(client::Config::default(), transport);
-    let client = service.spawn();
-    let _ = ping(&client).await;
-    Ok(client)
+    info!(
+        "Backend has version {} (protocol {})",
+        backend.crate_version, backend.protocol_version
+    );
+    own.check_compatible(&backend)?;
+    for capability in &own.capabilities {
+        if !backend.supports(capability) {
+            warn!("The backend does not support {capability}, it is turned off");
+        }
+    }
+    Ok(backend)
+}
+
+/// Trusts only the certificate authority of the backend, [None] if [Configuration::tls] is off
+fn tls_connector(config: &Configuration) -> Result<Option<TlsConnector>> {
+    if config.tls == TlsMode::Off {
+        return Ok(None);
+    }
+    let dir = tls_dir();
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&dir.join(CA_CERT_FILE))? {
+        roots.add(cert)?;
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let client_config = if config.tls == TlsMode::Mutual {
+        builder.with_client_auth_cert(
+            load_certs(&dir.join(CLIENT_CERT_FILE))?,
+            load_key(&dir.join(CLIENT_KEY_FILE))?,
+        )?
+    } else {
+        builder.with_no_client_auth()
+    };
+    Ok(Some(TlsConnector::from(Arc::new(client_config))))
 }
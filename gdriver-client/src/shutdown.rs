@@ -0,0 +1,117 @@
+use crate::filesystem::open_files::SharedOpenFiles;
+use crate::filesystem::ShutdownRequest;
+use crate::prelude::*;
+use fuser::SessionUnmounter;
+use gdriver_common::ipc::gdriver_service::GDriverServiceClient;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tarpc::context;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+type Result<T> = StdResult<T, Box<dyn Error>>;
+
+/// How often a graceful shutdown checks again whether the filesystem is still busy
+const BUSY_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How long the backend gets to finish its uploads before it is asked again
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Asks for a graceful shutdown on the first SIGINT or SIGTERM and forces it on every one
+/// after that
+pub(crate) async fn forward_signals(sender: Sender<ShutdownRequest>) -> Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    info!("Waiting for Ctrl-C");
+    let mut request = ShutdownRequest::Gracefully;
+    loop {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        println!(); //to not have ^C on the same line as the next log if it is directly in a console
+        info!("got signal to end program");
+        sender.send(request).await?;
+        request = ShutdownRequest::Force;
+    }
+}
+
+/// Unmounts the filesystem once a shutdown is requested.
+///
+/// A graceful shutdown waits until no file is open anymore and the backend confirmed that its
+/// uploads are done, if it can (`backend` is [None] otherwise). A forced one unmounts right
+/// away, the kernel still refuses that while a process uses the filesystem, so it can be
+/// requested again.
+pub(crate) async fn handle_shutdown_requests(
+    mut receiver: Receiver<ShutdownRequest>,
+    backend: Option<GDriverServiceClient>,
+    open_files: SharedOpenFiles,
+    mut session_unmounter: SessionUnmounter,
+    mountpoint: String,
+) -> Result<()> {
+    let Some(mut request) = receiver.recv().await else {
+        return Ok(());
+    };
+    loop {
+        let mut last_reason = None;
+        while request == ShutdownRequest::Gracefully {
+            // waiting for the backend can take a while, another request must not wait for it
+            let reason = tokio::select! {
+                reason = busy_reason(&open_files, backend.as_ref()) => reason,
+                next = receiver.recv() => match next {
+                    Some(next) => {
+                        request = next;
+                        continue;
+                    }
+                    None => return Ok(()),
+                },
+            };
+            let Some(reason) = reason else {
+                break;
+            };
+            if last_reason.as_ref() != Some(&reason) {
+                warn!("Not unmounting yet, {reason}. Press Ctrl-C again to unmount anyway.");
+                last_reason = Some(reason);
+            }
+            tokio::select! {
+                next = receiver.recv() => match next {
+                    Some(next) => request = next,
+                    None => return Ok(()),
+                },
+                _ = tokio::time::sleep(BUSY_RETRY_DELAY) => {}
+            }
+        }
+        info!("unmounting...");
+        match session_unmounter.unmount() {
+            Ok(()) => break,
+            Err(e) => warn!(
+                "Could not unmount {mountpoint}: {e}. Stop the processes using it and press \
+                Ctrl-C again."
+            ),
+        }
+        request = match receiver.recv().await {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+    }
+    info!("unmounted");
+    Ok(())
+}
+
+/// Returns why the filesystem can not be unmounted gracefully yet
+async fn busy_reason(
+    open_files: &SharedOpenFiles,
+    backend: Option<&GDriverServiceClient>,
+) -> Option<String> {
+    let open_count = open_files.lock().unwrap().open_count();
+    if open_count > 0 {
+        return Some(format!("{open_count} files are still open"));
+    }
+    let mut context = context::current();
+    // the backend answers once the uploads are done, which can take a while
+    context.deadline = Instant::now() + FLUSH_TIMEOUT;
+    match backend?.flush_uploads(context).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(format!("the backend could not save the changes: {e}")),
+        Err(e) => Some(format!("the backend did not confirm the save: {e}")),
+    }
+}
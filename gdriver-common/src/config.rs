@@ -1,20 +1,134 @@
 use super::*;
 use crate::prelude::*;
+use arc_swap::ArcSwap;
 use confique::{Config, Partial};
+use std::fmt::{Debug, Formatter};
 use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
 const IP_DEFAULT: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
 #[derive(Debug, Serialize, Deserialize, Config, Clone)]
 pub struct Configuration {
+    /// How the client talks to the backend
+    #[config(default = "unix")]
+    pub transport: TransportKind,
+    /// Replaces the default socket in the runtime directory when [Configuration::transport] is
+    /// [TransportKind::Unix]
+    pub socket_path: Option<std::path::PathBuf>,
     #[config(default = 33333)]
     pub port: u16,
+    /// Whether connections over [TransportKind::Tcp] are encrypted, required to listen beyond
+    /// localhost
+    #[config(default = "off")]
+    pub tls: TlsMode,
+    /// Replaces the default certificate directory in the data directory. The backend creates a
+    /// certificate authority and certificates signed by it there on first run; clients need
+    /// `ca.pem`, and with [TlsMode::Mutual] also `client.pem` and `client.key`.
+    pub tls_dir: Option<std::path::PathBuf>,
+    /// The name in the certificate of the backend, clients have to connect with this name
+    #[config(default = "localhost")]
+    pub tls_server_name: String,
+    /// A secret clients have to send before anything else over [TransportKind::Tcp]
+    pub auth_token: Option<String>,
+    /// How many clients can use the backend at the same time, further clients wait
+    #[config(default = 16)]
+    pub max_clients: usize,
+    /// How many of [Configuration::max_clients] can come from the same process (Unix socket) or
+    /// the same IP (TCP)
+    #[config(default = 8)]
+    pub max_clients_per_peer: u32,
+    /// How many requests of one client the backend works on at the same time
+    #[config(default = 64)]
+    pub max_requests_per_client: usize,
+    /// How long the backend waits for downloads in progress when it shuts down
+    #[config(default = 30)]
+    pub shutdown_timeout_secs: u64,
+    /// How messages between the client and the backend are encoded, both have to use the same
+    #[config(default = "bincode")]
+    pub ipc_format: IpcFormat,
     //    #[config(default = Test)]
     pub ip: std::net::IpAddr,
+    /// Files up to this size (in bytes) are downloaded in the background when their folder is
+    /// listed. 0 disables downloading content ahead of time.
+    #[config(default = 0)]
+    pub prefetch_max_file_size: u64,
+    /// How many files are downloaded at the same time while prefetching
+    #[config(default = 4)]
+    pub prefetch_concurrency: usize,
+    /// How many requests per second are sent to the Google Drive API at most
+    #[config(default = 10)]
+    pub api_requests_per_second: u32,
+    /// How often a request to the Google Drive API is retried when it failed temporarily
+    #[config(default = 5)]
+    pub api_max_retries: u32,
+    /// Replaces `https://www.googleapis.com/` as the root of all Google Drive API calls,
+    /// used to talk to a local stand-in of the API
+    pub drive_api_root_url: Option<String>,
+    /// Sends the requests to the Google Drive API without authentication, only useful together
+    /// with [Configuration::drive_api_root_url]
+    #[config(default = false)]
+    pub drive_api_skip_auth: bool,
+    /// Where the files are synced from
+    #[config(default = "google_drive")]
+    pub remote: RemoteKind,
+    /// The directory that is synced when [Configuration::remote] is [RemoteKind::Local]
+    pub local_remote_path: Option<std::path::PathBuf>,
+}
+/// The ways the client and the backend can be connected
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransportKind {
+    /// A Unix domain socket that only the user running the backend can connect to
+    Unix,
+    /// [Configuration::ip] and [Configuration::port], any local user can connect
+    Tcp,
+}
+/// The encodings messages between the client and the backend can use
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpcFormat {
+    /// Readable, useful for debugging
+    Json,
+    /// Compact and fast, for large directory listings
+    Bincode,
+}
+/// How connections over [TransportKind::Tcp] are secured
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsMode {
+    /// Plain text, only use this on localhost
+    Off,
+    /// The backend proves who it is with a certificate signed by its own authority
+    Server,
+    /// Like [TlsMode::Server], and clients also need a certificate signed by that authority
+    Mutual,
+}
+/// The storages the backend can sync with
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RemoteKind {
+    GoogleDrive,
+    /// A directory on this machine, like a mounted network share
+    Local,
 }
 pub fn load_config() -> Result<Configuration> {
-    Ok(add_default_locations(Config::builder()).load()?)
+    validate(add_default_locations(Config::builder()).load()?)
 }
 pub fn load_config_with_path(path: &Path) -> Result<Configuration> {
-    Ok(add_default_locations(Config::builder().file(path)).load()?)
+    validate(add_default_locations(Config::builder().file(path)).load()?)
+}
+/// Rejects the values the type allows but the backend can not work with
+fn validate(config: Configuration) -> Result<Configuration> {
+    let limits = [
+        ("max_clients", config.max_clients),
+        ("max_clients_per_peer", config.max_clients_per_peer as usize),
+        ("max_requests_per_client", config.max_requests_per_client),
+    ];
+    for (name, limit) in limits {
+        if limit == 0 {
+            return Err(format!("{name} has to be at least 1, no client could be served").into());
+        }
+    }
+    Ok(config)
 }
 fn add_default_locations(
     builder: confique::Builder<Configuration>,
@@ -27,7 +141,51 @@ fn add_default_locations(
     builder.env().file("config.toml").preloaded(prebuilt)
 }
 
+/// The configuration that is in use, it can be replaced while the program runs with
+/// [ReloadableConfiguration::reload].
+///
+/// [ReloadableConfiguration::current] returns the configuration at the time of the call, a
+/// reload does not change it. The previous configuration is freed once nothing uses it anymore.
+pub struct ReloadableConfiguration {
+    current: ArcSwap<Configuration>,
+}
+impl ReloadableConfiguration {
+    fn new(configuration: Configuration) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(configuration),
+        }
+    }
+    pub fn current(&self) -> Arc<Configuration> {
+        self.current.load_full()
+    }
+    /// Loads the configuration again and returns the names of the settings that changed
+    pub fn reload(&self) -> Result<Vec<String>> {
+        let new = load_config()?;
+        let changed = changed_settings(&self.current.load(), &new)?;
+        self.current.store(Arc::new(new));
+        Ok(changed)
+    }
+}
+impl Debug for ReloadableConfiguration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&**self.current.load(), f)
+    }
+}
+fn changed_settings(old: &Configuration, new: &Configuration) -> Result<Vec<String>> {
+    let old = serde_json::to_value(old)?;
+    let new = serde_json::to_value(new)?;
+    let (Some(old), Some(new)) = (old.as_object(), new.as_object()) else {
+        return Err("The configuration is not a map".into());
+    };
+    Ok(new
+        .iter()
+        .filter(|(key, value)| old.get(*key) != Some(value))
+        .map(|(key, _)| key.clone())
+        .collect())
+}
+
 use lazy_static::lazy_static;
 lazy_static! {
-    pub static ref CONFIGURATION: Configuration = load_config().unwrap();
+    pub static ref CONFIGURATION: ReloadableConfiguration =
+        ReloadableConfiguration::new(load_config().unwrap());
 }
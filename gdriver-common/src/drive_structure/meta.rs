@@ -21,6 +21,9 @@ pub struct Metadata {
     pub kind: FileKind,
     pub permissions: u16,
     pub extra_attributes: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// How many folders contain the file, shown as the number of hard links
+    #[serde(default)]
+    pub parent_count: u32,
 }
 
 pub const PERMISSIONS_RWXRWXRWX: u16 = 0o777;
@@ -39,6 +42,7 @@ impl Metadata {
             kind: FileKind::Directory,
             permissions: PERMISSIONS_RWXRWXRWX,
             extra_attributes: Default::default(),
+            parent_count: 0,
         }
     }
 }
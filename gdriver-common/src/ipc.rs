@@ -1,4 +1,9 @@
 use serde::{Deserialize, Serialize};
+pub mod admin;
+pub mod clients;
+pub mod events;
 pub mod gdriver_service;
 pub mod gdriver_settings;
 pub mod sample;
+pub mod transport;
+pub mod version;
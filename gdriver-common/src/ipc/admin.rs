@@ -0,0 +1,19 @@
+//! What management tools can ask the backend about itself.
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A snapshot of what the backend is doing, returned by
+/// [GDriverService::status](super::gdriver_service::GDriverService::status)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BackendStatus {
+    pub offline_mode: bool,
+    /// The backend was asked to shut down and finishes the work it already started
+    pub shutting_down: bool,
+    /// Downloads that were started and are not finished yet
+    pub downloads_in_progress: usize,
+    /// Bytes used by downloaded and cached file contents
+    pub cache_size: u64,
+    /// When the changes of the remote were applied successfully the last time
+    pub last_sync: Option<DateTime<Utc>>,
+    pub connected_clients: usize,
+}
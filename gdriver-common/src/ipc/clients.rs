@@ -0,0 +1,17 @@
+//! Many clients can use the backend at the same time, like the mount and management tools.
+use crate::ipc::events::EventSubscription;
+use serde::{Deserialize, Serialize};
+
+/// Identifies one connection to the backend, as long as the backend runs
+pub type ClientId = u64;
+
+/// What the backend knows about a connected client
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClientInfo {
+    pub id: ClientId,
+    /// The name the client gave itself, if any
+    pub name: Option<String>,
+    /// Where the client connected from
+    pub peer: String,
+    pub subscription: EventSubscription,
+}
@@ -0,0 +1,90 @@
+use crate::drive_structure::drive_id::DriveId;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Can be passed to [GDriverService::next_events](super::gdriver_service::GDriverService::next_events)
+/// to only get the current event number
+pub const LATEST_EVENT: u64 = u64::MAX;
+/// How long the backend waits for new events before answering with an empty batch
+pub const NEXT_EVENTS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Something that happened in the backend, that clients might want to react to
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub enum BackendEvent {
+    /// The metadata, the content or the children of the file changed
+    Changed(DriveId),
+    /// The file does not exist anymore
+    Removed(DriveId),
+    /// Some more bytes of a local change were uploaded
+    UploadProgress {
+        id: DriveId,
+        uploaded: u64,
+        total: u64,
+    },
+    /// The file was changed locally and remotely, so the local change could not be applied
+    Conflict(DriveId),
+    OfflineModeChanged(bool),
+}
+impl BackendEvent {
+    pub fn kind(&self) -> BackendEventKind {
+        match self {
+            BackendEvent::Changed(_) => BackendEventKind::Changed,
+            BackendEvent::Removed(_) => BackendEventKind::Removed,
+            BackendEvent::UploadProgress { .. } => BackendEventKind::UploadProgress,
+            BackendEvent::Conflict(_) => BackendEventKind::Conflict,
+            BackendEvent::OfflineModeChanged(_) => BackendEventKind::OfflineModeChanged,
+        }
+    }
+    /// The file the event is about, [None] for events about the whole backend
+    pub fn id(&self) -> Option<&DriveId> {
+        match self {
+            BackendEvent::Changed(id)
+            | BackendEvent::Removed(id)
+            | BackendEvent::UploadProgress { id, .. }
+            | BackendEvent::Conflict(id) => Some(id),
+            BackendEvent::OfflineModeChanged(_) => None,
+        }
+    }
+}
+/// The variants of [BackendEvent] without their data, to subscribe to them
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BackendEventKind {
+    Changed,
+    Removed,
+    UploadProgress,
+    Conflict,
+    OfflineModeChanged,
+}
+
+/// The events a client gets from
+/// [GDriverService::next_events](super::gdriver_service::GDriverService::next_events), every
+/// client has its own
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct EventSubscription {
+    /// Only events of these kinds, all kinds if empty
+    pub kinds: Vec<BackendEventKind>,
+    /// Only events about these files, events about the whole backend are always included.
+    /// All files if empty.
+    pub ids: Vec<DriveId>,
+}
+impl EventSubscription {
+    pub fn matches(&self, event: &BackendEvent) -> bool {
+        let kind_matches = self.kinds.is_empty() || self.kinds.contains(&event.kind());
+        let id_matches = match event.id() {
+            Some(id) => self.ids.is_empty() || self.ids.contains(id),
+            None => true,
+        };
+        kind_matches && id_matches
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct EventBatch {
+    /// The event number to pass on the next call to get the events after this batch
+    pub next: u64,
+    /// All events since the requested event number, oldest first
+    pub events: Vec<BackendEvent>,
+    /// The backend does not remember all events since the requested event number anymore,
+    /// so some events are missing
+    pub incomplete: bool,
+}
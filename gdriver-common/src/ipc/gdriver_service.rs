@@ -1,21 +1,41 @@
 use crate::drive_structure::drive_id::DriveId;
 use crate::drive_structure::meta::FileKind;
+use crate::ipc::admin::BackendStatus;
+use crate::ipc::clients::{ClientId, ClientInfo};
+use crate::ipc::events::{EventBatch, EventSubscription};
 use crate::ipc::gdriver_settings::GDriverSettings;
+use crate::ipc::version::VersionInfo;
 use crate::prelude::*;
 use errors::*;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::ffi::OsString;
 use std::path::PathBuf;
 
 #[tarpc::service]
 pub trait GDriverService {
+    /// Exchanges the versions of the client and the backend, the client calls this first.
+    ///
+    /// This has to stay the first method, so its request keeps the same encoding in every
+    /// version.
+    async fn handshake(client: VersionInfo) -> VersionInfo;
     async fn set_offline_mode(offline_mode: bool) -> StdResult<(), GDriverServiceError>;
     async fn get_file_by_name(
         name: OsString,
         parent: DriveId,
     ) -> StdResult<DriveId, GetFileByPathError>;
     async fn get_file_by_path(path: PathBuf) -> StdResult<DriveId, GetFileByPathError>;
+    /// Returns every absolute path below the mount point the file can be reached at
+    async fn get_paths_for_file(id: DriveId) -> StdResult<Vec<PathBuf>, GetPathsError>;
+    /// Adds the folder as another parent of the file, which shows up as a hard link.
+    ///
+    /// A file has the same name in all of its parents, so `name` has to be its current name.
+    async fn add_parent_to_file(
+        id: DriveId,
+        parent: DriveId,
+        name: OsString,
+    ) -> StdResult<(), AddParentError>;
     async fn write_local_change(id: DriveId) -> StdResult<(), WriteLocalChangeError>;
     async fn get_metadata_for_file(id: DriveId) -> StdResult<(), GetMetadataError>;
     async fn download_content_for_file(id: DriveId) -> StdResult<(), GetContentError>;
@@ -36,15 +56,47 @@ pub trait GDriverService {
     /// Returns true if the file was had remote changes and was updated
     async fn update_changes_for_file(id: DriveId) -> StdResult<bool, UpdateChangesError>;
     async fn update_changes() -> StdResult<(), UpdateChangesError>;
-    async fn do_something2(req: BackendActionRequest) -> StdResult<String, BackendActionError>;
-}
-#[derive(Debug, Serialize, Deserialize)]
-pub enum BackendActionRequest {
-    ShutdownGracefully,
-    UpdateChanges,
-    Ping,
-    RunLong,
-    StartLong,
+    /// Waits until there are events after the event number `since` or the timeout ran out.
+    ///
+    /// Pass [LATEST_EVENT](crate::ipc::events::LATEST_EVENT) to get the current event number without waiting.
+    async fn next_events(since: u64) -> StdResult<EventBatch, NextEventsError>;
+    /// Names this connection in the logs of the backend and in
+    /// [list_clients](GDriverService::list_clients)
+    async fn set_client_name(name: String) -> ClientId;
+    /// Replaces which events this connection gets from [next_events](GDriverService::next_events)
+    async fn set_event_subscription(subscription: EventSubscription);
+    /// All clients that are connected right now
+    async fn list_clients() -> Vec<ClientInfo>;
+    /// Answers right away, to check that the backend is reachable
+    async fn ping() -> VersionInfo;
+    async fn status() -> BackendStatus;
+    /// Stops accepting requests, finishes the work that is in progress and ends the backend.
+    ///
+    /// Answers once the downloads in progress are done or the shutdown timeout passed.
+    async fn shutdown() -> StdResult<(), ShutdownError>;
+    /// Forgets what is known about the remote and lists all files again, like on the first start
+    async fn force_resync() -> StdResult<(), ResyncError>;
+    /// Reads the configuration again and returns the names of the settings that changed.
+    ///
+    /// Settings that are only used on start, like the transport, need a restart to take effect.
+    async fn reload_config() -> StdResult<Vec<String>, ReloadConfigError>;
+    /// Moves the files to the trash with as few requests to the remote as possible and
+    /// returns the error of every file that could not be moved there
+    async fn mark_files_as_deleted(
+        ids: Vec<DriveId>,
+    ) -> StdResult<HashMap<DriveId, MarkFileAsDeletedError>, MarkFileAsDeletedError>;
+    /// Moves the files from one folder to another with as few requests to the remote as
+    /// possible and returns the error of every file that could not be moved
+    async fn move_files(
+        ids: Vec<DriveId>,
+        from: DriveId,
+        to: DriveId,
+    ) -> StdResult<HashMap<DriveId, MoveFilesError>, MoveFilesError>;
+    /// Waits until the uploads in progress are done and writes the state of the backend to
+    /// disk, so a client can unmount without losing changes.
+    ///
+    /// Answers once everything is saved, uploads that are started meanwhile wait for it.
+    async fn flush_uploads() -> StdResult<(), FlushError>;
 }
 
 lazy_static! {
@@ -58,10 +110,20 @@ pub mod errors {
     pub enum GDriverServiceError {
         #[error("Error getting the settings: {0}")]
         GetSettings(#[from] GetSettingsError),
-        #[error("Backend Action had an Error: {0}")]
-        BackendAction(#[from] BackendActionError),
+        #[error("Could not shut down: {0}")]
+        Shutdown(#[from] ShutdownError),
+        #[error("Could not flush the uploads: {0}")]
+        Flush(#[from] FlushError),
+        #[error("Could not resync: {0}")]
+        Resync(#[from] ResyncError),
+        #[error("Could not reload the configuration: {0}")]
+        ReloadConfig(#[from] ReloadConfigError),
         #[error("Could not get File by Path: {0}")]
         GetFileByPath(#[from] GetFileByPathError),
+        #[error("Could not get the paths of the file: {0}")]
+        GetPaths(#[from] GetPathsError),
+        #[error("Could not add the parent: {0}")]
+        AddParent(#[from] AddParentError),
         #[error("Could not update changes: {0}")]
         UpdateChanges(#[from] UpdateChangesError),
         #[error("Could not write local change: {0}")]
@@ -75,10 +137,14 @@ pub mod errors {
         GetFileList(#[from] GetFileListError),
         #[error("Could not mark file as deleted: {0}")]
         MarkFileAsDeleted(#[from] MarkFileAsDeletedError),
+        #[error("Could not move the files: {0}")]
+        MoveFiles(#[from] MoveFilesError),
         #[error("Could not mark file for keeping: {0}")]
         MarkFileForKeepingLocal(#[from] MarkFileForKeepingLocalError),
         #[error("Could not unmark file for keeping: {0}")]
         UnmarkFileForKeepingLocal(#[from] UnmarkFileForKeepingLocalError),
+        #[error("Could not get the next events: {0}")]
+        NextEvents(#[from] NextEventsError),
     }
 
     #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
@@ -87,11 +153,29 @@ pub mod errors {
         Unknown,
     }
     #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
-    pub enum BackendActionError {
-        #[error("Unknown Error")]
-        Unknown,
-        #[error("Could not complete Error")]
-        CouldNotComplete,
+    pub enum ShutdownError {
+        #[error("The backend is already shutting down")]
+        AlreadyShuttingDown,
+        /// The backend still shuts down, without the work that did not finish in time
+        #[error("The work in progress did not finish before the shutdown timeout")]
+        Unfinished,
+    }
+    #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
+    pub enum FlushError {
+        #[error("Could not write the state to disk: {0}")]
+        Store(String),
+    }
+    #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
+    pub enum ResyncError {
+        #[error("Can not resync in offline mode")]
+        Offline,
+        #[error("Error while talking to the remote: {0}")]
+        Remote(String),
+    }
+    #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
+    pub enum ReloadConfigError {
+        #[error("The configuration is invalid: {0}")]
+        Invalid(String),
     }
 
     #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
@@ -108,6 +192,32 @@ pub mod errors {
         PathResolve(#[from] PathResolveError),
     }
 
+    #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
+    pub enum GetPathsError {
+        #[error("Other")]
+        Other,
+        #[error("The file is not known")]
+        NotFound,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
+    pub enum AddParentError {
+        #[error("Other")]
+        Other,
+        #[error("The file or the folder is not known")]
+        NotFound,
+        #[error("The parent is not a folder")]
+        NotADirectory,
+        #[error("Folders can not have more than one parent")]
+        IsADirectory,
+        #[error("The name differs from the name of the file")]
+        NameMismatch,
+        #[error("The folder already contains an entry with this name")]
+        AlreadyExists,
+        #[error("Could not update the file on the remote: {0}")]
+        Remote(String),
+    }
+
     #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
     pub enum UpdateChangesError {
         #[error("Other")]
@@ -118,6 +228,12 @@ pub mod errors {
         Running,
     }
 
+    #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
+    pub enum NextEventsError {
+        #[error("Other")]
+        Other,
+    }
+
     #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
     pub enum WriteLocalChangeError {
         #[error("Remote has changed")]
@@ -156,12 +272,28 @@ pub mod errors {
 
     #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
     pub enum MarkFileAsDeletedError {
+        #[error("The remote refused it: {0}")]
+        Remote(String),
+        #[error("Other")]
+        Other,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
+    pub enum MoveFilesError {
+        #[error("The folder was not found")]
+        NotFound,
+        #[error("The target is not a directory")]
+        NotADirectory,
+        #[error("The remote refused it: {0}")]
+        Remote(String),
         #[error("Other")]
         Other,
     }
 
     #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
     pub enum MarkFileForKeepingLocalError {
+        #[error("{} files could not be kept", .0.len())]
+        Incomplete(Vec<DriveId>),
         #[error("Other")]
         Other,
     }
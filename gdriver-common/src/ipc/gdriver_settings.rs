@@ -9,6 +9,7 @@ pub struct GDriverSettings {
     cache_path: PathBuf,
     downloaded_path: PathBuf,
     data_path: PathBuf,
+    runtime_path: PathBuf,
 }
 impl GDriverSettings {
     #[instrument(skip(self))]
@@ -41,13 +42,27 @@ impl GDriverSettings {
     pub fn data_path(&self) -> &Path {
         &self.data_path
     }
+    /// The directory for files that only exist while the backend runs, like its socket
+    pub fn runtime_path(&self) -> &Path {
+        &self.runtime_path
+    }
 
     pub fn get_changes_file_path(&self) -> PathBuf {
         self.data_path.join("changes.txt")
     }
+    /// The file the path resolver was stored in before it was moved into the database
     pub fn get_path_resolver_file_path(&self) -> PathBuf {
         self.data_path.join("relations.json")
     }
+    pub fn get_database_file_path(&self) -> PathBuf {
+        self.data_path.join("gdriver.sqlite")
+    }
+    pub fn get_socket_file_path(&self) -> PathBuf {
+        self.runtime_path.join("gdriver.sock")
+    }
+    pub fn get_tls_dir_path(&self) -> PathBuf {
+        self.data_path.join("tls")
+    }
 
     pub fn get_metadata_file_path(&self, id: &DriveId) -> PathBuf {
         self.metadata_path.join(id.as_ref()).with_extension("meta")
@@ -68,6 +83,11 @@ impl Default for GDriverSettings {
             downloaded_path: p.data_dir().join("downloads"),
             cache_path: p.cache_dir().to_path_buf(),
             data_path: p.data_dir().join("data"),
+            // without XDG_RUNTIME_DIR use a directory that is just as private
+            runtime_path: p
+                .runtime_dir()
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| p.data_dir().join("run")),
         }
     }
 }
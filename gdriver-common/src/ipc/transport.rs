@@ -0,0 +1,137 @@
+//! The parts of the connection between the client and the backend both sides have to agree on.
+use crate::ipc::gdriver_service::SETTINGS;
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tarpc::serde_transport::Transport;
+use tarpc::tokio_util::codec::LengthDelimitedCodec;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+mod format;
+pub use format::Codec;
+
+pub const CA_CERT_FILE: &str = "ca.pem";
+pub const SERVER_CERT_FILE: &str = "server.pem";
+pub const SERVER_KEY_FILE: &str = "server.key";
+pub const CLIENT_CERT_FILE: &str = "client.pem";
+pub const CLIENT_KEY_FILE: &str = "client.key";
+
+/// How long a client may take to send its auth token before the backend gives up on it
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longer tokens are rejected without reading them, so a client can't make the backend allocate
+/// a lot of memory
+const MAX_TOKEN_LENGTH: usize = 1024;
+const TOKEN_ACCEPTED: u8 = 1;
+const TOKEN_REJECTED: u8 = 0;
+
+/// The socket the backend listens on when the Unix socket transport is used
+pub fn socket_path() -> PathBuf {
+    CONFIGURATION
+        .current()
+        .socket_path
+        .clone()
+        .unwrap_or_else(|| SETTINGS.get_socket_file_path())
+}
+
+/// The directory with the certificate authority and the certificates signed by it
+pub fn tls_dir() -> PathBuf {
+    CONFIGURATION
+        .current()
+        .tls_dir
+        .clone()
+        .unwrap_or_else(|| SETTINGS.get_tls_dir_path())
+}
+
+/// Reads all certificates from a PEM file
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<StdResult<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("{} contains no certificate", path.display()).into());
+    }
+    Ok(certs)
+}
+
+/// Reads the first private key from a PEM file
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| format!("{} contains no private key", path.display()).into())
+}
+
+/// The codec for [Configuration::ipc_format]
+pub fn codec<Item, SinkItem>() -> Codec<Item, SinkItem> {
+    Codec::new(CONFIGURATION.current().ipc_format)
+}
+
+/// Wraps an established connection into the transport tarpc sends its messages over
+pub fn framed<S, Item, SinkItem>(stream: S) -> Transport<S, Item, SinkItem, Codec<Item, SinkItem>>
+where
+    S: AsyncRead + AsyncWrite,
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let framed = LengthDelimitedCodec::builder()
+        .max_frame_length(usize::MAX)
+        .new_framed(stream);
+    tarpc::serde_transport::new(framed, codec())
+}
+
+/// Sends the auth token as the first message of a connection and waits for the backend to accept
+/// it.
+///
+/// Without a token an empty one is sent, so the backend can tell the client apart from one that
+/// does not know about tokens.
+pub async fn send_token<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    token: Option<&str>,
+) -> Result<()> {
+    let token = token.unwrap_or_default().as_bytes();
+    stream.write_u32(token.len() as u32).await?;
+    stream.write_all(token).await?;
+    stream.flush().await?;
+    match stream.read_u8().await? {
+        TOKEN_ACCEPTED => Ok(()),
+        _ => Err("The backend rejected the auth token".into()),
+    }
+}
+
+/// Reads the token [send_token] sent and tells the client whether it matches `expected`.
+///
+/// Every token is accepted if `expected` is [None].
+pub async fn check_token<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    expected: Option<&str>,
+) -> Result<bool> {
+    let length = stream.read_u32().await? as usize;
+    if length > MAX_TOKEN_LENGTH {
+        stream.write_u8(TOKEN_REJECTED).await?;
+        return Ok(false);
+    }
+    let mut received = vec![0; length];
+    stream.read_exact(&mut received).await?;
+    let accepted = match expected {
+        None => true,
+        Some(expected) => constant_time_eq(expected.as_bytes(), &received),
+    };
+    let answer = if accepted {
+        TOKEN_ACCEPTED
+    } else {
+        TOKEN_REJECTED
+    };
+    stream.write_u8(answer).await?;
+    stream.flush().await?;
+    Ok(accepted)
+}
+
+/// Compares without returning early, so the time it takes tells nothing about the token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
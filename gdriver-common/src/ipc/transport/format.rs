@@ -0,0 +1,49 @@
+use crate::config::IpcFormat;
+use bytes::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use tarpc::tokio_serde::{Deserializer, Serializer};
+
+/// Encodes the messages of a connection in the [IpcFormat] both sides are configured with
+pub struct Codec<Item, SinkItem> {
+    format: IpcFormat,
+    ghost: PhantomData<fn(SinkItem) -> Item>,
+}
+impl<Item, SinkItem> Codec<Item, SinkItem> {
+    pub fn new(format: IpcFormat) -> Self {
+        Self {
+            format,
+            ghost: PhantomData,
+        }
+    }
+}
+impl<Item, SinkItem: Serialize> Serializer<SinkItem> for Codec<Item, SinkItem> {
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> Result<Bytes, Self::Error> {
+        let bytes = match self.format {
+            IpcFormat::Json => serde_json::to_vec(item)?,
+            IpcFormat::Bincode => bincode::serialize(item).map_err(invalid_data)?,
+        };
+        Ok(bytes.into())
+    }
+}
+impl<Item, SinkItem> Deserializer<Item> for Codec<Item, SinkItem>
+where
+    Item: for<'de> Deserialize<'de>,
+{
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> Result<Item, Self::Error> {
+        match self.format {
+            IpcFormat::Json => Ok(serde_json::from_slice(src)?),
+            IpcFormat::Bincode => bincode::deserialize(src).map_err(invalid_data),
+        }
+    }
+}
+
+fn invalid_data(e: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
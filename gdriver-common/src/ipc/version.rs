@@ -0,0 +1,90 @@
+//! What the client and the backend tell each other in
+//! [GDriverService::handshake](super::gdriver_service::GDriverService::handshake), so they can be
+//! upgraded independently.
+use serde::{Deserialize, Serialize};
+
+/// Increased whenever [GDriverService](super::gdriver_service::GDriverService) changes in a way
+/// older clients or backends can not handle
+pub const PROTOCOL_VERSION: u32 = 3;
+/// The oldest protocol version of the other side this build can still talk to
+pub const MIN_COMPATIBLE_PROTOCOL_VERSION: u32 = 3;
+
+/// The backend sends [BackendEvent](super::events::BackendEvent)s from `next_events`
+pub const CAPABILITY_EVENTS: &str = "events";
+/// The backend can give a file more parents with `add_parent_to_file`
+pub const CAPABILITY_HARD_LINKS: &str = "hard_links";
+/// The backend can list all paths of a file with `get_paths_for_file`
+pub const CAPABILITY_PATHS_FOR_FILE: &str = "paths_for_file";
+
+/// The backend has `set_client_name`, `set_event_subscription` and `list_clients`
+pub const CAPABILITY_CLIENTS: &str = "clients";
+
+/// The backend has `ping`, `status`, `shutdown`, `force_resync` and `reload_config`
+pub const CAPABILITY_ADMIN: &str = "admin";
+/// The backend has `mark_files_as_deleted` and `move_files`, which change many files at once
+pub const CAPABILITY_BULK_CHANGES: &str = "bulk_changes";
+/// The backend has `flush_uploads`, which confirms that all changes are saved
+pub const CAPABILITY_FLUSH: &str = "flush";
+
+/// Everything this build supports, newer builds only ever add to this
+const CAPABILITIES: &[&str] = &[
+    CAPABILITY_EVENTS,
+    CAPABILITY_HARD_LINKS,
+    CAPABILITY_PATHS_FOR_FILE,
+    CAPABILITY_CLIENTS,
+    CAPABILITY_ADMIN,
+    CAPABILITY_BULK_CHANGES,
+    CAPABILITY_FLUSH,
+];
+
+/// The version of one side of the connection.
+///
+/// The fields of this must never change, otherwise the handshake itself can not be decoded
+/// by older builds. Capabilities are strings for the same reason, an unknown enum variant would
+/// fail to decode.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// The version of the crate that was built, only for messages
+    pub crate_version: String,
+    pub protocol_version: u32,
+    pub min_compatible_protocol_version: u32,
+    pub capabilities: Vec<String>,
+}
+impl VersionInfo {
+    /// The version of this build
+    pub fn current() -> Self {
+        Self {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            min_compatible_protocol_version: MIN_COMPATIBLE_PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+    /// Checks that both sides accept the protocol version of the other one
+    pub fn check_compatible(&self, other: &VersionInfo) -> Result<(), IncompatibleVersionError> {
+        if other.protocol_version < self.min_compatible_protocol_version {
+            return Err(IncompatibleVersionError::TooOld {
+                own: self.crate_version.clone(),
+                other: other.crate_version.clone(),
+            });
+        }
+        if self.protocol_version < other.min_compatible_protocol_version {
+            return Err(IncompatibleVersionError::TooNew {
+                own: self.crate_version.clone(),
+                other: other.crate_version.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, thiserror::Error)]
+pub enum IncompatibleVersionError {
+    #[error("Version {other} on the other side is too old for version {own}, please upgrade it")]
+    TooOld { own: String, other: String },
+    #[error("Version {own} is too old for version {other} on the other side, please upgrade it")]
+    TooNew { own: String, other: String },
+}
@@ -5,6 +5,7 @@ pub mod prelude;
 pub mod config;
 pub mod drive_structure;
 pub mod ipc;
+pub mod name_encoding;
 pub mod path_resolve_error;
 pub mod project_dirs;
 pub mod time_utils;
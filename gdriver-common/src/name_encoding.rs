@@ -0,0 +1,197 @@
+//! Turns Drive names into names that are valid on POSIX filesystems and back.
+//!
+//! Drive names can contain `/` and control characters, can be `.` or `..` and can be longer
+//! than most filesystems allow. [encode_name] replaces `/` with `∕` (U+2215) and control
+//! characters with their symbols from the Control Pictures block (`␀`, `␊`, ...). Characters
+//! that are used as replacements are prefixed with [ESCAPE] when they are part of the
+//! original name, as are the names `.` and `..`, so [decode_name] can restore the original.
+//!
+//! Names that are longer than [MAX_NAME_LENGTH] bytes after encoding are truncated and get a
+//! hash of the full name, those can not be decoded and have to be found by comparing the
+//! encoded names.
+
+/// Most filesystems do not allow longer names
+pub const MAX_NAME_LENGTH: usize = 255;
+/// Marks that the next character is part of the original name (U+FF05 FULLWIDTH PERCENT SIGN)
+pub const ESCAPE: char = '％';
+/// Replaces `/` (U+2215 DIVISION SLASH)
+const SLASH_REPLACEMENT: char = '∕';
+/// The symbol of the first control character, NUL, in the Control Pictures block
+const CONTROL_PICTURES_START: u32 = 0x2400;
+/// The symbol of DEL in the Control Pictures block
+const DELETE_PICTURE: char = '\u{2421}';
+/// Separates the truncated name from the hash of the full name
+const HASH_SEPARATOR: char = '~';
+/// Extensions up to this length are kept when a name is truncated
+const MAX_KEPT_EXTENSION_LENGTH: usize = 16;
+
+/// Returns a name that can be used on a POSIX filesystem
+pub fn encode_name(name: &str) -> String {
+    if name.is_empty() || name == "." || name == ".." {
+        return format!("{}{}", ESCAPE, name);
+    }
+    let mut encoded = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '/' => encoded.push(SLASH_REPLACEMENT),
+            '\u{7f}' => encoded.push(DELETE_PICTURE),
+            c if c.is_ascii_control() => encoded.push(control_picture(c)),
+            c if is_replacement(c) => {
+                encoded.push(ESCAPE);
+                encoded.push(c);
+            }
+            c => encoded.push(c),
+        }
+    }
+    truncate_name(&encoded)
+}
+
+/// Returns the original name of a name that was returned by [encode_name].
+///
+/// Names that were truncated can not be restored, use [is_truncated] to check for them.
+pub fn decode_name(encoded: &str) -> String {
+    let mut name = String::with_capacity(encoded.len());
+    let mut chars = encoded.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ESCAPE => name.extend(chars.next()),
+            SLASH_REPLACEMENT => name.push('/'),
+            DELETE_PICTURE => name.push('\u{7f}'),
+            c if is_control_picture(c) => {
+                let control = c as u32 - CONTROL_PICTURES_START;
+                name.extend(char::from_u32(control));
+            }
+            c => name.push(c),
+        }
+    }
+    name
+}
+
+/// Returns true if the name was shortened by [encode_name] or [truncate_name]
+pub fn is_truncated(encoded: &str) -> bool {
+    // truncated names can be up to 3 bytes shorter, so they end on a char boundary
+    encoded.len() + 3 >= MAX_NAME_LENGTH
+        && encoded
+            .rsplit_once(HASH_SEPARATOR)
+            .and_then(|(_, rest)| rest.get(..16))
+            .is_some_and(|hash| hash.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Shortens names that are longer than [MAX_NAME_LENGTH] bytes.
+///
+/// Adds a hash of the full name, so different long names stay different, and keeps short
+/// extensions: `<start of the name>~<hash>.pdf`.
+pub fn truncate_name(name: &str) -> String {
+    if name.len() <= MAX_NAME_LENGTH {
+        return name.to_string();
+    }
+    let extension = match name.rfind('.') {
+        Some(index) if index > 0 && name.len() - index <= MAX_KEPT_EXTENSION_LENGTH => {
+            &name[index..]
+        }
+        _ => "",
+    };
+    let hash = format!("{}{:016x}", HASH_SEPARATOR, fnv1a(name.as_bytes()));
+    let mut end = MAX_NAME_LENGTH - hash.len() - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}{}", &name[..end], hash, extension)
+}
+
+fn control_picture(c: char) -> char {
+    char::from_u32(CONTROL_PICTURES_START + c as u32).expect("control pictures are valid chars")
+}
+fn is_control_picture(c: char) -> bool {
+    (CONTROL_PICTURES_START..CONTROL_PICTURES_START + 0x20).contains(&(c as u32))
+}
+/// Returns true for characters that [encode_name] uses in place of other characters
+fn is_replacement(c: char) -> bool {
+    c == ESCAPE || c == SLASH_REPLACEMENT || c == DELETE_PICTURE || is_control_picture(c)
+}
+/// A hash that does not change between runs or versions, unlike the std hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_round_trip(name: &str) {
+        let encoded = encode_name(name);
+        assert!(!encoded.contains('/'), "{encoded:?} contains a slash");
+        assert!(!encoded.contains('\0'), "{encoded:?} contains NUL");
+        assert!(!is_truncated(&encoded), "{encoded:?} is truncated");
+        assert_eq!(decode_name(&encoded), name);
+    }
+
+    #[test]
+    fn names_with_slashes_are_restored() {
+        assert_round_trip("a/b");
+        assert_round_trip("/");
+        assert_round_trip("//");
+    }
+    #[test]
+    fn names_with_control_characters_are_restored() {
+        assert_round_trip("a\0b");
+        assert_round_trip("line\nbreak\t\u{7f}");
+    }
+    #[test]
+    fn dot_names_are_restored() {
+        assert_ne!(encode_name("."), ".");
+        assert_ne!(encode_name(".."), "..");
+        assert_round_trip(".");
+        assert_round_trip("..");
+        assert_round_trip("");
+        assert_round_trip(".hidden");
+    }
+    #[test]
+    fn replacement_characters_in_the_name_are_restored() {
+        assert_round_trip(&format!("100{ESCAPE}"));
+        assert_round_trip(&format!("{ESCAPE}{ESCAPE}"));
+        assert_round_trip(&format!("{SLASH_REPLACEMENT}"));
+        assert_round_trip(&format!("{}", control_picture('\0')));
+        assert_round_trip(&format!("{DELETE_PICTURE}"));
+    }
+    #[test]
+    fn plain_names_are_not_changed() {
+        assert_eq!(encode_name("report.pdf"), "report.pdf");
+    }
+    #[test]
+    fn long_names_are_truncated_and_keep_the_extension() {
+        let name = format!("{}.pdf", "a".repeat(300));
+        let encoded = encode_name(&name);
+
+        assert!(encoded.len() <= MAX_NAME_LENGTH);
+        assert!(encoded.ends_with(".pdf"));
+        assert!(is_truncated(&encoded));
+    }
+    #[test]
+    fn different_long_names_stay_different() {
+        let first = encode_name(&format!("{}1", "a".repeat(300)));
+        let second = encode_name(&format!("{}2", "a".repeat(300)));
+
+        assert_ne!(first, second);
+    }
+    #[test]
+    fn long_names_are_cut_at_a_char_boundary() {
+        let encoded = encode_name(&"ä".repeat(200));
+
+        assert!(encoded.len() <= MAX_NAME_LENGTH);
+        assert!(is_truncated(&encoded));
+    }
+    #[test]
+    fn multibyte_characters_after_the_hash_separator_are_no_hash() {
+        let name = format!("{}{HASH_SEPARATOR}1{}", "a".repeat(200), "ä".repeat(30));
+
+        assert!(name.len() + 3 >= MAX_NAME_LENGTH);
+        assert!(!is_truncated(&name));
+    }
+    #[test]
+    fn names_up_to_the_limit_are_restored() {
+        assert_round_trip(&"a".repeat(MAX_NAME_LENGTH));
+    }
+}
@@ -2,8 +2,12 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, thiserror::Error)]
 pub enum PathResolveError {
-    #[error("The path provided was invalid")]
-    InvalidPath,
+    #[error("The path is not valid UTF-8")]
+    InvalidUtf8,
+    #[error("No file named '{segment}' was found")]
+    NotFound { segment: String },
+    #[error("'{segment}' is not a directory")]
+    NotADirectory { segment: String },
     #[error("Some other error occurred")]
     Other(String),
 }
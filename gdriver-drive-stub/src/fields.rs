@@ -0,0 +1,75 @@
+use serde_json::Value;
+
+/// A single entry of a `fields` parameter like `nextPageToken, files(id, name)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FieldSelector {
+    name: String,
+    /// The fields selected inside of this field, empty if the whole field is selected
+    children: Vec<FieldSelector>,
+}
+
+/// Parses the `fields` parameter of a request
+pub(crate) fn parse_fields(fields: &str) -> Vec<FieldSelector> {
+    let mut chars = fields.chars().peekable();
+    parse_level(&mut chars)
+}
+fn parse_level(chars: &mut std::iter::Peekable<std::str::Chars>) -> Vec<FieldSelector> {
+    let mut selectors = vec![];
+    let mut name = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            ',' => push_selector(&mut selectors, &mut name, vec![]),
+            '(' => {
+                let children = parse_level(chars);
+                push_selector(&mut selectors, &mut name, children);
+                // skip until the next selector
+                while chars.peek().is_some_and(|c| *c != ',' && *c != ')') {
+                    chars.next();
+                }
+            }
+            ')' => break,
+            c if c.is_whitespace() => {}
+            c => name.push(c),
+        }
+    }
+    push_selector(&mut selectors, &mut name, vec![]);
+    selectors
+}
+fn push_selector(
+    selectors: &mut Vec<FieldSelector>,
+    name: &mut String,
+    children: Vec<FieldSelector>,
+) {
+    if name.is_empty() {
+        return;
+    }
+    selectors.push(FieldSelector {
+        name: std::mem::take(name),
+        children,
+    });
+}
+
+/// Removes everything from the value that was not selected, like the Drive API does
+pub(crate) fn apply_fields(value: Value, selectors: &[FieldSelector]) -> Value {
+    if selectors.is_empty() || selectors.iter().any(|s| s.name == "*") {
+        return value;
+    }
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .filter_map(|(key, value)| {
+                    let selector = selectors.iter().find(|s| s.name == key)?;
+                    Some((key, apply_fields(value, &selector.children)))
+                })
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| apply_fields(value, selectors))
+                .collect(),
+        ),
+        value => value,
+    }
+}
@@ -0,0 +1,262 @@
+//! A local stand-in for the parts of the Google Drive v3 API that gdriver uses.
+//!
+//! Start a [DriveStub], point the backend at [DriveStub::root_url] and script the remote
+//! state with the helpers, every change shows up in the changes feed of the stub.
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+
+mod fields;
+mod routes;
+mod state;
+
+use routes::{route, StubRequest, StubResponse};
+use state::{StubState, FOLDER_MIME_TYPE};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+/// An error in the format of the Drive API
+#[derive(Debug, thiserror::Error)]
+#[error("{status}: {reason}: {message}")]
+pub struct StubError {
+    pub status: StatusCode,
+    pub reason: String,
+    pub message: String,
+}
+impl StubError {
+    pub(crate) fn not_found(id: &str) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            reason: "notFound".to_string(),
+            message: format!("File not found: {}.", id),
+        }
+    }
+    pub(crate) fn not_found_route(method: &Method, path: &str) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            reason: "notFound".to_string(),
+            message: format!("The stub does not implement {} {}", method, path),
+        }
+    }
+    pub(crate) fn bad_request(reason: &str, message: &str) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            reason: reason.to_string(),
+            message: message.to_string(),
+        }
+    }
+    pub(crate) fn rate_limited() -> Self {
+        Self::forbidden("userRateLimitExceeded", "User Rate Limit Exceeded.")
+    }
+    pub(crate) fn forbidden(reason: &str, message: &str) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            reason: reason.to_string(),
+            message: message.to_string(),
+        }
+    }
+    pub(crate) fn into_response(self) -> StubResponse {
+        let body = json!({"error": {
+            "code": self.status.as_u16(),
+            "message": self.message,
+            "errors": [{"domain": "global", "reason": self.reason, "message": self.message}],
+        }});
+        StubResponse {
+            status: self.status,
+            content_type: "application/json; charset=UTF-8".to_string(),
+            body: body.to_string().into_bytes(),
+        }
+    }
+}
+
+/// A running stand-in Drive API server, stopped when dropped
+#[derive(Debug)]
+pub struct DriveStub {
+    address: SocketAddr,
+    state: Arc<Mutex<StubState>>,
+    server: JoinHandle<()>,
+}
+
+impl DriveStub {
+    /// Starts the server on a free port of the loopback interface
+    pub async fn start() -> Result<Self> {
+        Self::start_on(([127, 0, 0, 1], 0).into()).await
+    }
+    pub async fn start_on(address: SocketAddr) -> Result<Self> {
+        let state = Arc::new(Mutex::new(StubState::new()));
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |request| handle(state.clone(), request))) }
+        });
+        let server = Server::try_bind(&address)?.serve(make_service);
+        let address = server.local_addr();
+        info!("Drive stub listening on {}", address);
+        let server = tokio::spawn(async move {
+            if let Err(e) = server.await {
+                tracing::error!("Drive stub stopped: {}", e);
+            }
+        });
+        Ok(Self {
+            address,
+            state,
+            server,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+    /// The url that replaces `https://www.googleapis.com/`
+    pub fn root_url(&self) -> String {
+        format!("http://{}/", self.address)
+    }
+    pub fn root_id(&self) -> String {
+        self.state.lock().unwrap().root_id.clone()
+    }
+
+    /// Creates a file with the content and returns its id
+    pub fn create_file(&self, parent: &str, name: &str, content: &[u8]) -> String {
+        let metadata = json!({"name": name, "parents": [parent]});
+        self.create(metadata, Some(content.to_vec()))
+    }
+    /// Creates a folder and returns its id
+    pub fn create_folder(&self, parent: &str, name: &str) -> String {
+        let metadata = json!({"name": name, "parents": [parent], "mimeType": FOLDER_MIME_TYPE});
+        self.create(metadata, None)
+    }
+    fn create(&self, metadata: Value, content: Option<Vec<u8>>) -> String {
+        let Value::Object(metadata) = metadata else {
+            unreachable!("metadata is always an object")
+        };
+        let file = self.state.lock().unwrap().create(metadata, content);
+        file["id"].as_str().unwrap_or_default().to_string()
+    }
+    pub fn rename(&self, id: &str, name: &str) -> std::result::Result<(), StubError> {
+        let mut metadata = Map::new();
+        metadata.insert("name".into(), name.into());
+        self.state
+            .lock()
+            .unwrap()
+            .update(id, metadata, vec![], vec![], None)?;
+        Ok(())
+    }
+    /// Replaces the content of a file
+    pub fn write(&self, id: &str, content: &[u8]) -> std::result::Result<(), StubError> {
+        self.state.lock().unwrap().update(
+            id,
+            Map::new(),
+            vec![],
+            vec![],
+            Some(content.to_vec()),
+        )?;
+        Ok(())
+    }
+    /// Deletes a file for good, like it was removed by another client
+    pub fn remove(&self, id: &str) -> std::result::Result<(), StubError> {
+        self.state.lock().unwrap().delete(id)
+    }
+    /// Returns the file resource as the API would return it with `fields=*`
+    pub fn file(&self, id: &str) -> Option<Value> {
+        self.state.lock().unwrap().get(id).ok().cloned()
+    }
+    /// Returns the content of a file
+    pub fn content(&self, id: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().content(id).ok()
+    }
+    /// Lets the next calls fail because the rate limit is exceeded, the calls inside a batch
+    /// request count on their own
+    pub fn rate_limit_next_calls(&self, count: usize) {
+        self.state.lock().unwrap().rate_limited_calls = count;
+    }
+}
+
+impl Drop for DriveStub {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn handle(
+    state: Arc<Mutex<StubState>>,
+    request: Request<Body>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    let (parts, body) = request.into_parts();
+    debug!("{} {}", parts.method, parts.uri);
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body.to_vec(),
+        Err(e) => {
+            let response = StubError::bad_request("badContent", &e.to_string()).into_response();
+            return Ok(into_hyper_response(response));
+        }
+    };
+    let request = StubRequest {
+        method: parts.method,
+        path: parts.uri.path().to_string(),
+        query: parse_query_string(parts.uri.query().unwrap_or_default()),
+        content_type: parts
+            .headers
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string(),
+        body,
+    };
+    let response = {
+        let mut state = state.lock().unwrap();
+        route(&mut state, &request).unwrap_or_else(StubError::into_response)
+    };
+    Ok(into_hyper_response(response))
+}
+fn into_hyper_response(response: StubResponse) -> Response<Body> {
+    let mut builder = Response::builder().status(response.status);
+    if !response.content_type.is_empty() {
+        builder = builder.header(hyper::header::CONTENT_TYPE, response.content_type);
+    }
+    builder
+        .body(Body::from(response.body))
+        .expect("valid response")
+}
+
+pub(crate) fn parse_query_string(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+pub(crate) fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let byte = std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match byte {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
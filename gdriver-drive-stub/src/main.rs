@@ -0,0 +1,19 @@
+use gdriver_drive_stub::DriveStub;
+use std::error::Error;
+use std::net::SocketAddr;
+
+/// Serves the stand-in Drive API until Ctrl-C is pressed.
+///
+/// Takes the address to listen on as optional argument, set `drive_api_root_url` to the
+/// printed url and `drive_api_skip_auth` to true to point the backend at it.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let address: SocketAddr = match std::env::args().nth(1) {
+        Some(address) => address.parse()?,
+        None => ([127, 0, 0, 1], 0).into(),
+    };
+    let stub = DriveStub::start_on(address).await?;
+    println!("Drive stub listening on {}", stub.root_url());
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
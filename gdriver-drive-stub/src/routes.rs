@@ -0,0 +1,357 @@
+use crate::fields::{apply_fields, parse_fields};
+use crate::state::StubState;
+use crate::StubError;
+use hyper::{Method, StatusCode};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const JSON_CONTENT_TYPE: &str = "application/json; charset=UTF-8";
+const BATCH_BOUNDARY: &str = "batch_stub_boundary";
+
+/// Everything of a request the routes need
+pub(crate) struct StubRequest {
+    pub method: Method,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+impl StubRequest {
+    fn param(&self, key: &str) -> Option<&str> {
+        self.query.get(key).map(String::as_str)
+    }
+}
+/// The answer to a [StubRequest]
+pub(crate) struct StubResponse {
+    pub status: StatusCode,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+pub(crate) fn route(
+    state: &mut StubState,
+    request: &StubRequest,
+) -> Result<StubResponse, StubError> {
+    let segments: Vec<&str> = request.path.split('/').filter(|s| !s.is_empty()).collect();
+    // a batch request counts as the calls it contains
+    if segments.first() != Some(&"batch") && state.rate_limited_calls > 0 {
+        state.rate_limited_calls -= 1;
+        return Err(StubError::rate_limited());
+    }
+    match (&request.method, segments.as_slice()) {
+        (&Method::GET, ["drive", "v3", "about"]) => json_response(
+            request,
+            json!({"kind": "drive#about", "user": {"kind": "drive#user", "emailAddress": state.email}}),
+        ),
+        (&Method::GET, ["drive", "v3", "files"]) => list_files(state, request),
+        (&Method::POST, ["drive", "v3", "files"]) => {
+            let file = state.create(read_json(&request.body)?, None);
+            json_response(request, file)
+        }
+        (&Method::POST, ["upload", "drive", "v3", "files"]) => {
+            let (metadata, content) = read_upload(request)?;
+            let file = state.create(metadata, Some(content));
+            json_response(request, file)
+        }
+        (&Method::GET, ["drive", "v3", "files", id]) => {
+            if request.param("alt") == Some("media") {
+                return Ok(StubResponse {
+                    status: StatusCode::OK,
+                    content_type: "application/octet-stream".to_string(),
+                    body: state.content(id)?,
+                });
+            }
+            let file = state.get(id)?.clone();
+            json_response(request, file)
+        }
+        (&Method::PATCH, ["drive", "v3", "files", id]) => {
+            let metadata = read_json(&request.body)?;
+            update_file(state, request, id, metadata, None)
+        }
+        (&Method::PATCH, ["upload", "drive", "v3", "files", id]) => {
+            let (metadata, content) = read_upload(request)?;
+            update_file(state, request, id, metadata, Some(content))
+        }
+        (&Method::DELETE, ["drive", "v3", "files", id]) => {
+            state.delete(id)?;
+            Ok(StubResponse {
+                status: StatusCode::NO_CONTENT,
+                content_type: String::new(),
+                body: vec![],
+            })
+        }
+        (&Method::GET, ["drive", "v3", "changes", "startPageToken"]) => json_response(
+            request,
+            json!({"kind": "drive#startPageToken", "startPageToken": state.start_page_token()}),
+        ),
+        (&Method::GET, ["drive", "v3", "changes"]) => list_changes(state, request),
+        (&Method::POST, ["batch", "drive", "v3"]) => batch(state, request),
+        _ => Err(StubError::not_found_route(&request.method, &request.path)),
+    }
+}
+
+fn list_files(state: &StubState, request: &StubRequest) -> Result<StubResponse, StubError> {
+    let filters = parse_query(request.param("q").unwrap_or_default())?;
+    let files: Vec<&Value> = state
+        .list()
+        .filter(|file| filters.iter().all(|filter| filter.matches(state, file)))
+        .collect();
+    let (start, page_size) = page(request)?;
+    let end = (start + page_size).min(files.len());
+    let mut response = json!({
+        "kind": "drive#fileList",
+        "incompleteSearch": false,
+        "files": files[start.min(end)..end],
+    });
+    if end < files.len() {
+        response["nextPageToken"] = end.to_string().into();
+    }
+    json_response(request, response)
+}
+fn list_changes(state: &StubState, request: &StubRequest) -> Result<StubResponse, StubError> {
+    let token = request
+        .param("pageToken")
+        .ok_or_else(|| StubError::bad_request("required", "pageToken is required"))?;
+    let page_size = request
+        .param("pageSize")
+        .map(|size| size.parse().map_err(|_| invalid_page_size()))
+        .transpose()?
+        .unwrap_or(DEFAULT_PAGE_SIZE);
+    let (changes, next_page_token) = state.changes_since(token, page_size)?;
+    let mut response = json!({"kind": "drive#changeList", "changes": changes});
+    match next_page_token {
+        Some(next) => response["nextPageToken"] = next.into(),
+        None => response["newStartPageToken"] = state.start_page_token().into(),
+    }
+    json_response(request, response)
+}
+fn update_file(
+    state: &mut StubState,
+    request: &StubRequest,
+    id: &str,
+    metadata: Map<String, Value>,
+    content: Option<Vec<u8>>,
+) -> Result<StubResponse, StubError> {
+    let split_ids = |key: &str| -> Vec<String> {
+        request
+            .param(key)
+            .map(|ids| ids.split(',').map(str::to_string).collect())
+            .unwrap_or_default()
+    };
+    let file = state.update(
+        id,
+        metadata,
+        split_ids("addParents"),
+        split_ids("removeParents"),
+        content,
+    )?;
+    json_response(request, file)
+}
+
+/// Returns the offset and the size of the requested page
+fn page(request: &StubRequest) -> Result<(usize, usize), StubError> {
+    let start = match request.param("pageToken") {
+        None | Some("") => 0,
+        Some(token) => token
+            .parse()
+            .map_err(|_| StubError::bad_request("invalid", "Invalid page token"))?,
+    };
+    let page_size = match request.param("pageSize") {
+        None => DEFAULT_PAGE_SIZE,
+        Some(size) => size.parse().map_err(|_| invalid_page_size())?,
+    };
+    Ok((start, page_size))
+}
+fn invalid_page_size() -> StubError {
+    StubError::bad_request("invalid", "Invalid page size")
+}
+
+fn json_response(request: &StubRequest, value: Value) -> Result<StubResponse, StubError> {
+    let value = match request.param("fields") {
+        Some(fields) => apply_fields(value, &parse_fields(fields)),
+        None => value,
+    };
+    Ok(StubResponse {
+        status: StatusCode::OK,
+        content_type: JSON_CONTENT_TYPE.to_string(),
+        body: value.to_string().into_bytes(),
+    })
+}
+fn read_json(body: &[u8]) -> Result<Map<String, Value>, StubError> {
+    if body.is_empty() {
+        return Ok(Map::new());
+    }
+    serde_json::from_slice(body)
+        .map_err(|e| StubError::bad_request("parseError", &format!("Invalid json: {}", e)))
+}
+/// Reads the metadata and the content of a `media` or `multipart` upload
+fn read_upload(request: &StubRequest) -> Result<(Map<String, Value>, Vec<u8>), StubError> {
+    match request.param("uploadType") {
+        Some("media") => Ok((Map::new(), request.body.clone())),
+        Some("multipart") => {
+            let boundary = boundary(request)?;
+            let mut parts = split_multipart(&request.body, boundary).into_iter();
+            let metadata = parts
+                .next()
+                .ok_or_else(|| StubError::bad_request("badContent", "Missing metadata"))?;
+            let content = parts.next().unwrap_or_default();
+            Ok((read_json(&metadata)?, content))
+        }
+        _ => Err(StubError::bad_request(
+            "invalid",
+            "Only media and multipart uploads are supported",
+        )),
+    }
+}
+fn boundary(request: &StubRequest) -> Result<&str, StubError> {
+    Ok(request
+        .content_type
+        .split(';')
+        .filter_map(|part| part.trim().strip_prefix("boundary="))
+        .next()
+        .ok_or_else(|| StubError::bad_request("badContent", "Missing boundary"))?
+        .trim_matches('"'))
+}
+/// Returns the content of every part of the multipart body without the part headers
+fn split_multipart(body: &[u8], boundary: &str) -> Vec<Vec<u8>> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut parts = vec![];
+    let mut rest = body;
+    while let Some(start) = find(rest, &delimiter) {
+        rest = &rest[start + delimiter.len()..];
+        if rest.starts_with(b"--") {
+            break;
+        }
+        let Some(end) = find(rest, &delimiter) else {
+            break;
+        };
+        let part = &rest[..end];
+        let content_start = find(part, b"\r\n\r\n").map(|i| i + 4).unwrap_or(0);
+        let content = part[content_start..]
+            .strip_suffix(b"\r\n")
+            .unwrap_or(&part[content_start..]);
+        parts.push(content.to_vec());
+    }
+    parts
+}
+
+/// Answers every http request in the multipart body like it was sent on its own
+fn batch(state: &mut StubState, request: &StubRequest) -> Result<StubResponse, StubError> {
+    let boundary = boundary(request)?;
+    let mut body = String::new();
+    for (index, part) in split_multipart(&request.body, boundary).iter().enumerate() {
+        let response = match parse_http_request(part) {
+            Ok(inner) => route(state, &inner),
+            Err(e) => Err(e),
+        }
+        .unwrap_or_else(StubError::into_response);
+        body.push_str(&format!("--{}\r\n", BATCH_BOUNDARY));
+        body.push_str("Content-Type: application/http\r\n");
+        body.push_str(&format!("Content-ID: <response-item{}>\r\n\r\n", index));
+        body.push_str(&format!(
+            "HTTP/1.1 {} {}\r\n",
+            response.status.as_u16(),
+            response.status.canonical_reason().unwrap_or_default()
+        ));
+        if !response.content_type.is_empty() {
+            body.push_str(&format!("Content-Type: {}\r\n", response.content_type));
+        }
+        body.push_str("\r\n");
+        body.push_str(&String::from_utf8_lossy(&response.body));
+        body.push_str("\r\n");
+    }
+    body.push_str(&format!("--{}--\r\n", BATCH_BOUNDARY));
+    Ok(StubResponse {
+        status: StatusCode::OK,
+        content_type: format!("multipart/mixed; boundary={}", BATCH_BOUNDARY),
+        body: body.into_bytes(),
+    })
+}
+/// Parses a http request that was sent as part of a batch request
+fn parse_http_request(message: &[u8]) -> Result<StubRequest, StubError> {
+    let invalid = || StubError::bad_request("badContent", "Invalid request in batch");
+    let message = String::from_utf8_lossy(message);
+    let (head, body) = message
+        .split_once("\r\n\r\n")
+        .unwrap_or((message.as_ref(), ""));
+    let mut lines = head.lines();
+    let mut request_line = lines.next().ok_or_else(invalid)?.split_whitespace();
+    let method = request_line
+        .next()
+        .and_then(|method| method.parse::<Method>().ok())
+        .ok_or_else(invalid)?;
+    let target = request_line.next().ok_or_else(invalid)?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let content_type = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.trim().to_string())
+        .unwrap_or_default();
+    Ok(StubRequest {
+        method,
+        path: crate::percent_decode(path),
+        query: crate::parse_query_string(query),
+        content_type,
+        body: body.trim_end().as_bytes().to_vec(),
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// A single condition of the `q` parameter of files.list
+#[derive(Debug, PartialEq, Eq)]
+enum QueryFilter {
+    InParents(String),
+    Trashed(bool),
+    Name(String),
+    MimeType(String),
+}
+impl QueryFilter {
+    fn matches(&self, state: &StubState, file: &Value) -> bool {
+        match self {
+            QueryFilter::InParents(parent) => {
+                let parent = state.resolve_id(parent);
+                file["parents"]
+                    .as_array()
+                    .is_some_and(|parents| parents.iter().any(|p| *p == parent.as_str()))
+            }
+            QueryFilter::Trashed(trashed) => file["trashed"].as_bool().unwrap_or(false) == *trashed,
+            QueryFilter::Name(name) => file["name"] == name.as_str(),
+            QueryFilter::MimeType(mime_type) => file["mimeType"] == mime_type.as_str(),
+        }
+    }
+}
+/// Parses the subset of the query language that is used by gdriver: conditions joined by `and`
+fn parse_query(query: &str) -> Result<Vec<QueryFilter>, StubError> {
+    if query.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    query
+        .split(" and ")
+        .map(|condition| {
+            let condition = condition.trim();
+            let invalid =
+                || StubError::bad_request("invalid", &format!("Unsupported query: {}", condition));
+            if let Some(parent) = condition.strip_suffix(" in parents") {
+                return Ok(QueryFilter::InParents(unquote(parent).ok_or_else(invalid)?));
+            }
+            let (field, value) = condition.split_once('=').ok_or_else(invalid)?;
+            let value = value.trim();
+            match field.trim() {
+                "trashed" => Ok(QueryFilter::Trashed(value.parse().map_err(|_| invalid())?)),
+                "name" => Ok(QueryFilter::Name(unquote(value).ok_or_else(invalid)?)),
+                "mimeType" => Ok(QueryFilter::MimeType(unquote(value).ok_or_else(invalid)?)),
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
+fn unquote(value: &str) -> Option<String> {
+    let value = value.trim().strip_prefix('\'')?.strip_suffix('\'')?;
+    Some(value.replace("\\'", "'"))
+}
@@ -0,0 +1,225 @@
+use crate::StubError;
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+
+pub(crate) const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+/// The alias the API accepts for the id of the root folder
+pub(crate) const ROOT_ALIAS: &str = "root";
+
+/// The files of the stand-in drive and all changes that were made to them
+#[derive(Debug)]
+pub(crate) struct StubState {
+    pub root_id: String,
+    pub email: String,
+    /// The file resources as they are returned by the API
+    files: BTreeMap<String, Value>,
+    contents: HashMap<String, Vec<u8>>,
+    /// Every change gets the index in this list as page token
+    changes: Vec<Value>,
+    next_id: u64,
+    /// How many of the next calls fail because the rate limit is exceeded
+    pub rate_limited_calls: usize,
+}
+
+impl StubState {
+    pub fn new() -> Self {
+        let mut state = Self {
+            root_id: "stub_root".to_string(),
+            email: "stub@example.com".to_string(),
+            files: BTreeMap::new(),
+            contents: HashMap::new(),
+            changes: vec![],
+            next_id: 0,
+            rate_limited_calls: 0,
+        };
+        let root = json!({
+            "kind": "drive#file",
+            "id": state.root_id,
+            "name": "My Drive",
+            "mimeType": FOLDER_MIME_TYPE,
+            "trashed": false,
+            "createdTime": now(),
+            "modifiedTime": now(),
+        });
+        state.files.insert(state.root_id.clone(), root);
+        state
+    }
+    /// Replaces the `root` alias with the real root id
+    pub fn resolve_id(&self, id: &str) -> String {
+        if id == ROOT_ALIAS {
+            self.root_id.clone()
+        } else {
+            id.to_string()
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Result<&Value, StubError> {
+        self.files
+            .get(&self.resolve_id(id))
+            .ok_or_else(|| StubError::not_found(id))
+    }
+    pub fn content(&self, id: &str) -> Result<Vec<u8>, StubError> {
+        let id = self.resolve_id(id);
+        let file = self.get(&id)?;
+        if file["mimeType"] == FOLDER_MIME_TYPE {
+            return Err(StubError::bad_request(
+                "fileNotDownloadable",
+                "Only files with binary content can be downloaded",
+            ));
+        }
+        Ok(self.contents.get(&id).cloned().unwrap_or_default())
+    }
+    /// All files except the root folder, which the API never lists
+    pub fn list(&self) -> impl Iterator<Item = &Value> {
+        self.files
+            .iter()
+            .filter(|(id, _)| **id != self.root_id)
+            .map(|(_, file)| file)
+    }
+
+    /// Creates a file from the given metadata and returns it
+    pub fn create(&mut self, mut metadata: Map<String, Value>, content: Option<Vec<u8>>) -> Value {
+        self.next_id += 1;
+        let id = format!("stub_{}", self.next_id);
+        let parents = match metadata.remove("parents") {
+            Some(Value::Array(parents)) => parents
+                .into_iter()
+                .filter_map(|p| p.as_str().map(|p| self.resolve_id(p)))
+                .collect(),
+            _ => vec![self.root_id.clone()],
+        };
+        metadata.insert("kind".into(), "drive#file".into());
+        metadata.insert("id".into(), id.clone().into());
+        metadata.insert("parents".into(), parents.into());
+        metadata.entry("name").or_insert_with(|| "Untitled".into());
+        metadata
+            .entry("mimeType")
+            .or_insert_with(|| "application/octet-stream".into());
+        metadata.entry("trashed").or_insert(false.into());
+        metadata.insert("createdTime".into(), now().into());
+        self.contents
+            .insert(id.clone(), content.unwrap_or_default());
+        self.files.insert(id.clone(), Value::Object(metadata));
+        self.touch(&id);
+        self.files[&id].clone()
+    }
+    /// Applies the metadata, the parent changes and the content to the file and returns it
+    pub fn update(
+        &mut self,
+        id: &str,
+        metadata: Map<String, Value>,
+        add_parents: Vec<String>,
+        remove_parents: Vec<String>,
+        content: Option<Vec<u8>>,
+    ) -> Result<Value, StubError> {
+        let id = self.resolve_id(id);
+        let add_parents: Vec<String> = add_parents.iter().map(|p| self.resolve_id(p)).collect();
+        let remove_parents: Vec<String> =
+            remove_parents.iter().map(|p| self.resolve_id(p)).collect();
+        let file = self
+            .files
+            .get_mut(&id)
+            .ok_or_else(|| StubError::not_found(&id))?;
+        for (key, value) in metadata {
+            match key.as_str() {
+                "id" | "kind" | "parents" | "size" | "md5Checksum" => {
+                    return Err(StubError::bad_request(
+                        "fieldNotWritable",
+                        &format!("The field {} can not be written directly", key),
+                    ))
+                }
+                _ => {
+                    file[key.as_str()] = value;
+                }
+            }
+        }
+        let mut parents: Vec<String> = file["parents"]
+            .as_array()
+            .map(|parents| {
+                parents
+                    .iter()
+                    .filter_map(|p| p.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        parents.retain(|parent| !remove_parents.contains(parent));
+        for parent in add_parents {
+            if !parents.contains(&parent) {
+                parents.push(parent);
+            }
+        }
+        file["parents"] = parents.into();
+        if let Some(content) = content {
+            self.contents.insert(id.clone(), content);
+        }
+        self.touch(&id);
+        Ok(self.files[&id].clone())
+    }
+    pub fn delete(&mut self, id: &str) -> Result<(), StubError> {
+        let id = self.resolve_id(id);
+        if id == self.root_id {
+            return Err(StubError::forbidden(
+                "cannotDeleteRootFolder",
+                "The root folder can not be deleted",
+            ));
+        }
+        self.files
+            .remove(&id)
+            .ok_or_else(|| StubError::not_found(&id))?;
+        self.contents.remove(&id);
+        self.changes.push(json!({
+            "kind": "drive#change",
+            "changeType": "file",
+            "fileId": id,
+            "removed": true,
+            "time": now(),
+        }));
+        Ok(())
+    }
+    /// Updates the size and modification time and records a change for the file
+    fn touch(&mut self, id: &str) {
+        let size = self.contents.get(id).map(Vec::len).unwrap_or_default();
+        let file = self
+            .files
+            .get_mut(id)
+            .expect("only called for existing files");
+        if file["mimeType"] != FOLDER_MIME_TYPE {
+            // the API returns 64 bit numbers as strings
+            file["size"] = size.to_string().into();
+        }
+        file["modifiedTime"] = now().into();
+        let file = file.clone();
+        self.changes.push(json!({
+            "kind": "drive#change",
+            "changeType": "file",
+            "fileId": id,
+            "removed": false,
+            "file": file,
+            "time": now(),
+        }));
+    }
+
+    pub fn start_page_token(&self) -> String {
+        self.changes.len().to_string()
+    }
+    /// Returns the changes starting at the token and the next page token if there are more
+    pub fn changes_since(
+        &self,
+        token: &str,
+        page_size: usize,
+    ) -> Result<(Vec<Value>, Option<String>), StubError> {
+        let start: usize = token
+            .parse()
+            .ok()
+            .filter(|start| *start <= self.changes.len())
+            .ok_or_else(|| StubError::bad_request("invalid", "Invalid page token"))?;
+        let end = (start + page_size).min(self.changes.len());
+        let next = (end < self.changes.len()).then(|| end.to_string());
+        Ok((self.changes[start..end].to_vec(), next))
+    }
+}
+
+pub(crate) fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}